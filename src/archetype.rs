@@ -0,0 +1,176 @@
+use std::collections::HashSet;
+use std::fs::OpenOptions;
+use std::io::{BufReader, Error, ErrorKind};
+
+use serde::{Deserialize, Serialize};
+
+use crate::behavior::Behavior;
+use crate::pathfinding::Locomotion;
+
+// Archetype levels get when a spawn doesn't name one, and the only one before mobs.json
+pub const DEFAULT_ARCHETYPE: &str = "Monster";
+
+// How many bullets a mob fires in one attack
+#[derive(Copy, Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum BulletPattern {
+    #[default]
+    Single,
+    Spread {count: u32, angle: f64} // count bullets fanned out evenly over angle degrees
+}
+
+// What a mob does with its own color
+#[derive(Copy, Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum ColorBehavior {
+    #[default]
+    Counter,   // Switches to the opposite of the player's mana color when they match
+    Fixed,     // Never changes
+    Alternate  // Switches every time its color change cooldown runs out
+}
+
+fn default_hp() -> f64 {
+    100.0
+}
+
+fn default_move_cooldown() -> f64 {
+    6.0
+}
+
+fn default_attack_cooldown() -> f64 {
+    6.0
+}
+
+fn default_color_change_cooldown() -> f64 {
+    4.0
+}
+
+fn default_line_of_sight() -> f64 {
+    12.0
+}
+
+fn default_move_speed() -> f64 {
+    1.0
+}
+
+// One kind of mob.  Spawns in a level refer to it by id and can override some of its stats.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Archetype {
+    pub id: String,
+    pub sprite: String, // Asset name
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scale: Option<(f64, f64)>, // Billboard size, the asset's default scale if left out
+    #[serde(default = "default_hp")]
+    pub hp: f64,
+    #[serde(default = "default_move_cooldown")]
+    pub move_cooldown: f64,
+    #[serde(default = "default_attack_cooldown")]
+    pub attack_cooldown: f64,
+    #[serde(default = "default_color_change_cooldown")]
+    pub color_change_cooldown: f64,
+    #[serde(default = "default_line_of_sight")]
+    pub line_of_sight: f64,
+    #[serde(default = "default_move_speed")]
+    pub move_speed: f64,
+    #[serde(default)]
+    pub locomotion: Locomotion,
+    #[serde(default)]
+    pub bullet_pattern: BulletPattern,
+    #[serde(default)]
+    pub color_behavior: ColorBehavior,
+    #[serde(default)]
+    pub behavior: Behavior
+}
+
+impl Archetype {
+    // The original monster, what every mob was before archetypes
+    pub fn monster() -> Self {
+        Archetype {
+            id: DEFAULT_ARCHETYPE.to_string(),
+            sprite: "monster".to_string(),
+            scale: None,
+            hp: default_hp(),
+            move_cooldown: default_move_cooldown(),
+            attack_cooldown: default_attack_cooldown(),
+            color_change_cooldown: default_color_change_cooldown(),
+            line_of_sight: default_line_of_sight(),
+            move_speed: default_move_speed(),
+            locomotion: Locomotion::default(),
+            bullet_pattern: BulletPattern::default(),
+            color_behavior: ColorBehavior::default(),
+            behavior: Behavior::default()
+        }
+    }
+}
+
+// Every mob archetype, loaded from mobs.json
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ArchetypeRegistry {
+    pub archetypes: Vec<Archetype>
+}
+
+impl Default for ArchetypeRegistry {
+    fn default() -> Self {
+        ArchetypeRegistry {
+            archetypes: vec![Archetype::monster()]
+        }
+    }
+}
+
+impl ArchetypeRegistry {
+    pub fn load_from_file(filename: &str) -> Result<Self, Error> {
+        let reader = BufReader::new(OpenOptions::new().read(true).open(filename)?);
+        let registry: ArchetypeRegistry = serde_json::from_reader(reader)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, format!("{}: {}", filename, e)))?;
+        registry.check().map_err(|e| Error::new(ErrorKind::InvalidData, format!("{}: {}", filename, e)))?;
+        Ok(registry)
+    }
+
+    fn check(&self) -> Result<(), String> {
+        let mut ids: HashSet<&str> = HashSet::new();
+        for a in &self.archetypes {
+            if !ids.insert(a.id.as_str()) {
+                return Err(format!("archetype {} is listed twice", a.id));
+            }
+            if a.hp <= 0.0 {
+                return Err(format!("archetype {} needs more than 0 hp", a.id));
+            }
+            let times = [a.move_cooldown, a.attack_cooldown, a.color_change_cooldown, a.line_of_sight, a.move_speed];
+            if times.iter().any(|x| *x < 0.0) {
+                return Err(format!("archetype {} has a negative cooldown, sight or speed", a.id));
+            }
+            if let BulletPattern::Spread {count: 0, ..} = a.bullet_pattern {
+                return Err(format!("archetype {} fires a spread of no bullets", a.id));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn get(&self, id: &str) -> Option<&Archetype> {
+        self.archetypes.iter().find(|a| a.id == id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_registry() {
+        let registry = ArchetypeRegistry::load_from_file("mobs.json").unwrap();
+        // The shipped monster has to stay the same as the built in one, games without mobs.json get it
+        assert_eq!(registry.get(DEFAULT_ARCHETYPE), Some(&Archetype::monster()));
+
+        let text = r#"{"archetypes": [{"id": "Imp", "sprite": "monster", "hp": 20.0,
+                        "bullet_pattern": {"Spread": {"count": 3, "angle": 30.0}}}]}"#;
+        let registry: ArchetypeRegistry = serde_json::from_str(text).unwrap();
+        let imp = registry.get("Imp").unwrap();
+        assert_eq!(imp.hp, 20.0);
+        assert_eq!(imp.move_cooldown, 6.0);
+        assert_eq!(imp.color_behavior, ColorBehavior::Counter);
+        assert!(registry.check().is_ok());
+        assert!(registry.get("Monster").is_none());
+
+        let mut twice = registry.clone();
+        twice.archetypes.push(imp.clone());
+        assert!(twice.check().is_err());
+    }
+}
@@ -0,0 +1,208 @@
+use serde::{Deserialize, Serialize};
+
+// Refers to one entry of an Arena.  Once the entry is removed the handle goes stale: the slot's
+// generation moves on, so the handle never finds whatever is stored there next.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Handle {
+    index: u32,
+    generation: u32
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct Slot<T> {
+    generation: u32,
+    value: Option<T>
+}
+
+// Generational index store for entities (mobs, bullets), iterated in slot order
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(try_from = "ArenaData<T>")]
+pub struct Arena<T> {
+    slots: Vec<Slot<T>>,
+    free: Vec<u32> // Empty slots, reused last freed first
+}
+
+// An arena as read from a file, before the free list is checked against the slots
+#[derive(Deserialize)]
+struct ArenaData<T> {
+    slots: Vec<Slot<T>>,
+    free: Vec<u32>
+}
+
+impl<T> TryFrom<ArenaData<T>> for Arena<T> {
+    type Error = String;
+
+    // Every empty slot has to be on the free list exactly once, and nothing else
+    fn try_from(data: ArenaData<T>) -> Result<Self, Self::Error> {
+        let mut on_free_list = vec![false; data.slots.len()];
+        for index in data.free.iter() {
+            match data.slots.get(*index as usize) {
+                None => {return Err(format!("free slot {} is past the last slot", index));}
+                Some(slot) if slot.value.is_some() => {return Err(format!("free slot {} holds a value", index));}
+                Some(_) => {}
+            }
+            if on_free_list[*index as usize] {
+                return Err(format!("slot {} is on the free list twice", index));
+            }
+            on_free_list[*index as usize] = true;
+        }
+        if let Some(index) = data.slots.iter().zip(on_free_list.iter()).position(|(slot, free)| slot.value.is_none() && !free) {
+            return Err(format!("empty slot {} is not on the free list", index));
+        }
+        Ok(Arena {
+            slots: data.slots,
+            free: data.free
+        })
+    }
+}
+
+impl<T> Default for Arena<T> {
+    fn default() -> Self {
+        Arena {
+            slots: Vec::new(),
+            free: Vec::new()
+        }
+    }
+}
+
+impl<T> Arena<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, value: T) -> Handle {
+        match self.free.pop() {
+            None => {
+                self.slots.push(Slot {
+                    generation: 0,
+                    value: Some(value)
+                });
+                Handle {
+                    index: (self.slots.len() - 1) as u32,
+                    generation: 0
+                }
+            }
+            Some(index) => {
+                let slot = &mut self.slots[index as usize];
+                slot.value = Some(value);
+                Handle {
+                    index,
+                    generation: slot.generation
+                }
+            }
+        }
+    }
+
+    pub fn remove(&mut self, handle: Handle) -> Option<T> {
+        let slot = self.slots.get_mut(handle.index as usize)?;
+        if slot.generation != handle.generation || slot.value.is_none() {
+            return None;
+        }
+        slot.generation = slot.generation.wrapping_add(1);
+        self.free.push(handle.index);
+        slot.value.take()
+    }
+
+    pub fn get(&self, handle: Handle) -> Option<&T> {
+        match self.slots.get(handle.index as usize) {
+            Some(slot) if slot.generation == handle.generation => {slot.value.as_ref()}
+            _ => {None}
+        }
+    }
+
+    pub fn get_mut(&mut self, handle: Handle) -> Option<&mut T> {
+        match self.slots.get_mut(handle.index as usize) {
+            Some(slot) if slot.generation == handle.generation => {slot.value.as_mut()}
+            _ => {None}
+        }
+    }
+
+    pub fn contains(&self, handle: Handle) -> bool {
+        self.get(handle).is_some()
+    }
+
+    pub fn len(&self) -> usize {
+        self.slots.len() - self.free.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (Handle, &T)> {
+        self.slots.iter().enumerate().filter_map(|(i, slot)| {
+            slot.value.as_ref().map(|x| (Handle {index: i as u32, generation: slot.generation}, x))
+        })
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (Handle, &mut T)> {
+        self.slots.iter_mut().enumerate().filter_map(|(i, slot)| {
+            let generation = slot.generation;
+            slot.value.as_mut().map(|x| (Handle {index: i as u32, generation}, x))
+        })
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &T> {
+        self.slots.iter().filter_map(|slot| slot.value.as_ref())
+    }
+
+    pub fn values_mut(&mut self) -> impl Iterator<Item = &mut T> {
+        self.slots.iter_mut().filter_map(|slot| slot.value.as_mut())
+    }
+
+    // Handles of every entry, so the arena can be changed while going through them
+    pub fn handles(&self) -> Vec<Handle> {
+        self.iter().map(|(h, _)| h).collect()
+    }
+
+    // Removes every entry f returns false for
+    pub fn retain(&mut self, mut f: impl FnMut(Handle, &mut T) -> bool) {
+        for h in self.handles() {
+            let keep = match self.get_mut(h) {
+                None => {true}
+                Some(x) => {f(h, x)}
+            };
+            if !keep {
+                self.remove(h);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stale_handles() {
+        let mut arena: Arena<&str> = Arena::new();
+        let a = arena.insert("a");
+        let b = arena.insert("b");
+        assert_eq!(arena.remove(a), Some("a"));
+        assert_eq!(arena.get(a), None);
+        assert_eq!(arena.remove(a), None);
+
+        // The slot is reused, but the old handle still doesn't reach it
+        let c = arena.insert("c");
+        assert_ne!(a, c);
+        assert_eq!(arena.get(a), None);
+        assert_eq!(arena.get(c), Some(&"c"));
+        assert_eq!(arena.len(), 2);
+        assert_eq!(arena.values().copied().collect::<Vec<_>>(), vec!["c", "b"]);
+
+        // Handles survive saving
+        let text = serde_json::to_string(&arena).unwrap();
+        let mut loaded: Arena<String> = serde_json::from_str(&text).unwrap();
+        assert_eq!(loaded.get(b).map(|x| x.as_str()), Some("b"));
+        loaded.retain(|_, x| x != "b");
+        assert!(!loaded.contains(b));
+        assert_eq!(loaded.len(), 1);
+
+        // Free lists that don't match the slots are refused
+        let slots = r#"[{"generation": 1, "value": null}, {"generation": 0, "value": "b"}]"#;
+        for free in ["[0]", "[]", "[0, 0]", "[1]", "[0, 5]"] {
+            let text = format!(r#"{{"slots": {}, "free": {}}}"#, slots, free);
+            assert_eq!(serde_json::from_str::<Arena<String>>(&text).is_ok(), free == "[0]", "{}", free);
+        }
+    }
+}
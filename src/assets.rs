@@ -0,0 +1,52 @@
+use std::collections::HashSet;
+use std::fs::OpenOptions;
+use std::io::{BufReader, Error, ErrorKind};
+
+use serde::{Deserialize, Serialize};
+
+// What an asset is used for, mostly so tools like the editor can list the right ones
+#[derive(Copy, Clone, Serialize, Deserialize, PartialEq, Debug)]
+pub enum AssetCategory {
+    Mob,
+    Projectile,
+    Prop,
+    Screen,
+    Texture,
+    Ui,
+    Flavor
+}
+
+fn default_scale() -> (f64, f64) {
+    (1.0, 1.0)
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct AssetEntry {
+    pub name: String,
+    pub path: String,
+    pub category: AssetCategory,
+    #[serde(default = "default_scale")]
+    pub scale: (f64, f64) // Default billboard size in world units
+}
+
+// List of every image the game loads.  Code and level files refer to assets by name only.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct AssetManifest {
+    pub assets: Vec<AssetEntry>
+}
+
+impl AssetManifest {
+    pub fn load_from_file(filename: &str) -> Result<Self, Error> {
+        let reader = BufReader::new(OpenOptions::new().read(true).open(filename)?);
+        let manifest: AssetManifest = serde_json::from_reader(reader)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, format!("{}: {}", filename, e)))?;
+
+        let mut names: HashSet<&str> = HashSet::new();
+        for a in &manifest.assets {
+            if !names.insert(a.name.as_str()) {
+                return Err(Error::new(ErrorKind::InvalidData, format!("{}: asset {} is listed twice", filename, a.name)));
+            }
+        }
+        Ok(manifest)
+    }
+}
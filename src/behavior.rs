@@ -0,0 +1,237 @@
+use macroquad::math::IVec2;
+use serde::{Deserialize, Serialize};
+
+use crate::pathfinding::PathGoal;
+
+const RETREAT_HP: f64 = 0.25; // Fraction of max HP below which hunters fall back
+const MEDIC_RETREAT_HP: f64 = 0.5;
+pub const HURT_ALLY_HP: f64 = 0.5; // Allies below this fraction of max HP get healed by medics
+
+// The AI a mob runs, set per archetype in mobs.json and per spawn in "params"
+#[derive(Copy, Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum Behavior {
+    #[default]
+    Hunter, // Chases the player and shoots, falls back when badly hurt
+    Sentry, // Keeps to its post or patrol and shoots whatever comes into view
+    Coward, // Keeps away from the player, only fights when cornered
+    Medic   // Heals hurt allies it can see, otherwise a hunter that falls back early
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum BehaviorState {
+    #[default]
+    Idle,
+    Patrol,
+    Alert,   // Lost track of the player, checking where it was last sensed
+    Pursue,
+    Attack,
+    Retreat,
+    HealAlly
+}
+
+// What a mob knows this tick, gathered by the simulation
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Perception {
+    pub player_pos: IVec2,
+    pub senses_player: bool,     // The player is within its line of sight along its path
+    pub clear_shot: bool,        // Nothing between it and the player
+    pub can_hurt_player: bool,   // Its bullets hurt the player, or it will change color so they do
+    pub hp_fraction: f64,
+    pub cornered: bool,          // Running away would not get it anywhere
+    pub has_patrol: bool,
+    pub last_seen: Option<IVec2>, // Where it last sensed the player, until it gets there
+    pub hurt_ally: Option<IVec2>  // A badly hurt ally it has a clear shot at
+}
+
+impl Perception {
+    // Nothing around and nothing remembered
+    pub fn new(player_pos: IVec2) -> Self {
+        Perception {
+            player_pos,
+            senses_player: false,
+            clear_shot: false,
+            can_hurt_player: true,
+            hp_fraction: 1.0,
+            cornered: false,
+            has_patrol: false,
+            last_seen: None,
+            hurt_ally: None
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Movement {
+    Stay,
+    Wander,      // A random open room next to it
+    Patrol,      // Toward the next waypoint of its patrol route
+    Path(PathGoal)
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum FireAt {
+    Player,
+    Ally // The hurt ally, with bullets of its own color
+}
+
+// What a mob wants to do this tick, cooldowns decide whether it gets to
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Intent {
+    pub movement: Movement,
+    pub fire: Option<FireAt>
+}
+
+fn quiet_state(p: &Perception) -> BehaviorState {
+    if p.has_patrol {
+        BehaviorState::Patrol
+    } else {
+        BehaviorState::Idle
+    }
+}
+
+fn hunt(state: BehaviorState, p: &Perception, retreat_hp: f64) -> BehaviorState {
+    if p.senses_player {
+        if p.hp_fraction < retreat_hp && !p.cornered {
+            BehaviorState::Retreat
+        } else if p.clear_shot && p.can_hurt_player {
+            BehaviorState::Attack
+        } else {
+            BehaviorState::Pursue
+        }
+    } else {
+        match state {
+            // Only a mob that was after the player goes looking for it
+            BehaviorState::Pursue | BehaviorState::Attack | BehaviorState::Alert if p.last_seen.is_some() => {
+                BehaviorState::Alert
+            }
+            _ => {quiet_state(p)}
+        }
+    }
+}
+
+pub fn next_state(behavior: Behavior, state: BehaviorState, p: &Perception) -> BehaviorState {
+    match behavior {
+        Behavior::Hunter => {hunt(state, p, RETREAT_HP)}
+        Behavior::Sentry => {
+            if p.senses_player && p.clear_shot && p.can_hurt_player {
+                BehaviorState::Attack
+            } else {
+                quiet_state(p)
+            }
+        }
+        Behavior::Coward => {
+            if p.senses_player && !p.cornered {
+                BehaviorState::Retreat
+            } else if p.senses_player && p.clear_shot && p.can_hurt_player {
+                BehaviorState::Attack
+            } else {
+                quiet_state(p)
+            }
+        }
+        Behavior::Medic => {
+            if p.hurt_ally.is_some() {
+                BehaviorState::HealAlly
+            } else {
+                hunt(state, p, MEDIC_RETREAT_HP)
+            }
+        }
+    }
+}
+
+pub fn intent(behavior: Behavior, state: BehaviorState, p: &Perception) -> Intent {
+    let (movement, fire) = match state {
+        BehaviorState::Idle => {
+            match behavior {
+                Behavior::Sentry => {(Movement::Stay, None)}
+                _ => {(Movement::Wander, None)}
+            }
+        }
+        BehaviorState::Patrol => {(Movement::Patrol, None)}
+        BehaviorState::Alert => {
+            match p.last_seen {
+                None => {(Movement::Stay, None)}
+                Some(x) => {(Movement::Path(PathGoal::Reach(x)), None)}
+            }
+        }
+        BehaviorState::Pursue => {(Movement::Path(PathGoal::Pursue(p.player_pos)), None)}
+        BehaviorState::Attack => {
+            // Hunters close in between shots
+            match behavior {
+                Behavior::Hunter | Behavior::Medic => {(Movement::Path(PathGoal::Pursue(p.player_pos)), Some(FireAt::Player))}
+                Behavior::Sentry | Behavior::Coward => {(Movement::Stay, Some(FireAt::Player))}
+            }
+        }
+        BehaviorState::Retreat => {(Movement::Path(PathGoal::Flee(p.player_pos)), None)}
+        BehaviorState::HealAlly => {(Movement::Stay, Some(FireAt::Ally))}
+    };
+    Intent {
+        movement,
+        fire
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn seen(p: Perception) -> Perception {
+        Perception {
+            senses_player: true,
+            clear_shot: true,
+            last_seen: Some(p.player_pos),
+            ..p
+        }
+    }
+
+    #[test]
+    fn test_hunter() {
+        let quiet = Perception::new(IVec2::new(4, 1));
+        assert_eq!(next_state(Behavior::Hunter, BehaviorState::Idle, &quiet), BehaviorState::Idle);
+        let patrolling = Perception {has_patrol: true, ..quiet};
+        assert_eq!(next_state(Behavior::Hunter, BehaviorState::Idle, &patrolling), BehaviorState::Patrol);
+
+        let p = seen(quiet);
+        assert_eq!(next_state(Behavior::Hunter, BehaviorState::Patrol, &p), BehaviorState::Attack);
+        assert_eq!(intent(Behavior::Hunter, BehaviorState::Attack, &p).fire, Some(FireAt::Player));
+
+        // Same color as the player and stuck with it, so there is no point shooting
+        let harmless = Perception {can_hurt_player: false, ..p};
+        assert_eq!(next_state(Behavior::Hunter, BehaviorState::Attack, &harmless), BehaviorState::Pursue);
+
+        // Lost sight, goes to look where the player was
+        let lost = Perception {senses_player: false, clear_shot: false, ..p};
+        assert_eq!(next_state(Behavior::Hunter, BehaviorState::Pursue, &lost), BehaviorState::Alert);
+        assert_eq!(intent(Behavior::Hunter, BehaviorState::Alert, &lost).movement,
+                   Movement::Path(PathGoal::Reach(IVec2::new(4, 1))));
+        let forgotten = Perception {last_seen: None, ..lost};
+        assert_eq!(next_state(Behavior::Hunter, BehaviorState::Alert, &forgotten), BehaviorState::Idle);
+
+        let hurt = Perception {hp_fraction: 0.2, ..p};
+        assert_eq!(next_state(Behavior::Hunter, BehaviorState::Attack, &hurt), BehaviorState::Retreat);
+        let cornered = Perception {cornered: true, ..hurt};
+        assert_eq!(next_state(Behavior::Hunter, BehaviorState::Retreat, &cornered), BehaviorState::Attack);
+    }
+
+    #[test]
+    fn test_other_behaviors() {
+        let quiet = Perception::new(IVec2::new(4, 1));
+        let p = seen(quiet);
+
+        assert_eq!(intent(Behavior::Sentry, BehaviorState::Idle, &quiet).movement, Movement::Stay);
+        assert_eq!(next_state(Behavior::Sentry, BehaviorState::Idle, &p), BehaviorState::Attack);
+        assert_eq!(intent(Behavior::Sentry, BehaviorState::Attack, &p).movement, Movement::Stay);
+        let blocked = Perception {clear_shot: false, ..p};
+        assert_eq!(next_state(Behavior::Sentry, BehaviorState::Attack, &blocked), BehaviorState::Idle);
+
+        assert_eq!(next_state(Behavior::Coward, BehaviorState::Idle, &p), BehaviorState::Retreat);
+        let cornered = Perception {cornered: true, ..p};
+        assert_eq!(next_state(Behavior::Coward, BehaviorState::Retreat, &cornered), BehaviorState::Attack);
+
+        let ally = Perception {hurt_ally: Some(IVec2::new(2, 1)), ..p};
+        assert_eq!(next_state(Behavior::Medic, BehaviorState::Attack, &ally), BehaviorState::HealAlly);
+        assert_eq!(intent(Behavior::Medic, BehaviorState::HealAlly, &ally).fire, Some(FireAt::Ally));
+        let hurt = Perception {hp_fraction: 0.4, ..p};
+        assert_eq!(next_state(Behavior::Medic, BehaviorState::Attack, &hurt), BehaviorState::Retreat);
+        assert_eq!(next_state(Behavior::Hunter, BehaviorState::Attack, &hurt), BehaviorState::Attack);
+    }
+}
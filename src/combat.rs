@@ -1,7 +1,9 @@
-use crate::mob;
-use crate::mob::{MagicColor, MobId};
+use crate::mob::{MagicColor, MobId, Mobs};
 
 pub const PLAYER_HIT_DISTANCE: f64 = 0.4;
+pub const HAZARD_DAMAGE_PER_SECOND: f64 = 120.0;
+pub const BULLET_DAMAGE: f64 = 33.0; // To mobs, from bullets no spell fired
+pub const BULLET_HIT_DISTANCE: f64 = 0.2; // Bullets closer than this meet
 
 pub enum CollisionType {
     Bullet(MobId, MagicColor)
@@ -9,6 +11,8 @@ pub enum CollisionType {
 
 pub struct Collision {
     pub collision_type: CollisionType,
+    pub shooter: MobId,
+    pub damage: Option<f64>, // Spell damage to a mob, mob bullets do the fixed amount
 }
 
 #[derive(Debug)]
@@ -20,37 +24,45 @@ pub struct DamageReport {
 pub enum DamageIndicator {
     PlayerHit,
     PlayerHeal,
+    MobKilled(MagicColor),
     Other
 }
 
 impl Collision {
-    pub fn new_with_bullet(target: MobId, color: MagicColor) -> Self {
+    pub fn new_with_bullet(target: MobId, color: MagicColor, shooter: MobId, damage: Option<f64>) -> Self {
         Collision {
             collision_type: CollisionType::Bullet(
                 target,
                 color
-            )
+            ),
+            shooter,
+            damage
         }
     }
 
-    pub fn damage_target(&self, player_hp: &mut f64, max_player_hp: f64, player_color: MagicColor) -> DamageIndicator {
-        let player_to_monster_damage: f64 = 33.0;
+    // A mob that is already gone by the time its hit is handled is left alone
+    pub fn damage_target(&self, mobs: &mut Mobs, player_hp: &mut f64, max_player_hp: f64, player_color: MagicColor) -> DamageIndicator {
+        let player_to_monster_damage: f64 = self.damage.unwrap_or(BULLET_DAMAGE);
         let monster_to_player_damage: f64 = 100.0;
         let ct = &self.collision_type;
         match ct {
             CollisionType::Bullet(m, bullet_color) => {
                 match m {
                     MobId::NoMob => {DamageIndicator::Other}
-                    MobId::Mob(mob) => {
-                        let mut m = mob.borrow_mut();
+                    MobId::Mob(handle) => {
+                        let m = match mobs.mob_list.get_mut(*handle) {
+                            None => {return DamageIndicator::Other;}
+                            Some(x) => {x}
+                        };
                         if m.get_color() != *bullet_color {
                             m.hp -= player_to_monster_damage;
                         } else {
                             m.hp += player_to_monster_damage;
                         }
-                        m.hp = m.hp.clamp(0.0, mob::MONSTER_HP);
-                        if m.hp <= 0.0 {
+                        m.hp = m.hp.clamp(0.0, m.max_hp);
+                        if m.hp <= 0.0 && m.is_alive {
                             m.is_alive = false;
+                            return DamageIndicator::MobKilled(m.get_color());
                         }
                         DamageIndicator::Other
                     }
@@ -3,6 +3,7 @@ use macroquad::input::{get_last_key_pressed, KeyCode, mouse_position};
 use macroquad::math::{DVec2, Vec2};
 use macroquad::prelude::{clear_background, draw_circle, draw_line};
 use crate::grid_viewer;
+use crate::grid_viewer::GridViewport;
 use crate::level::Level;
 use crate::raycaster::cast_ray;
 
@@ -16,7 +17,8 @@ impl DebugView {
     pub fn draw_debug_view(&mut self, world: &mut Level, screen_size: (f32, f32)) ->
     Option<(DVec2, DVec2)>{
         clear_background(BLACK);
-        grid_viewer::draw_grid2d(&world.grid, screen_size);
+        let viewport = GridViewport::whole_grid(world.grid.get_size());
+        grid_viewer::draw_grid2d(&world.grid, &viewport, screen_size);
         match get_last_key_pressed() {
             None => {}
             Some(x) => {
@@ -52,12 +54,12 @@ impl DebugView {
         draw_line(self.debug_line.0.x, self.debug_line.0.y,self.debug_line.1.x, self.debug_line.1.y, 1.0, BLUE);
         draw_circle(self.debug_line.0.x, self.debug_line.0.y, 7.0, BLUE);
 
-        let ray_dir = world.grid.screen_to_grid_coords((self.debug_line.1 - self.debug_line.0).as_dvec2(), screen_size);
-        let ray_start = world.grid.screen_to_grid_coords(self.debug_line.0.as_dvec2(), screen_size);
+        let ray_dir = viewport.screen_to_grid_coords((self.debug_line.1 - self.debug_line.0).as_dvec2(), screen_size);
+        let ray_start = viewport.screen_to_grid_coords(self.debug_line.0.as_dvec2(), screen_size);
 
         let (perp_hit_dist, _, _, _) = cast_ray( &world.grid, &ray_start, &ray_dir, 256.0);
 
-        let first_step = world.grid.grid_to_screen_coords(ray_start + perp_hit_dist*ray_dir, screen_size).as_vec2();
+        let first_step = viewport.grid_to_screen_coords(ray_start + perp_hit_dist*ray_dir, screen_size).as_vec2();
 
         draw_circle(first_step.x, first_step.y, 2.0, RED);
 
@@ -1,5 +1,5 @@
 use crate::grid2d::WallGridCell::{Empty, Wall};
-use crate::image::ImageLoader;
+use crate::image::{save_png, ImageLoader};
 use macroquad::color::{Color, BLACK, BLUE, DARKGREEN, GREEN, RED, SKYBLUE, WHITE};
 use macroquad::input::KeyCode::P;
 use macroquad::math::{DVec2, Vec2};
@@ -17,23 +17,45 @@ pub fn fog_factor(distance: f64, max_distance: f64) -> f64 {
     f64::exp(-(2.0 * distance / max_distance).powi(2))
 }
 
+// Tint applied to the side walls of see-through cells so they can be told apart
+fn cell_tint(cell: Option<&WallGridCell>) -> [f32; 3] {
+    match cell {
+        Some(WallGridCell::Ladder) => [0.8, 0.65, 0.45],
+        Some(WallGridCell::Water) => [0.4, 0.6, 1.0],
+        Some(WallGridCell::Hazard) => [1.0, 0.35, 0.3],
+        Some(WallGridCell::Ledge) => [1.0, 0.8, 0.4],
+        Some(WallGridCell::WhitePhase) => [1.5, 1.5, 1.6], // Brightened, saturates toward white
+        Some(WallGridCell::BlackPhase) => [0.3, 0.25, 0.4],
+        _ => [1.0, 1.0, 1.0],
+    }
+}
+
+fn tint_pixel(pixel: [u8; 4], tint: [f32; 3]) -> [u8; 4] {
+    [
+        (pixel[0] as f32 * tint[0]) as u8,
+        (pixel[1] as f32 * tint[1]) as u8,
+        (pixel[2] as f32 * tint[2]) as u8,
+        pixel[3],
+    ]
+}
+
+// CPU side renderer, everything is drawn into render_image so it works without a window
 pub struct FirstPersonViewer {
     pub render_size: (u16, u16),
     pub render_image: Image,
-    render_texture: Texture2D,
     pub z_buffer: Vec<f64>,
 }
 
-pub type SpriteId = usize;
-pub struct RoomTextureBindings {
-    pub floor: SpriteId,
-    pub wall: SpriteId,
-    pub ceiling: SpriteId,
+// GPU texture that a finished render_image is copied into and drawn to the screen with
+pub struct ScreenTexture {
+    texture: Texture2D,
 }
 
+pub type SpriteId = usize;
+
+// How side wall textures are mapped, the textures themselves come from the room material
 #[derive(Clone, Copy)]
 pub struct WallTextureBinding {
-    pub sprite_id: SpriteId,
     pub repeat_speed: f64,
     pub pin: bool,
 }
@@ -47,8 +69,6 @@ pub struct WallTextureBindings {
 impl FirstPersonViewer {
     pub fn new(width: u16, height: u16) -> Self {
         let render_image = Image::gen_image_color(width, height, BLACK);
-        let render_texture = Texture2D::from_image(&render_image);
-        render_texture.set_filter(FilterMode::Nearest);
         let z_size = height.max(width);
         let mut z_buffer: Vec<f64> = Vec::with_capacity(z_size as usize);
         for _ in 0..z_size as usize {
@@ -58,7 +78,6 @@ impl FirstPersonViewer {
         FirstPersonViewer {
             render_size: (width, height),
             render_image,
-            render_texture,
             z_buffer,
         }
     }
@@ -82,7 +101,6 @@ impl FirstPersonViewer {
         pos: DVec2,
         dir: DVec2,
         plane_scale: f64,
-        texture_bindings: &RoomTextureBindings,
         wall_texture_bindings: &WallTextureBindings,
         sprite_manager: &ImageLoader,
         hide_floor_ceiling: bool,
@@ -108,29 +126,7 @@ impl FirstPersonViewer {
 
         let wall_pin = wall_texture_bindings.left.pin;
         let wall_speed = wall_texture_bindings.left.repeat_speed;
-
-        let wall_texture_bindings = if dir_x_sign > 0.0 {
-            *wall_texture_bindings
-        } else {
-            WallTextureBindings {
-                left: wall_texture_bindings.right,
-                right: wall_texture_bindings.left,
-            }
-        };
-
-        let left_wall_image = sprite_manager.get_image(wall_texture_bindings.left.sprite_id);
-        let (left_wall_pixels, left_wall_width, left_wall_height) = (
-            left_wall_image.get_image_data(),
-            left_wall_image.width as usize,
-            left_wall_image.height as usize,
-        );
-
-        let right_wall_image = sprite_manager.get_image(wall_texture_bindings.right.sprite_id);
-        let (right_wall_pixels, right_wall_width, right_wall_height) = (
-            right_wall_image.get_image_data(),
-            right_wall_image.width as usize,
-            right_wall_image.height as usize,
-        );
+        let materials = world.resolve_materials(sprite_manager);
 
         for y in 0..(render_height as usize) {
             let camera_y = up * (2.0 * (y as f64) / (render_height as f64) - 1.0);
@@ -156,14 +152,14 @@ impl FirstPersonViewer {
 
             // Store z buffer
             match hit_type {
-                Empty => self.z_buffer[y] = f64::INFINITY,
                 Wall => self.z_buffer[y] = perp_wall_dist,
+                _ => self.z_buffer[y] = f64::INFINITY,
             }
 
             // tex size
             let mut hit_ceiling = false;
+            let material = world.get_material(map_coord, &materials);
             let sid: Option<SpriteId> = match hit_type {
-                Empty => None,
                 Wall => {
                     match hit_side {
                         Horizontal => {
@@ -174,16 +170,17 @@ impl FirstPersonViewer {
                                 None
                             } else {
                                 if ray_dir.y > 0.0 {
-                                    Some(texture_bindings.floor)
+                                    Some(material.floor)
                                 } else {
                                     hit_ceiling = true;
-                                    Some(texture_bindings.ceiling)
+                                    Some(material.ceiling)
                                 }
                             }
                         }
-                        Vertical => Some(texture_bindings.wall), //side
+                        Vertical => Some(material.wall), //side
                     }
                 }
+                _ => None,
             };
 
             // Fog and misc variables for textures
@@ -225,7 +222,7 @@ impl FirstPersonViewer {
                     let tex_x = (tex_pos as usize).clamp(0, tex_width_u - 1);
                     tex_pos += step;
 
-                    let cvp = wall_pixels[tex_y * tex_height_u + tex_x];
+                    let cvp = wall_pixels[tex_y * tex_width_u + tex_x];
                     let cv = Color::from_rgba(cvp[0], cvp[1], cvp[2], 255).to_vec();
 
                     let pixel = &mut rd[y * rw + x];
@@ -249,7 +246,7 @@ impl FirstPersonViewer {
 
                     let (u, v) = if !wall_pin {
                         let uv =
-                            apply_boundary_conditions_f64(current_floor_pos, world.grid.get_size());
+                            apply_boundary_conditions_f64(current_floor_pos, world.grid.get_size(), world.grid.get_topology());
                         (1.0 - uv.x / world_size.x, uv.y / world_size.y)
                     } else {
                         let distx =
@@ -261,6 +258,27 @@ impl FirstPersonViewer {
                         )
                     };
 
+                    // Side walls are textured by the room they belong to
+                    let room = current_floor_pos.floor().as_ivec2();
+                    let room_material = world.get_material(room, &materials);
+                    let (left_sprite, right_sprite) = if dir_x_sign > 0.0 {
+                        (room_material.left, room_material.right)
+                    } else {
+                        (room_material.right, room_material.left)
+                    };
+                    let left_wall_image = sprite_manager.get_image(left_sprite);
+                    let (left_wall_pixels, left_wall_width, left_wall_height) = (
+                        left_wall_image.get_image_data(),
+                        left_wall_image.width as usize,
+                        left_wall_image.height as usize,
+                    );
+                    let right_wall_image = sprite_manager.get_image(right_sprite);
+                    let (right_wall_pixels, right_wall_width, right_wall_height) = (
+                        right_wall_image.get_image_data(),
+                        right_wall_image.width as usize,
+                        right_wall_image.height as usize,
+                    );
+
                     // Left wall tex coords
                     let left_tex_x = ((left_wall_width - 1) as f64 * u) as usize;
                     let left_tex_y = ((left_wall_height - 1) as f64 * v) as usize;
@@ -269,10 +287,15 @@ impl FirstPersonViewer {
                     let right_tex_x = ((right_wall_width - 1) as f64 * u) as usize;
                     let right_tex_y = ((right_wall_height - 1) as f64 * v) as usize;
 
-                    let left_wall_color =
-                        left_wall_pixels[left_tex_y * left_wall_width + left_tex_x];
-                    let right_wall_color =
-                        right_wall_pixels[right_tex_y * right_wall_width + right_tex_x];
+                    let tint = cell_tint(world.grid.get_cell_at_grid_coords_int(room));
+                    let left_wall_color = tint_pixel(
+                        left_wall_pixels[left_tex_y * left_wall_width + left_tex_x],
+                        tint,
+                    );
+                    let right_wall_color = tint_pixel(
+                        right_wall_pixels[right_tex_y * right_wall_width + right_tex_x],
+                        tint,
+                    );
 
                     rd[y * rw + x] = left_wall_color;
                     rd[y * rw + (render_width as usize - 1 - x)] = right_wall_color;
@@ -295,6 +318,7 @@ impl FirstPersonViewer {
         hide_floor_ceiling: bool,
         lhs: f64,
         wall_texture_bindings: &WallTextureBindings,
+        wall_sprite: SpriteId,
         player_y: f64,
         vert_world_size: Vec2
     ) {
@@ -332,8 +356,8 @@ impl FirstPersonViewer {
 
             // Store z buffer
             match hit_type {
-                WallGridCell::Empty => self.z_buffer[x] = f64::INFINITY,
                 WallGridCell::Wall => self.z_buffer[x] = perp_wall_dist,
+                _ => self.z_buffer[x] = f64::INFINITY,
             }
 
             let dist_wall = perp_wall_dist;
@@ -395,7 +419,7 @@ impl FirstPersonViewer {
                 let draw_start_2 = 0.max(true_draw_start);
                 let draw_end = h.min(line_height_2 / 2 + h / 2) as usize;
 
-                let image = image_manager.get_image(wall_texture_bindings.left.sprite_id);
+                let image = image_manager.get_image(wall_sprite);
                 let tex_width_u = image.width as usize;
                 let tex_width = image.width as f64;
                 let tex_height = image.height as f64;
@@ -488,7 +512,19 @@ impl FirstPersonViewer {
         }
     }
 
-    pub fn render(&self, screen_size: (f32, f32)) {
+    pub fn save_png(&self, filename: &str) -> Result<(), std::io::Error> {
+        save_png(&self.render_image, filename)
+    }
+}
+
+impl ScreenTexture {
+    pub fn new(width: u16, height: u16) -> Self {
+        let texture = Texture2D::from_image(&Image::gen_image_color(width, height, BLACK));
+        texture.set_filter(FilterMode::Nearest);
+        ScreenTexture { texture }
+    }
+
+    pub fn render(&self, view: &FirstPersonViewer, screen_size: (f32, f32)) {
         // Update texture
         let render_texture_params = DrawTextureParams {
             dest_size: Some(Vec2::from(screen_size)),
@@ -498,7 +534,7 @@ impl FirstPersonViewer {
             flip_y: false,
             pivot: None,
         };
-        self.render_texture.update(&self.render_image);
-        draw_texture_ex(&self.render_texture, 0., 0., WHITE, render_texture_params);
+        self.texture.update(&view.render_image);
+        draw_texture_ex(&self.texture, 0., 0., WHITE, render_texture_params);
     }
 }
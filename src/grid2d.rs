@@ -1,5 +1,6 @@
-use macroquad::math::{DVec2, IVec2};
-use macroquad::prelude::Vec2;
+use std::io::{Error, ErrorKind};
+
+use macroquad::math::IVec2;
 use serde::{Deserialize, Serialize};
 use serde::de::DeserializeOwned;
 use crate::level::apply_boundary_conditions_i32;
@@ -8,13 +9,147 @@ use crate::level::apply_boundary_conditions_i32;
 pub enum WallGridCell {
     #[default]
     Empty,
-    Wall
+    Wall,
+    Ladder, // Climb freely up and down, never fall off
+    Water,  // Float anywhere inside, swim up and down
+    Hazard, // Passable, but hurts while standing in it
+    Ledge,  // One-way platform, can be climbed up through but not dropped down through
+    WhitePhase, // Solid while the player or mob is white, open while black
+    BlackPhase  // Solid while black, open while white
+}
+
+impl WallGridCell {
+    pub const ALL: [WallGridCell; 8] = [
+        WallGridCell::Empty,
+        WallGridCell::Wall,
+        WallGridCell::Ladder,
+        WallGridCell::Water,
+        WallGridCell::Hazard,
+        WallGridCell::Ledge,
+        WallGridCell::WhitePhase,
+        WallGridCell::BlackPhase
+    ];
+}
+
+// How positions outside of the grid map back onto it
+#[derive(Default, Copy, Clone, Serialize, Deserialize, PartialEq, Debug)]
+pub enum Topology {
+    #[default]
+    WrapX,  // Infinite corridor.  Wrap horizontal, clamp vertical
+    Torus,  // Wrap both axes
+    Clamp,  // Bounded dungeon, clamp both axes
+    Mirror  // Reflect at every edge
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum EdgeRule {
+    Wrap,
+    Clamp,
+    Mirror
+}
+
+impl Topology {
+    pub const ALL: [Topology; 4] = [
+        Topology::WrapX,
+        Topology::Torus,
+        Topology::Clamp,
+        Topology::Mirror
+    ];
+
+    // Rules for the horizontal and vertical axis
+    pub fn edge_rules(&self) -> (EdgeRule, EdgeRule) {
+        match self {
+            Topology::WrapX => {(EdgeRule::Wrap, EdgeRule::Clamp)}
+            Topology::Torus => {(EdgeRule::Wrap, EdgeRule::Wrap)}
+            Topology::Clamp => {(EdgeRule::Clamp, EdgeRule::Clamp)}
+            Topology::Mirror => {(EdgeRule::Mirror, EdgeRule::Mirror)}
+        }
+    }
 }
 
-#[derive(Serialize, Deserialize)]
+impl EdgeRule {
+    // Index of the cell a coordinate refers to, None if it is outside a clamped edge
+    pub fn cell_index(&self, p: i32, size: usize) -> Option<usize> {
+        let n = size as i32;
+        match self {
+            EdgeRule::Wrap => {Some(self.apply_i32(p, size) as usize)}
+            EdgeRule::Clamp => {
+                if p < 0 || p >= n {
+                    None
+                } else {
+                    Some(p as usize)
+                }
+            }
+            EdgeRule::Mirror => {Some(self.apply_i32(p, size) as usize)}
+        }
+    }
+
+    pub fn apply_i32(&self, p: i32, size: usize) -> i32 {
+        let n = size as i32;
+        match self {
+            EdgeRule::Wrap => {
+                let ps = p % n;
+                if ps < 0 {
+                    n - (ps.abs() % n)
+                } else {
+                    ps
+                }
+            }
+            EdgeRule::Clamp => {p.clamp(0, n - 1)}
+            EdgeRule::Mirror => {
+                let m = p.rem_euclid(2 * n);
+                if m >= n {
+                    2 * n - 1 - m
+                } else {
+                    m
+                }
+            }
+        }
+    }
+
+    pub fn apply_f64(&self, p: f64, size: usize) -> f64 {
+        let n = size as f64;
+        match self {
+            EdgeRule::Wrap => {
+                if p < 0.0 {
+                    let nr = (p.abs() / n).floor();
+                    n - (p.abs() - (n * nr))
+                } else if p >= n {
+                    let nr = (p / n).floor();
+                    p - (n * nr)
+                } else {
+                    p
+                }
+            }
+            EdgeRule::Clamp => {p.clamp(0.0, n)}
+            EdgeRule::Mirror => {
+                let m = p.rem_euclid(2.0 * n);
+                if m > n {
+                    2.0 * n - m
+                } else {
+                    m
+                }
+            }
+        }
+    }
+
+    // Coordinates that refer to the same point as p once the rule is applied, nearest copies only
+    pub fn images_f64(&self, p: f64, size: usize) -> Vec<f64> {
+        let n = size as f64;
+        match self {
+            EdgeRule::Wrap => {vec![p, p - n, p + n]}
+            EdgeRule::Clamp => {vec![p]}
+            EdgeRule::Mirror => {vec![p, -p, 2.0 * n - p]}
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
 pub struct Grid2D<T> {
     width: usize,
     height: usize,
+    #[serde(default)]
+    topology: Topology,
     cells: Vec<T>
 }
 
@@ -23,37 +158,47 @@ impl<T: Serialize + DeserializeOwned + Default> Grid2D<T>{
         Grid2D {
             width,
             height,
+            topology: Topology::default(),
             cells: Vec::with_capacity(width*height)
         }
     }
 
+    // Every lookup wraps or clamps against the size, so an empty grid is never valid
+    pub fn from_cells(width: usize, height: usize, cells: Vec<T>) -> Result<Self, Error> {
+        if width == 0 || height == 0 {
+            return Err(Error::new(ErrorKind::InvalidData, format!("grid is {}x{}, it needs at least one cell", width, height)));
+        }
+        if width.checked_mul(height) != Some(cells.len()) {
+            return Err(Error::new(ErrorKind::InvalidData, format!("grid does not have {}x{} cells", width, height)));
+        }
+        Ok(Grid2D {
+            width,
+            height,
+            topology: Topology::default(),
+            cells
+        })
+    }
+
     pub fn get_size(&self) -> (usize, usize) {
         (self.width, self.height)
     }
 
-    pub fn get_cells(&self) -> &Vec<T> {
-        &self.cells
+    pub fn get_topology(&self) -> Topology {
+        self.topology
     }
 
-    pub fn get_cell_screen_size(&self, screen_size: (f32, f32)) -> Vec2{
-        let cell_width: f32 = screen_size.0 / (self.width as f32);
-        let cell_height: f32 = screen_size.1 / (self.height as f32);
-        Vec2::from((cell_width, cell_height))
+    pub fn set_topology(&mut self, topology: Topology) {
+        self.topology = topology;
     }
 
-    pub fn get_cell_at_grid_coords_int(&self, pos: IVec2) -> Option<&T> {
-        if pos.y < 0 || pos.y >= self.height as i32 {
-            return None;
-        }
-
-        let xs = pos.x % self.width as i32;
-        let x = if xs < 0 {
-            self.width - (xs.abs() as usize % self.width)
-        } else {
-            xs as usize
-        };
+    pub fn get_cells(&self) -> &Vec<T> {
+        &self.cells
+    }
 
-        let y = pos.y as usize;
+    pub fn get_cell_at_grid_coords_int(&self, pos: IVec2) -> Option<&T> {
+        let (x_rule, y_rule) = self.topology.edge_rules();
+        let x = x_rule.cell_index(pos.x, self.width)?;
+        let y = y_rule.cell_index(pos.y, self.height)?;
         debug_assert!(x < self.width);
         debug_assert!(y < self.height);
         self.cells.get(y * self.width + x)
@@ -65,7 +210,7 @@ impl<T: Serialize + DeserializeOwned + Default> Grid2D<T>{
             return None;
         }
 
-        let pos= apply_boundary_conditions_i32(pos, self.get_size());
+        let pos= apply_boundary_conditions_i32(pos, self.get_size(), self.topology);
 
         let x= pos.x as usize;
         let y = pos.y as usize;
@@ -75,30 +220,55 @@ impl<T: Serialize + DeserializeOwned + Default> Grid2D<T>{
         Some(())
     }
 
-    pub fn screen_to_grid_coords(&self, pos: DVec2, screen_size: (f32, f32)) -> DVec2 {
-        let (sw, sh) = screen_size;
-        let cell_w =  sw as f64 / self.width as f64;
-        let cell_h = sh as f64 / self.height as f64;
-        DVec2 {
-            x: pos.x / cell_w,
-            y: pos.y / cell_h
+    pub fn zero(&mut self) {
+        self.cells = Vec::new();
+        for _ in 0..self.width * self.height {
+            self.cells.push(T::default());
         }
     }
+}
 
-    pub fn grid_to_screen_coords(&self, pos: DVec2, screen_size: (f32, f32)) -> DVec2 {
-        let (sw, sh) = screen_size;
-        let cell_w =  sw as f64 / self.width as f64;
-        let cell_h = sh as f64 / self.height as f64;
-        DVec2 {
-            x: pos.x * cell_w,
-            y: pos.y * cell_h
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_edge_rules() {
+        assert_eq!(EdgeRule::Wrap.apply_i32(-1, 16), 15);
+        assert_eq!(EdgeRule::Wrap.apply_i32(16, 16), 0);
+        assert_eq!(EdgeRule::Clamp.apply_i32(-3, 16), 0);
+        assert_eq!(EdgeRule::Clamp.cell_index(16, 16), None);
+        assert_eq!(EdgeRule::Mirror.apply_i32(-1, 16), 0);
+        assert_eq!(EdgeRule::Mirror.apply_i32(17, 16), 14);
+        assert_eq!(EdgeRule::Mirror.apply_f64(-0.25, 16), 0.25);
+        assert_eq!(EdgeRule::Wrap.apply_f64(-0.25, 16), 15.75);
     }
 
-    pub fn zero(&mut self) {
-        self.cells = Vec::new();
-        for _ in 0..self.width * self.height {
-            self.cells.push(T::default());
+    #[test]
+    fn test_grid_lookup_topology() {
+        let mut grid: Grid2D<WallGridCell> = Grid2D::new(4, 4);
+        grid.zero();
+        grid.set_cell_at_grid_coords_int(IVec2::new(0, 0), WallGridCell::Wall);
+
+        assert_eq!(grid.get_cell_at_grid_coords_int(IVec2::new(4, 0)), Some(&WallGridCell::Wall));
+        assert_eq!(grid.get_cell_at_grid_coords_int(IVec2::new(0, 4)), None);
+
+        grid.set_topology(Topology::Torus);
+        assert_eq!(grid.get_cell_at_grid_coords_int(IVec2::new(4, 4)), Some(&WallGridCell::Wall));
+
+        grid.set_topology(Topology::Clamp);
+        assert_eq!(grid.get_cell_at_grid_coords_int(IVec2::new(4, 0)), None);
+
+        grid.set_topology(Topology::Mirror);
+        assert_eq!(grid.get_cell_at_grid_coords_int(IVec2::new(-1, -1)), Some(&WallGridCell::Wall));
+    }
+
+    #[test]
+    fn test_from_cells_size() {
+        assert!(Grid2D::from_cells(2, 1, vec![WallGridCell::Empty; 2]).is_ok());
+        for (width, height, count) in [(0, 0, 0), (0, 3, 0), (3, 0, 0), (2, 1, 3)] {
+            let err = Grid2D::from_cells(width, height, vec![WallGridCell::Empty; count]).err().unwrap();
+            assert_eq!(err.kind(), ErrorKind::InvalidData);
         }
     }
 }
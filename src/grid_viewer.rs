@@ -1,28 +1,136 @@
 use crate::grid2d::{Grid2D, WallGridCell};
+use crate::level::Level;
 use macroquad::prelude::*;
 
-pub fn draw_grid2d(world: &Grid2D<WallGridCell>, screen_size: (f32, f32)) {
-    let (ww, wh) = world.get_size();
-    let cell_dim = world.get_cell_screen_size(screen_size);
-    let cell_border: f32 = 1.0;
+// Largest number of cells shown at once before the view has to be panned
+const MAX_VIEW_CELLS: (f64, f64) = (64.0, 64.0);
+const MIN_VIEW_CELLS: f64 = 4.0;
+
+// The rectangle of grid cells shown on screen, in grid coordinates
+pub struct GridViewport {
+    origin: DVec2,
+    view_size: DVec2,
+    world_size: DVec2,
+}
+
+impl GridViewport {
+    pub fn new(world_size: (usize, usize)) -> Self {
+        let world_size = DVec2::new(world_size.0 as f64, world_size.1 as f64);
+        GridViewport {
+            origin: DVec2::ZERO,
+            view_size: DVec2::new(world_size.x.min(MAX_VIEW_CELLS.0), world_size.y.min(MAX_VIEW_CELLS.1)),
+            world_size,
+        }
+    }
+
+    // Shows the whole grid regardless of its size
+    pub fn whole_grid(world_size: (usize, usize)) -> Self {
+        let world_size = DVec2::new(world_size.0 as f64, world_size.1 as f64);
+        GridViewport {
+            origin: DVec2::ZERO,
+            view_size: world_size,
+            world_size,
+        }
+    }
+
+    pub fn get_world_size(&self) -> (usize, usize) {
+        (self.world_size.x as usize, self.world_size.y as usize)
+    }
+
+    pub fn cell_screen_size(&self, screen_size: (f32, f32)) -> Vec2 {
+        Vec2::new(screen_size.0 / self.view_size.x as f32, screen_size.1 / self.view_size.y as f32)
+    }
+
+    pub fn grid_to_screen_coords(&self, pos: DVec2, screen_size: (f32, f32)) -> DVec2 {
+        let scale = DVec2::new(screen_size.0 as f64, screen_size.1 as f64) / self.view_size;
+        (pos - self.origin) * scale
+    }
+
+    pub fn screen_to_grid_coords(&self, pos: DVec2, screen_size: (f32, f32)) -> DVec2 {
+        let scale = DVec2::new(screen_size.0 as f64, screen_size.1 as f64) / self.view_size;
+        pos / scale + self.origin
+    }
+
+    pub fn center_on(&mut self, pos: DVec2) {
+        self.origin = pos - 0.5 * self.view_size;
+        self.clamp_to_world();
+    }
+
+    // Zooms around a fixed point in grid coordinates, factor < 1 zooms in
+    pub fn zoom(&mut self, factor: f64, focus: DVec2) {
+        let max_view = DVec2::new(self.world_size.x, self.world_size.y);
+        let min_view = DVec2::splat(MIN_VIEW_CELLS).min(max_view);
+        let new_size = (self.view_size * factor).clamp(min_view, max_view);
+        let focus_frac = (focus - self.origin) / self.view_size;
+        self.origin = focus - focus_frac * new_size;
+        self.view_size = new_size;
+        self.clamp_to_world();
+    }
+
+    pub fn pan(&mut self, delta: DVec2) {
+        self.origin += delta;
+        self.clamp_to_world();
+    }
+
+    fn clamp_to_world(&mut self) {
+        let max_origin = (self.world_size - self.view_size).max(DVec2::ZERO);
+        self.origin = self.origin.clamp(DVec2::ZERO, max_origin);
+    }
 
-    for y in 0..wh {
-        for x in 0..ww {
-            let cells = world.get_cells();
-            let cell = &cells[y * ww + x];
-            let cell_pos = Vec2::from((
-                x as f32 * cell_dim.x,
-                y as f32 * cell_dim.y
-            ));
+    // Mouse wheel zooms around the cursor, arrow keys pan
+    pub fn handle_input(&mut self, screen_size: (f32, f32)) {
+        let (_, wheel) = mouse_wheel();
+        if wheel != 0.0 {
+            let focus = self.screen_to_grid_coords(Vec2::from(mouse_position()).as_dvec2(), screen_size);
+            let factor = if wheel > 0.0 { 0.8 } else { 1.25 };
+            self.zoom(factor, focus);
+        }
 
-            draw_grid2d_cell(cell_pos, *cell, cell_border, world, screen_size);
+        let pan_speed = 0.5 * self.view_size.max_element() * get_frame_time() as f64;
+        let mut delta = DVec2::ZERO;
+        if is_key_down(KeyCode::Left) {
+            delta.x -= pan_speed;
+        }
+        if is_key_down(KeyCode::Right) {
+            delta.x += pan_speed;
+        }
+        if is_key_down(KeyCode::Up) {
+            delta.y -= pan_speed;
+        }
+        if is_key_down(KeyCode::Down) {
+            delta.y += pan_speed;
         }
+        if delta != DVec2::ZERO {
+            self.pan(delta);
+        }
+    }
+
+    // First and one past the last cell on screen
+    pub fn visible_cells(&self) -> (IVec2, IVec2) {
+        let start = self.origin.floor().as_ivec2().max(IVec2::ZERO);
+        let end = (self.origin + self.view_size).ceil().as_ivec2().min(self.world_size.as_ivec2());
+        (start, end)
     }
 }
 
-pub fn draw_grid2d_cell(pos: Vec2, cell_type: WallGridCell, cell_border: f32, world: &Grid2D<WallGridCell>, screen_size: (f32, f32)) {
-    let cell_dim = world.get_cell_screen_size(screen_size);
+pub fn draw_grid2d(world: &Grid2D<WallGridCell>, viewport: &GridViewport, screen_size: (f32, f32)) {
+    let (ww, _) = world.get_size();
+    let cell_dim = viewport.cell_screen_size(screen_size);
+    let cell_border: f32 = 1.0;
+    let cells = world.get_cells();
+    let (start, end) = viewport.visible_cells();
+
+    for y in start.y..end.y {
+        for x in start.x..end.x {
+            let cell = &cells[y as usize * ww + x as usize];
+            let cell_pos = viewport.grid_to_screen_coords(DVec2::new(x as f64, y as f64), screen_size).as_vec2();
+
+            draw_grid2d_cell(cell_pos, *cell, cell_border, cell_dim);
+        }
+    }
+}
 
+pub fn draw_grid2d_cell(pos: Vec2, cell_type: WallGridCell, cell_border: f32, cell_dim: Vec2) {
     let color = match cell_type {
         WallGridCell::Empty => {
             GRAY
@@ -30,9 +138,50 @@ pub fn draw_grid2d_cell(pos: Vec2, cell_type: WallGridCell, cell_border: f32, wo
         WallGridCell::Wall => {
             GOLD
         }
+        WallGridCell::Ladder => {
+            BROWN
+        }
+        WallGridCell::Water => {
+            BLUE
+        }
+        WallGridCell::Hazard => {
+            RED
+        }
+        WallGridCell::Ledge => {
+            ORANGE
+        }
+        WallGridCell::WhitePhase => {
+            WHITE
+        }
+        WallGridCell::BlackPhase => {
+            BLACK
+        }
     };
 
-    draw_rectangle(pos.x + cell_border, pos.y + cell_border,
-                   cell_dim.x - cell_border, cell_dim.y - cell_border,
+    // Keep cells visible when zoomed far out
+    let border = cell_border.min(0.25 * cell_dim.x.min(cell_dim.y));
+    draw_rectangle(pos.x + border, pos.y + border,
+                   cell_dim.x - border, cell_dim.y - border,
                    color);
-}
\ No newline at end of file
+}
+
+// Writes the material id of every room that does not use the default material
+pub fn draw_material_ids(world: &Level, viewport: &GridViewport, screen_size: (f32, f32)) {
+    let cell_dim = viewport.cell_screen_size(screen_size);
+    let font_size = cell_dim.y.min(cell_dim.x * 2.0);
+    let (start, end) = viewport.visible_cells();
+
+    for y in start.y..end.y {
+        for x in start.x..end.x {
+            let id = world.get_material_id(IVec2::new(x, y));
+            if id != 0 {
+                let p = viewport.grid_to_screen_coords(DVec2::new(x as f64, (y + 1) as f64), screen_size).as_vec2();
+                draw_text(format!("{}", id).as_str(),
+                          p.x + 2.0,
+                          p.y - 2.0,
+                          font_size,
+                          WHITE);
+            }
+        }
+    }
+}
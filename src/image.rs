@@ -1,55 +1,165 @@
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
 use std::path::Path;
 
-use macroquad::prelude::{Image, load_image};
+use macroquad::math::DVec4;
+use macroquad::prelude::{Image, ImageFormat, load_image, Color, BLACK, MAGENTA};
+
+use crate::assets::{AssetCategory, AssetEntry, AssetManifest};
 
 pub struct GameImage {
     image: Image,
+    name: String,
+    category: AssetCategory,
+    scale: DVec4
 }
 
 pub struct ImageLoader  {
-    images: Vec<GameImage>
+    images: Vec<GameImage>,
+    names: HashMap<String, ImageId>
 }
 
 pub type ImageId = usize;
 
+// Stands in for any image that is missing, broken or looked up with a bad id
+const PLACEHOLDER_ID: ImageId = 0;
+const PLACEHOLDER_SIZE: u16 = 16;
+
+pub fn checkerboard_image(size: u16, a: Color, b: Color) -> Image {
+    let mut image = Image::gen_image_color(size, size, a);
+    let cell = (size / 4).max(1);
+    for y in 0..size {
+        for x in 0..size {
+            if (x / cell + y / cell) % 2 == 1 {
+                image.set_pixel(x as u32, y as u32, b);
+            }
+        }
+    }
+    image
+}
+
+// Writes an image as a PNG, the first row of the image is the top
+pub fn save_png(image: &Image, filename: &str) -> Result<(), Error> {
+    ::image::save_buffer(filename, &image.bytes, image.width as u32, image.height as u32, ::image::ColorType::Rgba8)
+        .map_err(|e| Error::new(ErrorKind::Other, format!("{}: {}", filename, e)))
+}
+
+pub fn load_png(filename: &str) -> Result<Image, Error> {
+    let bytes = std::fs::read(filename)?;
+    Image::from_file_with_format(&bytes, Some(ImageFormat::Png))
+        .map_err(|e| Error::new(ErrorKind::InvalidData, format!("{}: {}", filename, e)))
+}
+
 impl ImageLoader {
     pub fn new() -> Self {
+        let placeholder = GameImage {
+            image: checkerboard_image(PLACEHOLDER_SIZE, MAGENTA, BLACK),
+            name: "placeholder".to_string(),
+            category: AssetCategory::Texture,
+            scale: DVec4::new(0.5, 0.5, 0.0, 0.0)
+        };
         ImageLoader {
-            images: Vec::new()
+            images: vec![placeholder],
+            names: HashMap::new()
+        }
+    }
+
+    // Loads an image, returns its image ID.  Images that fail to load get a placeholder so
+    // the name still resolves and the game keeps running.
+    pub async fn load_game_image(&mut self, asset: &AssetEntry) -> ImageId {
+        let name = Path::new(asset.path.as_str());
+        let image = load_image(name.to_str().unwrap()).await.map_err(|e| e.to_string());
+        self.add_game_image(asset, image)
+    }
+
+    pub async fn load_manifest(&mut self, manifest: &AssetManifest) {
+        for asset in &manifest.assets {
+            self.load_game_image(asset).await;
+        }
+    }
+
+    // Same as load_manifest, but reads the files directly so it also works with no window
+    pub fn load_manifest_from_disk(&mut self, manifest: &AssetManifest) {
+        for asset in &manifest.assets {
+            let image = std::fs::read(&asset.path).map_err(|e| e.to_string()).and_then(|bytes| {
+                Image::from_file_with_format(&bytes, None).map_err(|e| e.to_string())
+            });
+            self.add_game_image(asset, image);
         }
     }
 
-    // Loads an image, returns its image ID
-    pub async fn load_game_image(&mut self, name: &Path) -> Option<ImageId> {
-        match  load_image(name.to_str().unwrap()).await {
-            Ok(im) => {
-                self.images.push(GameImage {
-                    image: im,
-                });
-                Some(self.images.len() - 1)
+    fn add_game_image(&mut self, asset: &AssetEntry, image: Result<Image, String>) -> ImageId {
+        let image = match image {
+            Ok(im) if im.width > 0 && im.height > 0 => {im}
+            Ok(_) => {
+                eprintln!("Warning: image {} is empty, using a placeholder", asset.path);
+                checkerboard_image(PLACEHOLDER_SIZE, MAGENTA, BLACK)
             }
             Err(e) => {
-                eprintln!("Failed to load image {}: {}", name.to_str().unwrap(), e);
-                None
+                eprintln!("Warning: failed to load image {} ({}), using a placeholder", asset.path, e);
+                checkerboard_image(PLACEHOLDER_SIZE, MAGENTA, BLACK)
             }
-        }
+        };
+        self.images.push(GameImage {
+            image,
+            name: asset.name.clone(),
+            category: asset.category,
+            scale: DVec4::new(asset.scale.0, asset.scale.1, 0.0, 0.0)
+        });
+        let id = self.images.len() - 1;
+        self.names.insert(asset.name.clone(), id);
+        id
     }
 
-    pub async fn load_image_list(&mut self, files: &Vec<String>) -> Result<(), String> {
-        for f in files {
-            let file = Path::new(f.as_str());
-            if self.load_game_image(file).await.is_none() {
-                return Err(format!("Failed to load image {}", f))
-            }
+    fn get_game_image(&self, id: ImageId) -> &GameImage {
+        match self.images.get(id) {
+            None => {&self.images[PLACEHOLDER_ID]}
+            Some(x) => {x}
         }
-        Ok(())
     }
 
     pub fn get_image(&self, id: ImageId) -> &Image {
-        &self.images[id].image
+        &self.get_game_image(id).image
+    }
+
+    pub fn get_id(&self, name: &str) -> Option<ImageId> {
+        self.names.get(name).copied()
+    }
+
+    // Like get_id, but names missing from the manifest show the placeholder
+    pub fn get_id_or_placeholder(&self, name: &str) -> ImageId {
+        self.get_id(name).unwrap_or(PLACEHOLDER_ID)
+    }
+
+    pub fn get_name(&self, id: ImageId) -> &str {
+        self.get_game_image(id).name.as_str()
+    }
+
+    pub fn get_category(&self, id: ImageId) -> AssetCategory {
+        self.get_game_image(id).category
+    }
+
+    // Default sprite scaling from the manifest
+    pub fn get_scale(&self, id: ImageId) -> DVec4 {
+        self.get_game_image(id).scale
     }
 
     pub fn check_image_index(&self, id: ImageId) -> bool {
         self.images.get(id).is_some()
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_placeholder_lookups() {
+        let images = ImageLoader::new();
+        assert_eq!(images.get_id("lamp"), None);
+        let id = images.get_id_or_placeholder("lamp");
+        assert_eq!(images.get_image(id).width, PLACEHOLDER_SIZE);
+        assert_eq!(images.get_image(1234).width, PLACEHOLDER_SIZE);
+        assert_eq!(images.get_name(1234), "placeholder");
+    }
+}
@@ -1,27 +1,41 @@
 use std::fs::{File, OpenOptions};
 use std::io::{BufReader, BufWriter, ErrorKind, Write};
 
-use macroquad::color::{BLACK, colors, GOLD, PINK, RED, SKYBLUE, WHITE};
-use macroquad::input::{get_last_key_pressed, is_mouse_button_down, is_mouse_button_pressed, KeyCode, mouse_position, MouseButton};
+use macroquad::color::{BLACK, colors, GOLD, PINK, RED, SKYBLUE, WHITE, YELLOW};
+use macroquad::input::{get_last_key_pressed, is_key_down, is_mouse_button_down, is_mouse_button_pressed, KeyCode, mouse_position, MouseButton};
 use macroquad::math::{IVec2, Vec2};
 use macroquad::prelude::{DVec2};
-use macroquad::shapes::draw_circle;
+use macroquad::shapes::{draw_circle, draw_rectangle_lines};
+use macroquad::text::draw_text;
 use macroquad::window::clear_background;
 use serde::{Deserialize, Serialize};
 
 use crate::{GameState, grid_viewer};
-use crate::grid2d::{Grid2D, WallGridCell};
-use crate::grid_viewer::draw_grid2d_cell;
-use crate::mob::{MobId, Mobs, MobType};
+use crate::archetype::ArchetypeRegistry;
+use crate::grid2d::{Grid2D, Topology, WallGridCell};
+use crate::grid_viewer::{draw_grid2d_cell, GridViewport};
+use crate::level_format;
+use crate::level_format::CURRENT_LEVEL_VERSION;
+use crate::level_validation::{has_errors, LevelDiagnostic, Severity};
+use crate::image::ImageLoader;
+use crate::material::{default_material_count, default_materials, Material, MaterialId, MaterialSprites};
+use crate::mob::{MobId, Mobs, MobSpawn, MobType};
 use crate::mob::MagicColor::{Black, White};
 
 #[derive(Serialize, Deserialize)]
 pub struct Level {
+    pub version: u32,
     pub player_start: (usize, usize),
     pub win_room: (usize, usize),
+    #[serde(with = "level_format::cell_rows")]
     pub grid: Grid2D<WallGridCell>,
-    pub mob_list: Vec<(i32, i32)>, // For now assume monster type
-    pub flavor_sprites: Option<Vec<(f64, f64, usize)>>,
+    pub mob_list: Vec<MobSpawn>,
+    pub flavor_sprites: Option<Vec<(f64, f64, String)>>, // Position and asset name
+    pub materials: Option<Vec<Material>>,
+    #[serde(default, with = "level_format::rle_grid")]
+    pub material_grid: Option<Grid2D<MaterialId>>,
+    #[serde(default)]
+    pub bullet_collisions: bool, // Bullets from different shooters cancel or merge when they meet
     filename: Option<String>
 }
 
@@ -38,12 +52,16 @@ impl Level
         };
 
         let mut new_level = Level {
+            version: CURRENT_LEVEL_VERSION,
             player_start: (8, 8),
             win_room: (world_width / 4, 0),
             grid,
             filename,
             mob_list: Vec::new(),
-            flavor_sprites: None
+            flavor_sprites: None,
+            materials: None,
+            material_grid: None,
+            bullet_collisions: false
         };
 
         if let Some(level_name) = level_name {
@@ -51,6 +69,11 @@ impl Level
                 Ok(_) => {
                     println!("Loaded {}", level_name);
                 }
+                Err(x) if x.kind() != ErrorKind::NotFound => {
+                    // Don't overwrite a level we failed to understand
+                    println!("Level {} could not be loaded ({}), starting with an empty level", level_name, x);
+                    new_level.grid.zero();
+                }
                 Err(x) => {
                     println!("Level {} could not be loaded ({}), generating random", level_name, x);
                     new_level.grid.zero();
@@ -67,6 +90,93 @@ impl Level
         }
         new_level
     }
+    // Level with no file, written as rows in the level file cell format
+    pub fn from_rows(rows: &[&str]) -> Option<Self> {
+        let width = rows.first()?.chars().count();
+        let mut level = Level::new(None, width, rows.len());
+        level.grid.zero();
+        for (y, row) in rows.iter().enumerate() {
+            if row.chars().count() != width {
+                return None;
+            }
+            for (x, c) in row.chars().enumerate() {
+                level.grid.set_cell_at_grid_coords_int(IVec2::new(x as i32, y as i32), level_format::char_to_cell(c)?);
+            }
+        }
+        Some(level)
+    }
+
+    pub fn material_count(&self) -> usize {
+        match &self.materials {
+            None => {default_material_count()}
+            Some(x) => {x.len()}
+        }
+    }
+
+    pub fn get_materials(&self) -> Vec<Material> {
+        match &self.materials {
+            None => {default_materials()}
+            Some(x) => {x.clone()}
+        }
+    }
+
+    // Looks up the texture names of every material, renderers do this once per frame
+    pub fn resolve_materials(&self, images: &ImageLoader) -> Vec<MaterialSprites> {
+        self.get_materials().iter().map(|m| m.resolve(images)).collect()
+    }
+
+    // Asset names used by the level that are not in the asset manifest
+    pub fn missing_assets(&self, images: &ImageLoader) -> Vec<String> {
+        let mut missing: Vec<String> = Vec::new();
+        let materials = self.get_materials();
+        let mut names: Vec<&str> = materials.iter().flat_map(|m| m.names()).collect();
+        if let Some(flavor_sprites) = &self.flavor_sprites {
+            names.extend(flavor_sprites.iter().map(|(_, _, name)| name.as_str()));
+        }
+        for name in names {
+            if images.get_id(name).is_none() && !missing.iter().any(|m| m == name) {
+                missing.push(name.to_string());
+            }
+        }
+        missing
+    }
+
+    pub fn get_material_id(&self, pos: IVec2) -> MaterialId {
+        if let Some(material_grid) = &self.material_grid {
+            // The material grid follows the topology of the level grid
+            let (x_rule, y_rule) = self.grid.get_topology().edge_rules();
+            let (width, height) = self.grid.get_size();
+            if let (Some(x), Some(y)) = (x_rule.cell_index(pos.x, width), y_rule.cell_index(pos.y, height)) {
+                if let Some(id) = material_grid.get_cell_at_grid_coords_int(IVec2::new(x as i32, y as i32)) {
+                    return *id;
+                }
+            }
+        }
+        0
+    }
+
+    // Texture set of the room at pos, from a table made by resolve_materials
+    pub fn get_material(&self, pos: IVec2, materials: &[MaterialSprites]) -> MaterialSprites {
+        materials.get(self.get_material_id(pos)).or(materials.first()).copied().unwrap_or_default()
+    }
+
+    pub fn set_material(&mut self, pos: IVec2, id: MaterialId) {
+        let (width, height) = self.grid.get_size();
+        let needs_new_grid = match &self.material_grid {
+            None => {true}
+            Some(x) => {x.get_size() != (width, height)}
+        };
+        if needs_new_grid {
+            let mut material_grid = Grid2D::new(width, height);
+            material_grid.zero();
+            self.material_grid = Some(material_grid);
+        }
+        let pos = apply_boundary_conditions_i32(pos, (width, height), self.grid.get_topology());
+        if let Some(material_grid) = &mut self.material_grid {
+            material_grid.set_cell_at_grid_coords_int(pos, id);
+        }
+    }
+
     pub fn save_to_file(&self, filename: &str) -> Result<(), std::io::Error> {
         let file =
             match OpenOptions::new().write(true).truncate(true).open(filename) {
@@ -99,11 +209,26 @@ impl Level
                     return Err(x);
                 }
             };
-        let mut v: Self = serde_json::from_reader(reader)?;
+        let raw: serde_json::Value = serde_json::from_reader(reader)
+            .map_err(|e| std::io::Error::new(ErrorKind::InvalidData, format!("{} is not valid JSON: {}", filename, e)))?;
+        let mut v = Self::from_json_value(raw)
+            .map_err(|e| std::io::Error::new(e.kind(), format!("{}: {}", filename, e)))?;
         std::mem::swap(self, &mut v);
         Ok(())
     }
 
+    // Builds a level from a parsed level file of any supported version
+    pub fn from_json_value(raw: serde_json::Value) -> Result<Self, std::io::Error> {
+        let upgraded = level_format::migrate_level(raw)?;
+        let level: Self = serde_json::from_value(upgraded)
+            .map_err(|e| std::io::Error::new(ErrorKind::InvalidData, e.to_string()))?;
+        Ok(level)
+    }
+
+    pub fn get_filename(&self) -> Option<&str> {
+        self.filename.as_deref()
+    }
+
     pub fn load(&mut self) -> Result<(), std::io::Error> {
         match self.filename.clone() {
             None => {
@@ -122,63 +247,48 @@ impl Level
                 return Err(std::io::Error::new(ErrorKind::Other, "No filename provided"));
             }
             Some(f) => {
-                self.save_to_file(f.as_str());
-                Ok(())
+                self.save_to_file(f.as_str())
             }
         }
     }
 }
 
 pub struct LevelEditor {
-    current_brush_idx: usize
+    current_brush_idx: usize,
+    current_material: MaterialId,
+    paint_materials: bool,
+    viewport: Option<GridViewport>,
+    diagnostics: Option<Vec<LevelDiagnostic>>, // None when the level changed since the last check
+    show_diagnostics: bool
 }
 
 pub struct PlayerMap {
-    grid: Grid2D<WallGridCell>
+    grid: Grid2D<WallGridCell>,
+    viewport: GridViewport,
+    followed_cell: Option<IVec2> // Player room the view was last centered on
 }
 
-// Applies boundary conditions.  Clamp vertical, wrap horizontal
-pub(crate) fn apply_boundary_conditions_i32(pos: IVec2, world_size: (usize, usize)) -> IVec2 {
-    let ws = IVec2::from((world_size.0 as i32, world_size.1 as i32));
-
-    let xs = pos.x % ws.x;
-    let nx = if xs < 0 {
-        ws.x - (xs.abs() % ws.x)
-    } else {
-        xs
-    };
-    let ny = if pos.y < 0 {
-        0
-    } else if pos.y >= ws.y {
-        ws.y - 1
-    } else {
-        pos.y
-    };
-    IVec2::from((nx, ny))
+// Applies boundary conditions of the world topology.
+pub(crate) fn apply_boundary_conditions_i32(pos: IVec2, world_size: (usize, usize), topology: Topology) -> IVec2 {
+    let (x_rule, y_rule) = topology.edge_rules();
+    IVec2::from((x_rule.apply_i32(pos.x, world_size.0), y_rule.apply_i32(pos.y, world_size.1)))
 }
 
-pub(crate) fn apply_boundary_conditions_f64(pos: DVec2, world_size: (usize, usize)) -> DVec2 {
-    let ws = DVec2::from((world_size.0 as f64, world_size.1 as f64));
-    let nx = if pos.x < 0.0 {
-        let x_abs = pos.x.abs();
-        let nr = (x_abs/ws.x).floor();
-        ws.x - (pos.x.abs() - (ws.x*nr))
-    } else if pos.x >= ws.x{
-        let nr = (pos.x/ws.x).floor();
-        pos.x - (ws.x*nr)
-    } else {
-        pos.x
-    };
-
-    let ny = if pos.y < 0.0 {
-        0.0
-    } else if pos.y >= ws.y {
-        ws.y
-    } else {
-        pos.y
-    };
-
-    DVec2::from((nx, ny))
+pub(crate) fn apply_boundary_conditions_f64(pos: DVec2, world_size: (usize, usize), topology: Topology) -> DVec2 {
+    let (x_rule, y_rule) = topology.edge_rules();
+    DVec2::from((x_rule.apply_f64(pos.x, world_size.0), y_rule.apply_f64(pos.y, world_size.1)))
+}
+
+// All nearby positions that map onto pos, used to look across world boundaries
+pub fn periodic_images(pos: DVec2, world_size: (usize, usize), topology: Topology) -> Vec<DVec2> {
+    let (x_rule, y_rule) = topology.edge_rules();
+    let mut images = Vec::new();
+    for y in y_rule.images_f64(pos.y, world_size.1) {
+        for x in x_rule.images_f64(pos.x, world_size.0) {
+            images.push(DVec2::new(x, y));
+        }
+    }
+    images
 }
 
 pub fn ucoords_to_icoords(x: (usize, usize)) -> (i32, i32) {
@@ -200,7 +310,12 @@ pub fn world_space_centered_coord(pos: (i32, i32), x_off: f64, y_off: f64) -> DV
 impl LevelEditor {
     pub fn new() -> Self {
         LevelEditor {
-            current_brush_idx: 1
+            current_brush_idx: 1,
+            current_material: 0,
+            paint_materials: false,
+            viewport: None,
+            diagnostics: None,
+            show_diagnostics: true
         }
     }
 
@@ -208,12 +323,10 @@ impl LevelEditor {
                    world: &mut Level,
                    mob_manager: &mut Mobs,
                    mob_grid: &mut Grid2D<MobId>,
+                   archetypes: &ArchetypeRegistry,
                    screen_size: (f32, f32), pos: DVec2, dir: DVec2) -> (Option<(DVec2, DVec2)>, Option<GameState>) {
         let mut new_game_state: Option<GameState> = None;
-        let brush_table: [WallGridCell; 2] = [
-            WallGridCell::Empty,
-            WallGridCell::Wall
-        ];
+        let brush_table = WallGridCell::ALL;
         let current_brush = brush_table[self.current_brush_idx];
 
         // Create flavor sprites list
@@ -221,40 +334,60 @@ impl LevelEditor {
             world.flavor_sprites = Some(Vec::new());
         }
 
+        // Keep the view sized to the level being edited
+        let needs_viewport = match &self.viewport {
+            None => {true}
+            Some(x) => {x.get_world_size() != world.grid.get_size()}
+        };
+        if needs_viewport {
+            let mut viewport = GridViewport::new(world.grid.get_size());
+            viewport.center_on(pos);
+            self.viewport = Some(viewport);
+        }
+        let viewport = self.viewport.as_mut().unwrap();
+        viewport.handle_input(screen_size);
+        let viewport = &*viewport;
+        let cell_dim = viewport.cell_screen_size(screen_size);
+
         //clear_background(BLACK);
-        grid_viewer::draw_grid2d(&world.grid, screen_size);
+        grid_viewer::draw_grid2d(&world.grid, viewport, screen_size);
 
         let mouse_screen_pos = Vec2::from(mouse_position()).as_dvec2();
-        let mouse_world_pos = world.grid.screen_to_grid_coords(mouse_screen_pos, screen_size);
+        let mouse_world_pos = viewport.screen_to_grid_coords(mouse_screen_pos, screen_size);
 
-        draw_grid2d_cell(mouse_screen_pos.as_vec2(), current_brush, 1.0, &world.grid, screen_size);
+        if self.paint_materials {
+            grid_viewer::draw_material_ids(world, viewport, screen_size);
+            let label = format!("M{}", self.current_material);
+            draw_text(label.as_str(), mouse_screen_pos.x as f32, mouse_screen_pos.y as f32, 20.0, WHITE);
+        } else {
+            draw_grid2d_cell(mouse_screen_pos.as_vec2(), current_brush, 1.0, cell_dim);
+        }
 
         // Draw start position
         let start_pos_world = world_space_centered_coord((world.player_start.0 as i32,world.player_start.1 as i32), 0.0, 0.0);
-        let start_pos_screen = world.grid.grid_to_screen_coords(start_pos_world, screen_size).as_vec2();
+        let start_pos_screen = viewport.grid_to_screen_coords(start_pos_world, screen_size).as_vec2();
         draw_circle(start_pos_screen.x, start_pos_screen.y, 5.0, BLACK);
 
         // Draw win position
         let win_pos_world = world_space_centered_coord((world.win_room.0 as i32,world.win_room.1 as i32), 0.0, 0.0);
-        let win_pos_screen = world.grid.grid_to_screen_coords(win_pos_world, screen_size).as_vec2();
+        let win_pos_screen = viewport.grid_to_screen_coords(win_pos_world, screen_size).as_vec2();
         draw_circle(win_pos_screen.x, win_pos_screen.y, 5.0, SKYBLUE);
 
         // Draw current player position
-        let player_screen_coords = world.grid.grid_to_screen_coords(pos, screen_size).as_vec2();
+        let player_screen_coords = viewport.grid_to_screen_coords(pos, screen_size).as_vec2();
         draw_circle(player_screen_coords.x, player_screen_coords.y, 3.0, colors::GOLD);
 
         // Draw flavor sprites
         if let Some(flavor) = &world.flavor_sprites {
-            for &(x, y, sprite_id) in flavor {
-                let sc = world.grid.grid_to_screen_coords(DVec2::new(x,y), screen_size).as_vec2();
+            for (x, y, _) in flavor {
+                let sc = viewport.grid_to_screen_coords(DVec2::new(*x, *y), screen_size).as_vec2();
                 draw_circle(sc.x, sc.y, 1.0, colors::RED);
             }
         }
 
         // Draw monster positions
-        for mob in mob_manager.mob_list.iter() {
-            let s = mob.borrow();
-            let p = world.grid.grid_to_screen_coords(s.get_pos(), screen_size).as_vec2();
+        for s in mob_manager.mob_list.values() {
+            let p = viewport.grid_to_screen_coords(s.get_pos(), screen_size).as_vec2();
             let mob_color = match s.is_alive {
                 true => {PINK}
                 false => {RED}
@@ -264,11 +397,39 @@ impl LevelEditor {
             }
         }
 
+        // Validation overlay
+        if self.diagnostics.is_none() {
+            self.diagnostics = Some(world.validate());
+        }
+        if self.show_diagnostics {
+            if let Some(diagnostics) = &self.diagnostics {
+                for (i, d) in diagnostics.iter().enumerate() {
+                    let color = match d.severity {
+                        Severity::Error => {RED}
+                        Severity::Warning => {YELLOW}
+                    };
+                    let p = viewport.grid_to_screen_coords(d.pos.as_dvec2(), screen_size).as_vec2();
+                    draw_rectangle_lines(p.x, p.y, cell_dim.x, cell_dim.y, 3.0, color);
+                    draw_text(d.message().as_str(), 10.0, 20.0 + 20.0 * i as f32, 20.0, color);
+                }
+            }
+        }
+
         if is_mouse_button_pressed(MouseButton::Right){
-            self.current_brush_idx = (self.current_brush_idx + 1) % brush_table.len();
+            if self.paint_materials {
+                self.current_material = (self.current_material + 1) % world.material_count();
+            } else {
+                self.current_brush_idx = (self.current_brush_idx + 1) % brush_table.len();
+            }
+        }
+
+        if is_mouse_button_down(MouseButton::Left) && !self.paint_materials {
+            self.diagnostics = None;
         }
 
-        if is_mouse_button_down(MouseButton::Left){
+        if is_mouse_button_down(MouseButton::Left) && self.paint_materials {
+            world.set_material(mouse_world_pos.as_ivec2(), self.current_material);
+        } else if is_mouse_button_down(MouseButton::Left){
             let cp = mouse_world_pos.as_ivec2();
             world.grid.set_cell_at_grid_coords_int(cp, current_brush);
             // Clear flavor sprites in room
@@ -285,47 +446,71 @@ impl LevelEditor {
         match get_last_key_pressed() {
             None => {}
             Some(x) => {
+                // Anything but saving, loading or toggling views may change the level
+                match &x {
+                    KeyCode::F12 | KeyCode::M | KeyCode::V | KeyCode::Escape => {}
+                    _ => {self.diagnostics = None;}
+                }
                 match &x {
                     KeyCode::Key1 => {
                         let p = mouse_world_pos;
                         if let Some(x) = &mut world.flavor_sprites {
-                            x.push((p.x, p.y, 0));
+                            x.push((p.x, p.y, "lamp".to_string()));
                         }
                     }
                     KeyCode::Key2 => {
                         let p = mouse_world_pos;
                         if let Some(x) = &mut world.flavor_sprites {
-                            x.push((p.x, p.y, 1));
+                            x.push((p.x, p.y, "tentacle_plant".to_string()));
                         }
                     }
 
+                    KeyCode::M => {
+                        self.paint_materials = !self.paint_materials;
+                    }
+                    KeyCode::V => {
+                        self.show_diagnostics = !self.show_diagnostics;
+                    }
+                    KeyCode::T => {
+                        let topology = world.grid.get_topology();
+                        let i = Topology::ALL.iter().position(|x| *x == topology).unwrap_or(0);
+                        let new_topology = Topology::ALL[(i + 1) % Topology::ALL.len()];
+                        world.grid.set_topology(new_topology);
+                        mob_grid.set_topology(new_topology);
+                        println!("World topology is now {:?}", new_topology);
+                    }
+                    KeyCode::B => {
+                        world.bullet_collisions = !world.bullet_collisions;
+                        println!("Bullet collisions are now {}", if world.bullet_collisions {"on"} else {"off"});
+                    }
                     KeyCode::P => {
                         let t = mouse_world_pos.as_uvec2();
                         let t = (t.x as usize, t.y as usize);
                         world.player_start = t;
                     }
                     KeyCode::E => {
-                        let new_monster_pos = mouse_world_pos.as_ivec2();
-                        if mob_manager.new_monster(new_monster_pos, mob_grid, White) {
-                            world.mob_list.push(<(i32, i32)>::from(new_monster_pos));
+                        let spawn = MobSpawn::new(mouse_world_pos.as_ivec2(), White);
+                        if mob_manager.new_monster(&spawn, archetypes, mob_grid) {
+                            world.mob_list.push(spawn);
                         }
                     }
                     KeyCode::R => {
-                        let new_monster_pos = mouse_world_pos.as_ivec2();
-                        if mob_manager.new_monster(new_monster_pos, mob_grid, Black) {
-                            world.mob_list.push(<(i32, i32)>::from(new_monster_pos));
+                        let spawn = MobSpawn::new(mouse_world_pos.as_ivec2(), Black);
+                        if mob_manager.new_monster(&spawn, archetypes, mob_grid) {
+                            world.mob_list.push(spawn);
                         }
                     }
                     KeyCode::K => {
                         let kill_monster_pos = mouse_world_pos.as_ivec2();
-                        world.mob_list.retain_mut(|mob_pos| {
-                            IVec2::from(mob_pos.clone()) != kill_monster_pos
+                        world.mob_list.retain_mut(|spawn| {
+                            spawn.get_pos() != kill_monster_pos
                         });
                         if let Some(mob_to_die) = mob_grid.get_cell_at_grid_coords_int(kill_monster_pos) {
                             match mob_to_die {
                                 MobId::Mob(x) => {
-                                    let mm = &mut x.borrow_mut();
-                                    mm.is_alive = false;
+                                    if let Some(mm) = mob_manager.mob_list.get_mut(*x) {
+                                        mm.is_alive = false;
+                                    }
                                 }
                                 _ => {}
                             }
@@ -335,11 +520,20 @@ impl LevelEditor {
                         new_game_state = Some(GameState::FirstPerson);
                     }
                     KeyCode::F12 => {
-                        if world.save().is_err() {
-                            println!("Failed to save level");
+                        let diagnostics = world.validate();
+                        for d in &diagnostics {
+                            println!("{:?}: {}", d.severity, d.message());
+                        }
+                        let force = is_key_down(KeyCode::LeftShift) || is_key_down(KeyCode::RightShift);
+                        if has_errors(&diagnostics) && !force {
+                            println!("Level has errors, not saving.  Hold shift and press F12 to save anyway");
                         } else {
-                            println!("Saved level");
+                            match world.save() {
+                                Ok(_) => {println!("Saved level");}
+                                Err(e) => {println!("Failed to save level ({})", e);}
+                            }
                         }
+                        self.diagnostics = Some(diagnostics);
                     }
                     KeyCode::F9 => {
                         if world.load().is_err() {
@@ -358,41 +552,61 @@ impl LevelEditor {
 }
 
 impl PlayerMap {
-    pub fn new(world_size: (usize, usize)) -> Self {
+    pub fn new(world_size: (usize, usize), topology: Topology) -> Self {
         let mut x = Grid2D::new(world_size.0, world_size.1);
+        x.set_topology(topology);
         x.zero();
-        PlayerMap {grid: x}
+        PlayerMap {grid: x, viewport: GridViewport::new(world_size), followed_cell: None}
+    }
+
+    pub fn from_grid(grid: Grid2D<WallGridCell>) -> Self {
+        let viewport = GridViewport::new(grid.get_size());
+        PlayerMap {grid, viewport, followed_cell: None}
+    }
+
+    pub fn get_grid(&self) -> &Grid2D<WallGridCell> {
+        &self.grid
     }
 
     pub fn add_marker(&mut self, pos: IVec2) {
         //let pos = apply_boundary_conditions_i32(pos, self.grid.get_size());
         self.grid.set_cell_at_grid_coords_int(pos, WallGridCell::Wall);
     }
-    pub fn draw_map(&self,
+    pub fn draw_map(&mut self,
                        screen_size: (f32, f32),
                        pos: DVec2) -> Option<GameState> {
         let mut new_game_state: Option<GameState> = None;
 
-        let (ww, wh) = self.grid.get_size();
+        // Follow the player on maps bigger than the screen.  Only recenter when the player moves to
+        // another room, so panning with the arrow keys sticks until then.
+        let player_pos = world_space_centered_coord(pos.as_ivec2().into(), 0.0, 0.0);
+        if self.followed_cell != Some(pos.as_ivec2()) {
+            self.followed_cell = Some(pos.as_ivec2());
+            self.viewport.center_on(player_pos);
+        }
+        self.viewport.handle_input(screen_size);
+        let cell_dim = self.viewport.cell_screen_size(screen_size);
+
+        let (ww, _) = self.grid.get_size();
+        let (start, end) = self.viewport.visible_cells();
         clear_background(BLACK);
-        for y in 0..wh {
-            for x in 0..ww {
+        for y in start.y as usize..end.y as usize {
+            for x in start.x as usize..end.x as usize {
                 let cells = self.grid.get_cells();
                 let cell = &cells[y * ww + x];
                 let cell_pos = Vec2::from((
                       (x as f32),
                       (y as f32)
                 ));
-                let pos =  self.grid.grid_to_screen_coords(cell_pos.as_dvec2(), screen_size).as_vec2();
+                let pos =  self.viewport.grid_to_screen_coords(cell_pos.as_dvec2(), screen_size).as_vec2();
                 if *cell == WallGridCell::Wall{
                     //draw_circle(pos.x, pos.y, 2.0, GOLD);
-                    draw_grid2d_cell(pos, WallGridCell::Wall, 2.0, &self.grid, screen_size);
+                    draw_grid2d_cell(pos, WallGridCell::Wall, 2.0, cell_dim);
                 }
             }
         }
 
-        let pos =  world_space_centered_coord(pos.as_ivec2().into(), 0.0, 0.0);
-        let pos =  self.grid.grid_to_screen_coords(pos, screen_size).as_vec2();
+        let pos =  self.viewport.grid_to_screen_coords(player_pos, screen_size).as_vec2();
         draw_circle(pos.x as f32, pos.y as f32, 2.0, RED);
 
         match get_last_key_pressed() {
@@ -0,0 +1,481 @@
+use std::io::{Error, ErrorKind};
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde::de::Error as DeError;
+use serde_json::{json, Map, Value};
+
+use crate::grid2d::{Grid2D, Topology, WallGridCell};
+use crate::material::MaterialId;
+
+// Bump this and add a migration step whenever the layout of level files changes
+pub const CURRENT_LEVEL_VERSION: u32 = 3;
+
+// Image list the game used before the asset manifest, in load order.  Version 2 and older
+// files refer to these by index.  Never change this table, it only exists to read old files.
+const LEGACY_SPRITE_NAMES: [&str; 17] = [
+    "monster", "light_bullet", "dark_bullet", "space_ship", "start_screen", "win_screen",
+    "fail_screen", "steel_floor", "light_wall", "pez_wall", "sf5", "sf6", "damage", "heal",
+    "sf1", "sf8", "pn1"
+];
+const LEGACY_FLAVOR_NAMES: [&str; 2] = ["lamp", "tentacle_plant"];
+
+pub fn cell_to_char(cell: WallGridCell) -> char {
+    match cell {
+        WallGridCell::Empty => {'.'}
+        WallGridCell::Wall => {'#'}
+        WallGridCell::Ladder => {'H'}
+        WallGridCell::Water => {'~'}
+        WallGridCell::Hazard => {'^'}
+        WallGridCell::Ledge => {'='}
+        WallGridCell::WhitePhase => {'w'}
+        WallGridCell::BlackPhase => {'b'}
+    }
+}
+
+pub fn char_to_cell(c: char) -> Option<WallGridCell> {
+    WallGridCell::ALL.iter().copied().find(|cell| cell_to_char(*cell) == c)
+}
+
+fn invalid_data(msg: String) -> Error {
+    Error::new(ErrorKind::InvalidData, msg)
+}
+
+// Upgrades a level file of any known version to the current layout
+pub fn migrate_level(mut level: Value) -> Result<Value, Error> {
+    loop {
+        let version = match level.get("version") {
+            None => {0} // Files written before versioning
+            Some(v) => {
+                match v.as_u64() {
+                    None => {return Err(invalid_data(format!("Level version {} is not a number", v)));}
+                    Some(x) => {x as u32}
+                }
+            }
+        };
+
+        level = match version {
+            0 => {migrate_v0_to_v1(level)?}
+            1 => {migrate_v1_to_v2(level)?}
+            2 => {migrate_v2_to_v3(level)?}
+            CURRENT_LEVEL_VERSION => {return Ok(level);}
+            x => {
+                return Err(invalid_data(format!(
+                    "Level version {} is newer than this game supports ({}), update the game to load it",
+                    x, CURRENT_LEVEL_VERSION)));
+            }
+        };
+    }
+}
+
+fn get_grid_size(grid: &Map<String, Value>, name: &str) -> Result<(usize, usize), Error> {
+    let width = grid.get("width").and_then(|x| x.as_u64());
+    let height = grid.get("height").and_then(|x| x.as_u64());
+    match (width, height) {
+        (Some(w), Some(h)) => {Ok((w as usize, h as usize))}
+        _ => {Err(invalid_data(format!("{} is missing its width or height", name)))}
+    }
+}
+
+// Version 0 stored every cell as a quoted string, version 1 stores rows of characters
+// and run length encodes the material grid.
+fn migrate_v0_to_v1(level: Value) -> Result<Value, Error> {
+    let mut level = match level {
+        Value::Object(x) => {x}
+        _ => {return Err(invalid_data("Level file is not a JSON object".to_string()));}
+    };
+
+    let grid = match level.remove("grid") {
+        Some(Value::Object(x)) => {x}
+        _ => {return Err(invalid_data("Version 0 level has no grid".to_string()));}
+    };
+    let (width, height) = get_grid_size(&grid, "grid")?;
+    let cells = match grid.get("cells") {
+        Some(Value::Array(x)) => {x}
+        _ => {return Err(invalid_data("Version 0 grid has no cell list".to_string()));}
+    };
+    if width.checked_mul(height) != Some(cells.len()) {
+        return Err(invalid_data(format!("Version 0 grid has {} cells, expected {}x{}", cells.len(), width, height)));
+    }
+
+    let mut rows: Vec<Value> = Vec::with_capacity(height);
+    for y in 0..height {
+        let mut row = String::with_capacity(width);
+        for x in 0..width {
+            let cell: WallGridCell = serde_json::from_value(cells[y * width + x].clone())
+                .map_err(|e| invalid_data(format!("Bad cell at ({}, {}): {}", x, y, e)))?;
+            row.push(cell_to_char(cell));
+        }
+        rows.push(Value::String(row));
+    }
+
+    let mut new_grid = Map::new();
+    new_grid.insert("width".to_string(), json!(width));
+    new_grid.insert("height".to_string(), json!(height));
+    new_grid.insert("topology".to_string(), grid.get("topology").cloned().unwrap_or(json!(Topology::default())));
+    new_grid.insert("rows".to_string(), Value::Array(rows));
+    level.insert("grid".to_string(), Value::Object(new_grid));
+
+    // Material grids were stored cell by cell as well
+    let material_grid = match level.remove("material_grid") {
+        None | Some(Value::Null) => {Value::Null}
+        Some(Value::Object(x)) => {
+            let (mw, mh) = get_grid_size(&x, "material_grid")?;
+            let ids: Vec<MaterialId> = match x.get("cells") {
+                None => {return Err(invalid_data("Version 0 material grid has no cell list".to_string()));}
+                Some(c) => {
+                    serde_json::from_value(c.clone())
+                        .map_err(|e| invalid_data(format!("Bad material grid: {}", e)))?
+                }
+            };
+            if mw.checked_mul(mh) != Some(ids.len()) {
+                return Err(invalid_data(format!("Version 0 material grid has {} cells, expected {}x{}", ids.len(), mw, mh)));
+            }
+            json!({"width": mw, "height": mh, "rle": run_length_encode(&ids)})
+        }
+        Some(_) => {return Err(invalid_data("material_grid is not an object".to_string()));}
+    };
+    level.insert("material_grid".to_string(), material_grid);
+
+    // Optional fields that older files may not have
+    if !level.contains_key("flavor_sprites") {
+        level.insert("flavor_sprites".to_string(), Value::Null);
+    }
+    if !level.contains_key("mob_list") {
+        level.insert("mob_list".to_string(), json!([]));
+    }
+
+    level.insert("version".to_string(), json!(1));
+    Ok(Value::Object(level))
+}
+
+// Version 1 stored mob spawns as bare positions and always spawned white monsters
+fn migrate_v1_to_v2(level: Value) -> Result<Value, Error> {
+    let mut level = match level {
+        Value::Object(x) => {x}
+        _ => {return Err(invalid_data("Level file is not a JSON object".to_string()));}
+    };
+
+    let positions: Vec<(i32, i32)> = match level.remove("mob_list") {
+        None | Some(Value::Null) => {Vec::new()}
+        Some(x) => {
+            serde_json::from_value(x).map_err(|e| invalid_data(format!("Bad version 1 mob list: {}", e)))?
+        }
+    };
+    let spawns: Vec<Value> = positions.iter()
+        .map(|p| json!({"pos": p, "color": "White", "archetype": "Monster"}))
+        .collect();
+    level.insert("mob_list".to_string(), Value::Array(spawns));
+
+    level.insert("version".to_string(), json!(2));
+    Ok(Value::Object(level))
+}
+
+fn legacy_name(table: &[&str], prefix: &str, index: u64) -> String {
+    match table.get(index as usize) {
+        Some(x) => {x.to_string()}
+        None => {format!("{}_{}", prefix, index)} // Kept so the level still reports it as missing
+    }
+}
+
+// Version 2 referred to flavor sprites and material textures by image index
+fn migrate_v2_to_v3(level: Value) -> Result<Value, Error> {
+    let mut level = match level {
+        Value::Object(x) => {x}
+        _ => {return Err(invalid_data("Level file is not a JSON object".to_string()));}
+    };
+
+    let flavor_sprites = match level.remove("flavor_sprites") {
+        None | Some(Value::Null) => {Value::Null}
+        Some(x) => {
+            let sprites: Vec<(f64, f64, u64)> = serde_json::from_value(x)
+                .map_err(|e| invalid_data(format!("Bad version 2 flavor sprites: {}", e)))?;
+            json!(sprites.iter()
+                .map(|(x, y, i)| json!([x, y, legacy_name(&LEGACY_FLAVOR_NAMES, "legacy_flavor", *i)]))
+                .collect::<Vec<Value>>())
+        }
+    };
+    level.insert("flavor_sprites".to_string(), flavor_sprites);
+
+    let materials = match level.remove("materials") {
+        None | Some(Value::Null) => {Value::Null}
+        Some(x) => {
+            let materials: Vec<Map<String, Value>> = serde_json::from_value(x)
+                .map_err(|e| invalid_data(format!("Bad version 2 materials: {}", e)))?;
+            let mut new_materials: Vec<Value> = Vec::new();
+            for m in materials {
+                let mut new_material = Map::new();
+                for (face, id) in m {
+                    let id = id.as_u64()
+                        .ok_or_else(|| invalid_data(format!("Material {} texture is not an image index", face)))?;
+                    new_material.insert(face, json!(legacy_name(&LEGACY_SPRITE_NAMES, "legacy_sprite", id)));
+                }
+                new_materials.push(Value::Object(new_material));
+            }
+            Value::Array(new_materials)
+        }
+    };
+    level.insert("materials".to_string(), materials);
+
+    level.insert("version".to_string(), json!(3));
+    Ok(Value::Object(level))
+}
+
+fn run_length_encode(ids: &[MaterialId]) -> Vec<(usize, MaterialId)> {
+    let mut runs: Vec<(usize, MaterialId)> = Vec::new();
+    for id in ids {
+        match runs.last_mut() {
+            Some((count, last)) if *last == *id => {*count += 1;}
+            _ => {runs.push((1, *id));}
+        }
+    }
+    runs
+}
+
+// Level grids are written as one string per row, one character per cell
+pub mod cell_rows {
+    use super::*;
+
+    #[derive(Serialize, Deserialize)]
+    struct RowGrid {
+        width: usize,
+        height: usize,
+        #[serde(default)]
+        topology: Topology,
+        rows: Vec<String>
+    }
+
+    pub fn serialize<S>(grid: &Grid2D<WallGridCell>, serializer: S) -> Result<S::Ok, S::Error> where S: Serializer {
+        let (width, height) = grid.get_size();
+        let cells = grid.get_cells();
+        let rows = (0..height).map(|y| {
+            cells[y * width..(y + 1) * width].iter().map(|c| cell_to_char(*c)).collect()
+        }).collect();
+        RowGrid {width, height, topology: grid.get_topology(), rows}.serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Grid2D<WallGridCell>, D::Error> where D: Deserializer<'de> {
+        let row_grid = RowGrid::deserialize(deserializer)?;
+        if row_grid.width == 0 || row_grid.height == 0 {
+            return Err(D::Error::custom(format!("grid is {}x{}, it needs at least one cell", row_grid.width, row_grid.height)));
+        }
+        if row_grid.rows.len() != row_grid.height {
+            return Err(D::Error::custom(format!("grid has {} rows, expected {}", row_grid.rows.len(), row_grid.height)));
+        }
+        // Check every row before allocating, the size in the file can't be trusted on its own
+        for (y, row) in row_grid.rows.iter().enumerate() {
+            if row.chars().count() != row_grid.width {
+                return Err(D::Error::custom(format!("grid row {} has {} cells, expected {}", y, row.chars().count(), row_grid.width)));
+            }
+        }
+        let size = row_grid.width.checked_mul(row_grid.height)
+            .ok_or_else(|| D::Error::custom(format!("grid size {}x{} is too large", row_grid.width, row_grid.height)))?;
+
+        let mut cells = Vec::with_capacity(size);
+        for (y, row) in row_grid.rows.iter().enumerate() {
+            for (x, c) in row.chars().enumerate() {
+                match char_to_cell(c) {
+                    None => {return Err(D::Error::custom(format!("unknown cell '{}' at ({}, {})", c, x, y)));}
+                    Some(cell) => {cells.push(cell);}
+                }
+            }
+        }
+
+        let mut grid = Grid2D::from_cells(row_grid.width, row_grid.height, cells).map_err(D::Error::custom)?;
+        grid.set_topology(row_grid.topology);
+        Ok(grid)
+    }
+}
+
+// Material grids are mostly one material, so they are run length encoded as [count, id] pairs
+pub mod rle_grid {
+    use super::*;
+
+    #[derive(Serialize, Deserialize)]
+    struct RleGrid {
+        width: usize,
+        height: usize,
+        rle: Vec<(usize, MaterialId)>
+    }
+
+    pub fn serialize<S>(grid: &Option<Grid2D<MaterialId>>, serializer: S) -> Result<S::Ok, S::Error> where S: Serializer {
+        match grid {
+            None => {serializer.serialize_none()}
+            Some(g) => {
+                let (width, height) = g.get_size();
+                let rle = run_length_encode(g.get_cells());
+                serializer.serialize_some(&RleGrid {width, height, rle})
+            }
+        }
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Option<Grid2D<MaterialId>>, D::Error> where D: Deserializer<'de> {
+        let rle_grid: Option<RleGrid> = Option::deserialize(deserializer)?;
+        match rle_grid {
+            None => {Ok(None)}
+            Some(x) => {
+                let size_error = || D::Error::custom(format!("material grid does not have {}x{} cells", x.width, x.height));
+                let size = x.width.checked_mul(x.height).ok_or_else(size_error)?;
+                // Add the runs up before expanding them, a bad count must not allocate anything
+                let mut total: usize = 0;
+                for (count, _) in x.rle.iter() {
+                    total = total.checked_add(*count).filter(|t| *t <= size).ok_or_else(size_error)?;
+                }
+                let mut cells = Vec::with_capacity(total);
+                for (count, id) in x.rle {
+                    cells.extend(std::iter::repeat(id).take(count));
+                }
+                let grid = Grid2D::from_cells(x.width, x.height, cells)
+                    .map_err(|e| D::Error::custom(format!("material {}", e)))?;
+                Ok(Some(grid))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::level::Level;
+    use crate::assets::AssetManifest;
+    use crate::mob::{MagicColor, MobSpawn};
+    use macroquad::math::IVec2;
+
+    #[test]
+    fn test_migrate_v0() {
+        let v0 = json!({
+            "player_start": [1, 0],
+            "win_room": [0, 0],
+            "grid": {"width": 3, "height": 2, "cells": ["Empty", "Wall", "Empty", "Wall", "Wall", "Wall"]},
+            "mob_list": [[0, 0], [2, 0]],
+            "filename": null
+        });
+        let level = Level::from_json_value(v0).unwrap();
+        assert_eq!(level.version, CURRENT_LEVEL_VERSION);
+        assert_eq!(level.grid.get_cell_at_grid_coords_int(IVec2::new(1, 0)), Some(&WallGridCell::Wall));
+        assert_eq!(level.grid.get_cell_at_grid_coords_int(IVec2::new(2, 0)), Some(&WallGridCell::Empty));
+        assert_eq!(level.mob_list.len(), 2);
+        assert_eq!(level.mob_list[1], MobSpawn::new(IVec2::new(2, 0), MagicColor::White));
+        assert!(level.flavor_sprites.is_none());
+
+        // Round trip through the current format
+        let saved = serde_json::to_value(&level).unwrap();
+        assert_eq!(saved["grid"]["rows"], json!([".#.", "###"]));
+        let reloaded = Level::from_json_value(saved).unwrap();
+        assert_eq!(reloaded.grid.get_cells(), level.grid.get_cells());
+    }
+
+    #[test]
+    fn test_mob_spawns() {
+        let v2 = json!({
+            "version": 2,
+            "player_start": [0, 0],
+            "win_room": [0, 0],
+            "grid": {"width": 2, "height": 1, "rows": [".."]},
+            "mob_list": [{"pos": [1, 0], "color": "Black", "archetype": "Monster", "hp": 50.0, "params": {"attack_cooldown": 2.0}}],
+            "flavor_sprites": null,
+            "filename": null
+        });
+        let level = Level::from_json_value(v2).unwrap();
+        let spawn = &level.mob_list[0];
+        assert_eq!(spawn.color, MagicColor::Black);
+        assert_eq!(spawn.hp, Some(50.0));
+        assert_eq!(spawn.params.unwrap().attack_cooldown, Some(2.0));
+        assert_eq!(spawn.params.unwrap().move_cooldown, None);
+
+        // Defaults are left out when saving
+        let saved = serde_json::to_value(MobSpawn::new(IVec2::new(1, 0), MagicColor::White)).unwrap();
+        assert_eq!(saved, json!({"pos": [1, 0], "color": "White", "archetype": "Monster"}));
+    }
+
+    #[test]
+    fn test_migrate_v2_asset_names() {
+        let v2 = json!({
+            "version": 2,
+            "player_start": [0, 0],
+            "win_room": [0, 0],
+            "grid": {"width": 2, "height": 1, "rows": [".."]},
+            "mob_list": [],
+            "flavor_sprites": [[0.5, 0.5, 1], [1.5, 0.5, 7]],
+            "materials": [{"floor": 7, "ceiling": 8, "wall": 9, "left": 14, "right": 15}],
+            "filename": null
+        });
+        let level = Level::from_json_value(v2).unwrap();
+        let flavor_sprites = level.flavor_sprites.unwrap();
+        assert_eq!(flavor_sprites[0].2, "tentacle_plant");
+        assert_eq!(flavor_sprites[1].2, "legacy_flavor_7");
+        let material = &level.materials.unwrap()[0];
+        assert_eq!(material.names(), ["steel_floor", "light_wall", "pez_wall", "sf1", "sf8"]);
+    }
+
+    #[test]
+    fn test_legacy_names_in_manifest() {
+        let manifest = AssetManifest::load_from_file("assets.json").unwrap();
+        for name in LEGACY_SPRITE_NAMES.iter().chain(LEGACY_FLAVOR_NAMES.iter()) {
+            assert!(manifest.assets.iter().any(|a| a.name == *name), "{} missing from assets.json", name);
+        }
+    }
+
+    #[test]
+    fn test_migrate_errors() {
+        let future = json!({"version": CURRENT_LEVEL_VERSION + 1});
+        let err = Level::from_json_value(future).err().unwrap();
+        assert!(err.to_string().contains("newer"));
+
+        let short_cells = json!({
+            "player_start": [0, 0],
+            "win_room": [0, 0],
+            "grid": {"width": 3, "height": 2, "cells": ["Empty"]},
+            "mob_list": []
+        });
+        let err = Level::from_json_value(short_cells).err().unwrap();
+        assert!(err.to_string().contains("expected 3x2"));
+
+        // Runs are checked against the size before they are expanded
+        let level = |material_grid: serde_json::Value| json!({
+            "version": CURRENT_LEVEL_VERSION,
+            "player_start": [0, 0],
+            "win_room": [0, 0],
+            "grid": {"width": 2, "height": 1, "rows": [".."]},
+            "mob_list": [],
+            "material_grid": material_grid
+        });
+        for material_grid in [
+            json!({"width": 2, "height": 1, "rle": [[usize::MAX, 0]]}),
+            json!({"width": 2, "height": 1, "rle": [[1, 0], [usize::MAX, 0]]}),
+            json!({"width": usize::MAX, "height": 2, "rle": [[2, 0]]}),
+        ] {
+            let err = Level::from_json_value(level(material_grid)).err().unwrap();
+            assert!(err.to_string().contains("material grid does not have"));
+        }
+        assert!(Level::from_json_value(level(json!({"width": 2, "height": 1, "rle": [[2, 1]]}))).is_ok());
+
+        // Grid rows are checked against the size before any cells are allocated
+        let level = |grid: serde_json::Value| json!({
+            "version": CURRENT_LEVEL_VERSION,
+            "player_start": [0, 0],
+            "win_room": [0, 0],
+            "grid": grid,
+            "mob_list": []
+        });
+        for (grid, message) in [
+            (json!({"width": usize::MAX, "height": 1, "rows": [".."]}), "grid row 0 has 2 cells"),
+            (json!({"width": usize::MAX, "height": usize::MAX, "rows": [".."]}), "grid has 1 rows"),
+            (json!({"width": 2, "height": 2, "rows": [".."]}), "grid has 1 rows"),
+            (json!({"width": 0, "height": 0, "rows": []}), "needs at least one cell"),
+            (json!({"width": 0, "height": 1, "rows": [""]}), "needs at least one cell"),
+            (json!({"width": 2, "height": 0, "rows": []}), "needs at least one cell"),
+        ] {
+            let err = Level::from_json_value(level(grid)).err().unwrap();
+            assert!(err.to_string().contains(message), "{}", err);
+        }
+        let empty_materials = json!({
+            "version": CURRENT_LEVEL_VERSION,
+            "player_start": [0, 0],
+            "win_room": [0, 0],
+            "grid": {"width": 2, "height": 1, "rows": [".."]},
+            "mob_list": [],
+            "material_grid": {"width": 0, "height": 0, "rle": []}
+        });
+        assert!(Level::from_json_value(empty_materials).is_err());
+    }
+}
@@ -0,0 +1,306 @@
+use std::collections::VecDeque;
+
+use macroquad::math::IVec2;
+
+use crate::grid2d::{Grid2D, WallGridCell};
+use crate::level::{apply_boundary_conditions_i32, Level};
+use crate::mob::{MagicColor, MobId};
+use crate::player_movement::{has_floor, is_climbable, is_solid, is_supported_position, is_wall, try_move, MoveDirection};
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Severity {
+    Warning, // Level can be saved and played, but probably isn't what was intended
+    Error    // Level can't be played or finished
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum LevelProblem {
+    NoRooms,
+    PlayerStartOutOfBounds,
+    PlayerStartInWall,
+    WinRoomOutOfBounds,
+    WinRoomInWall,
+    WinRoomUnreachable,
+    MobOutOfBounds {index: usize},
+    MobInWall {index: usize},
+    MobOverlapsMob {index: usize, other: usize},
+    MobOnPlayerStart {index: usize}
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct LevelDiagnostic {
+    pub severity: Severity,
+    pub problem: LevelProblem,
+    pub pos: IVec2
+}
+
+impl LevelDiagnostic {
+    fn new(severity: Severity, problem: LevelProblem, pos: IVec2) -> Self {
+        LevelDiagnostic {severity, problem, pos}
+    }
+
+    pub fn message(&self) -> String {
+        let (x, y) = (self.pos.x, self.pos.y);
+        match self.problem {
+            LevelProblem::NoRooms => {"Level has no rooms".to_string()}
+            LevelProblem::PlayerStartOutOfBounds => {format!("Player start ({}, {}) is outside the level", x, y)}
+            LevelProblem::PlayerStartInWall => {format!("Player start ({}, {}) is inside a wall", x, y)}
+            LevelProblem::WinRoomOutOfBounds => {format!("Win room ({}, {}) is outside the level", x, y)}
+            LevelProblem::WinRoomInWall => {format!("Win room ({}, {}) is inside a wall", x, y)}
+            LevelProblem::WinRoomUnreachable => {format!("Win room ({}, {}) can't be reached from the player start", x, y)}
+            LevelProblem::MobOutOfBounds {index} => {format!("Mob {} at ({}, {}) is outside the level", index, x, y)}
+            LevelProblem::MobInWall {index} => {format!("Mob {} at ({}, {}) is inside a wall", index, x, y)}
+            LevelProblem::MobOverlapsMob {index, other} => {
+                format!("Mob {} at ({}, {}) overlaps mob {} and will not spawn", index, x, y, other)
+            }
+            LevelProblem::MobOnPlayerStart {index} => {format!("Mob {} at ({}, {}) is on the player start", index, x, y)}
+        }
+    }
+}
+
+pub fn has_errors(diagnostics: &[LevelDiagnostic]) -> bool {
+    diagnostics.iter().any(|d| d.severity == Severity::Error)
+}
+
+impl Level {
+    // Checks the level for mistakes that make it unplayable or that are easy to miss in the editor
+    pub fn validate(&self) -> Vec<LevelDiagnostic> {
+        let mut diagnostics: Vec<LevelDiagnostic> = Vec::new();
+        let topology = self.grid.get_topology();
+        let world_size = self.grid.get_size();
+
+        // Every lookup below wraps or clamps against the size, which an empty grid doesn't have
+        if world_size.0 == 0 || world_size.1 == 0 {
+            diagnostics.push(LevelDiagnostic::new(Severity::Error, LevelProblem::NoRooms, IVec2::ZERO));
+            return diagnostics;
+        }
+
+        // Player start
+        let start = IVec2::new(self.player_start.0 as i32, self.player_start.1 as i32);
+        let start_ok = match self.grid.get_cell_at_grid_coords_int(start) {
+            None => {
+                diagnostics.push(LevelDiagnostic::new(Severity::Error, LevelProblem::PlayerStartOutOfBounds, start));
+                false
+            }
+            // The player starts out white
+            Some(x) if is_solid(*x, MagicColor::White) => {
+                diagnostics.push(LevelDiagnostic::new(Severity::Error, LevelProblem::PlayerStartInWall, start));
+                false
+            }
+            Some(_) => {true}
+        };
+
+        // Win room
+        let win = IVec2::new(self.win_room.0 as i32, self.win_room.1 as i32);
+        let win_ok = match self.grid.get_cell_at_grid_coords_int(win) {
+            None => {
+                diagnostics.push(LevelDiagnostic::new(Severity::Error, LevelProblem::WinRoomOutOfBounds, win));
+                false
+            }
+            // A phased cell can be entered in one of the colors, only a cell solid for both is a wall
+            Some(x) if is_solid(*x, MagicColor::White) && is_solid(*x, MagicColor::Black) => {
+                diagnostics.push(LevelDiagnostic::new(Severity::Error, LevelProblem::WinRoomInWall, win));
+                false
+            }
+            Some(_) => {true}
+        };
+
+        if start_ok && win_ok {
+            let reachable = self.reachable_cells(start);
+            let win = apply_boundary_conditions_i32(win, world_size, topology);
+            if reachable.get_cell_at_grid_coords_int(win) != Some(&true) {
+                diagnostics.push(LevelDiagnostic::new(Severity::Error, LevelProblem::WinRoomUnreachable, win));
+            }
+        }
+
+        // Mob spawns, compared after boundary conditions so wrapped duplicates are caught too
+        let start = apply_boundary_conditions_i32(start, world_size, topology);
+        let mut spawned: Vec<(usize, IVec2)> = Vec::new();
+        for (index, m) in self.mob_list.iter().enumerate() {
+            let pos = m.get_pos();
+            match self.grid.get_cell_at_grid_coords_int(pos) {
+                None => {
+                    diagnostics.push(LevelDiagnostic::new(Severity::Warning, LevelProblem::MobOutOfBounds {index}, pos));
+                    continue;
+                }
+                Some(x) if is_solid(*x, m.color) => {
+                    diagnostics.push(LevelDiagnostic::new(Severity::Warning, LevelProblem::MobInWall {index}, pos));
+                }
+                Some(_) => {}
+            }
+
+            let pos = apply_boundary_conditions_i32(pos, world_size, topology);
+            match spawned.iter().find(|(_, p)| *p == pos) {
+                None => {spawned.push((index, pos));}
+                Some((other, _)) => {
+                    diagnostics.push(LevelDiagnostic::new(Severity::Warning, LevelProblem::MobOverlapsMob {index, other: *other}, pos));
+                }
+            }
+            if start_ok && pos == start {
+                diagnostics.push(LevelDiagnostic::new(Severity::Error, LevelProblem::MobOnPlayerStart {index}, pos));
+            }
+        }
+
+        diagnostics
+    }
+
+    // Flood fills every cell the player can stand in, using the same movement rules as the game.
+    // The player can switch color wherever that doesn't put them inside a phased cell, so rooms
+    // are searched once for each color.
+    pub fn reachable_cells(&self, start: IVec2) -> Grid2D<bool> {
+        let (width, height) = self.grid.get_size();
+        let topology = self.grid.get_topology();
+
+        let new_grid = || {
+            let mut grid: Grid2D<bool> = Grid2D::new(width, height);
+            grid.set_topology(topology);
+            grid.zero();
+            grid
+        };
+        let mut reachable = new_grid();
+        let mut seen_white = new_grid();
+        let mut seen_black = new_grid();
+
+        // Mobs move around, so only the level geometry counts
+        let mut mob_grid: Grid2D<MobId> = Grid2D::new(width, height);
+        mob_grid.set_topology(topology);
+        mob_grid.zero();
+
+        // The player starts out white
+        let mut open: VecDeque<(IVec2, MagicColor)> = VecDeque::new();
+        open.push_back((apply_boundary_conditions_i32(start, (width, height), topology), MagicColor::White));
+        while let Some((pos, color)) = open.pop_front() {
+            let pos = self.settle(apply_boundary_conditions_i32(pos, (width, height), topology), color);
+            let seen = match color {
+                MagicColor::White => {&mut seen_white}
+                MagicColor::Black => {&mut seen_black}
+            };
+            if seen.get_cell_at_grid_coords_int(pos) != Some(&false) {
+                continue;
+            }
+            seen.set_cell_at_grid_coords_int(pos, true);
+            reachable.set_cell_at_grid_coords_int(pos, true);
+
+            let moves = [
+                MoveDirection::WalkForward,
+                MoveDirection::WalkBackward,
+                MoveDirection::ClimbUp,
+                MoveDirection::ClimbDown
+            ];
+            for dir in moves {
+                if let Some(new_pos) = try_move(pos, dir, 1, color, self, &mob_grid) {
+                    open.push_back((new_pos, color));
+                }
+            }
+            if !is_wall(pos, self, color.get_opposite()) {
+                open.push_back((pos, color.get_opposite()));
+            }
+        }
+
+        reachable
+    }
+
+    // Where the player ends up after entering pos, falling if nothing holds them there
+    fn settle(&self, pos: IVec2, color: MagicColor) -> IVec2 {
+        let (width, height) = self.grid.get_size();
+        let topology = self.grid.get_topology();
+        if is_supported_position(pos, self, color) {
+            return pos;
+        }
+
+        // A column without any floor loops forever on a torus, stop after one trip around
+        let mut pos = pos;
+        for _ in 0..height {
+            if has_floor(pos, self, color).is_some() || is_climbable(pos, self) {
+                break;
+            }
+            pos = apply_boundary_conditions_i32(pos + IVec2::new(0, 1), (width, height), topology);
+        }
+        pos
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mob::{MagicColor, MobSpawn};
+
+    #[test]
+    fn test_validate_reachability() {
+        // Win room sits on a shelf that can only be reached by the ladder
+        let mut level = Level::from_rows(&[
+            "####",
+            "#..#",
+            "#H##",
+            "#H.#",
+            "####",
+        ]).unwrap();
+        level.player_start = (2, 3);
+        level.win_room = (2, 1);
+        assert_eq!(level.validate(), vec![]);
+
+        // Without the ladder the shelf is out of reach
+        level.grid.set_cell_at_grid_coords_int(IVec2::new(1, 2), WallGridCell::Wall);
+        level.grid.set_cell_at_grid_coords_int(IVec2::new(1, 3), WallGridCell::Empty);
+        let problems: Vec<LevelProblem> = level.validate().iter().map(|d| d.problem).collect();
+        assert_eq!(problems, vec![LevelProblem::WinRoomUnreachable]);
+
+        // Switching color between the phased cells gets through both
+        let mut level = Level::from_rows(&[
+            "#######",
+            "#.w.b.#",
+            "#######",
+        ]).unwrap();
+        level.player_start = (1, 1);
+        level.win_room = (5, 1);
+        assert_eq!(level.validate(), vec![]);
+
+        // With no room to switch in between, one of them is always solid
+        level.grid.set_cell_at_grid_coords_int(IVec2::new(3, 1), WallGridCell::BlackPhase);
+        let problems: Vec<LevelProblem> = level.validate().iter().map(|d| d.problem).collect();
+        assert_eq!(problems, vec![LevelProblem::WinRoomUnreachable]);
+
+        // A win room in a phased cell is reached by switching to the color it is open for
+        level.grid.set_cell_at_grid_coords_int(IVec2::new(3, 1), WallGridCell::Empty);
+        level.grid.set_cell_at_grid_coords_int(IVec2::new(5, 1), WallGridCell::BlackPhase);
+        assert_eq!(level.validate(), vec![]);
+        level.grid.set_cell_at_grid_coords_int(IVec2::new(5, 1), WallGridCell::Wall);
+        let problems: Vec<LevelProblem> = level.validate().iter().map(|d| d.problem).collect();
+        assert_eq!(problems, vec![LevelProblem::WinRoomInWall]);
+    }
+
+    #[test]
+    fn test_validate_spawns() {
+        let mut level = Level::from_rows(&[
+            "####",
+            "#..#",
+            "####",
+        ]).unwrap();
+        level.player_start = (0, 0);
+        level.win_room = (2, 1);
+        level.mob_list = [(1, 1), (1, 1), (3, 1), (5, 1)].iter()
+            .map(|p| MobSpawn::new(IVec2::from(*p), MagicColor::White))
+            .collect();
+        let diagnostics = level.validate();
+        let problems: Vec<LevelProblem> = diagnostics.iter().map(|d| d.problem).collect();
+        assert_eq!(problems, vec![
+            LevelProblem::PlayerStartInWall,
+            LevelProblem::MobOverlapsMob {index: 1, other: 0},
+            LevelProblem::MobInWall {index: 2},
+            LevelProblem::MobOverlapsMob {index: 3, other: 0}, // Wraps onto mob 0
+        ]);
+        assert!(has_errors(&diagnostics));
+    }
+
+    #[test]
+    fn test_validate_empty_level() {
+        for (width, height) in [(0, 0), (0, 4), (4, 0)] {
+            let mut level = Level::new(None, width, height);
+            level.mob_list.push(MobSpawn::new(IVec2::new(1, 1), MagicColor::White));
+            let diagnostics = level.validate();
+            let problems: Vec<LevelProblem> = diagnostics.iter().map(|d| d.problem).collect();
+            assert_eq!(problems, vec![LevelProblem::NoRooms]);
+            assert!(has_errors(&diagnostics));
+        }
+    }
+}
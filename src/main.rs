@@ -1,6 +1,7 @@
 #![allow(warnings)]
 extern crate rand;
 
+use std::collections::HashMap;
 use std::default::Default;
 use std::f64::consts::PI;
 
@@ -10,26 +11,33 @@ use macroquad::math::f64;
 use macroquad::miniquad::window;
 use macroquad::prelude::*;
 use macroquad::telemetry::frame;
-use rand::Rng;
 
-use crate::combat::{Collision, DamageIndicator};
-use crate::fpv::{FirstPersonViewer, RoomTextureBindings, WallTextureBindings};
-use crate::grid2d::{Grid2D, WallGridCell};
+use crate::combat::DamageIndicator;
+use crate::fpv::{FirstPersonViewer, ScreenTexture, WallTextureBindings};
+use crate::grid2d::{Topology, WallGridCell};
+use crate::archetype::ArchetypeRegistry;
+use crate::assets::AssetManifest;
 use crate::image::ImageLoader;
-use crate::level::{
-    apply_boundary_conditions_f64, ucoords_to_dvec2, ucoords_to_icoords,
-    world_space_centered_coord, Level,
-};
+use crate::level::{ucoords_to_dvec2, ucoords_to_icoords, world_space_centered_coord, Level};
 use crate::mob::MagicColor::{Black, White};
-use crate::mob::{mob_at_cell, MagicColor, MobData, MobId, MobType, Mobs, MONSTER_HP, Bullets, Bullet};
-use crate::player_movement::{
-    has_floor, is_room_occupiable, is_supported_position, is_wall, try_move, MoveDirection,
-    PlayerPosition,
+use crate::mob::{MagicColor, MobType};
+use crate::render::{draw_first_person_frame, draw_horizontal_frame};
+use crate::replay::{Replay, ReplayPlayer};
+use crate::rng::GameRng;
+use crate::score::{HighScores, Score, CHAIN_LENGTH};
+use crate::session::SessionSnapshot;
+use crate::spell::SpellBook;
+use crate::simulation::{
+    calculate_view_dir, interpolate, FixedTimestep, InputSnapshot, PlayerCommand, SimEvent,
+    Simulation, PLAYER_MAX_HP, PLAYER_MAX_MANA, POLARITY_MAX, TICK_TIME,
 };
 use crate::sprites::Sprites;
 use crate::GameState::FirstPersonHorizonal;
-use crate::PlayerMode::{Falling, Idle, Moving, Turning};
 
+mod arena;
+mod archetype;
+mod assets;
+mod behavior;
 mod combat;
 mod debug;
 mod fpv;
@@ -37,15 +45,37 @@ mod grid2d;
 mod grid_viewer;
 mod image;
 mod level;
+mod level_format;
+mod level_validation;
+mod material;
 mod mob;
+mod pathfinding;
 mod physics;
 mod player_movement;
 mod raycaster;
+mod render;
+mod replay;
+mod rng;
+mod score;
+mod script;
+mod session;
+mod simulation;
+mod spell;
 mod sprites;
 
 const RENDER_WIDTH: u16 = 640;
 const RENDER_HEIGHT: u16 = 480;
 
+// Quicksave file, F5 saves and F6 loads
+const SESSION_FILE: &str = "session.json";
+
+// Replay of the current game, saved with F7 and when the game is won or lost.  Play one back
+// with "dungeoncrawl --replay replay.json".
+const REPLAY_FILE: &str = "replay.json";
+
+// Size of the empty level created when there is no level file to load
+const NEW_LEVEL_SIZE: (usize, usize) = (16, 64);
+
 #[derive(PartialEq)]
 enum GameState {
     Start,
@@ -58,31 +88,6 @@ enum GameState {
     Dead,
 }
 
-#[derive(PartialEq)]
-enum PlayerMode {
-    Idle,
-    Moving,
-    Falling,
-    Turning(f64),
-}
-
-struct PlayerState {
-    last_key_pressed: Option<KeyCode>,
-    mode: PlayerMode,
-    look_rotation: f64,
-    fire_cooldown: f64,
-
-    new_player_pos: Option<(i32, i32)>,
-    new_player_look: Option<(f64, f64)>,
-    lerp: f64,
-}
-
-struct HorizontalPlayerState {
-    turn_direction: f64,
-    look_rotation: f64,
-    lerp: f64,
-}
-
 struct DamageUIState {
     indicator: DamageIndicator,
     timer: f64,
@@ -99,395 +104,92 @@ impl DamageUIState {
     }
 }
 
-fn calculate_view_dir(rotation_angle: f64, player_facing: f64) -> DVec2 {
-    let rot2d = DVec2::from((rotation_angle.cos(), player_facing * rotation_angle.sin()));
-    let dir = player_facing * DVec2::from((-1.0, 0.0));
-    rot2d.rotate(dir)
-}
-
-impl HorizontalPlayerState {
-    fn player_look_horizonal(&mut self) {
-        let look_speed: f64 = 1.5; // Time in seconds to cover range
-        let look_range: f64 = 2.0 * 3.14;
-        let frame_time = get_frame_time() as f64;
-
-        if is_key_down(KeyCode::A) {
-            self.look_rotation += look_range / look_speed * frame_time;
-            // Need to use time to animate
-        } else if is_key_down(KeyCode::D) {
-            self.look_rotation -= look_range / look_speed * frame_time;
-        }
-    }
-
-    fn player_do_180(&mut self) -> bool {
-        let look_speed: f64 = 0.35; // Time in seconds to cover range
-        let frame_time = get_frame_time() as f64;
-
-        self.lerp += frame_time / look_speed;
-        self.lerp = self.lerp.clamp(0.0, 1.0);
-        self.look_rotation = self.turn_direction * PI * self.lerp;
-        self.lerp == 1.0
-    }
+// Sprites the game code refers to directly, looked up by name from the asset manifest
+struct GameSprites {
+    mobs: HashMap<String, (SpriteId, DVec4)>, // Sprite and scaling for each mob archetype
+    spells: HashMap<String, (SpriteId, SpriteId)>, // Light and dark sprite for each spell
+    light_bullet: SpriteId,
+    dark_bullet: SpriteId,
+    space_ship: SpriteId,
+    start_screen: SpriteId,
+    win_screen: SpriteId,
+    fail_screen: SpriteId,
+    damage: SpriteId,
+    heal: SpriteId,
 }
 
-impl PlayerState {
-    fn player_look(&mut self) {
-        let look_up_max: f64 = PI / 2.1;
-        let look_down_max: f64 = -PI / 2.1;
-        let look_speed: f64 = 1.5; // Time in seconds to cover range
-        let look_range: f64 = look_up_max - look_down_max;
-        let frame_time = get_frame_time() as f64;
-
-        if is_key_down(KeyCode::Up) {
-            self.look_rotation += look_range / look_speed * frame_time; // Need to use time to animate
-            self.look_rotation = self.look_rotation.min(look_up_max);
-        } else if is_key_down(KeyCode::Down) {
-            self.look_rotation -= look_range / look_speed * frame_time;
-            self.look_rotation = self.look_rotation.max(look_down_max);
-        }
-    }
-    fn do_idle_state(
-        &mut self,
-        mobs: &mut Mobs,
-        bullets: &mut Bullets,
-        player_facing: &mut f64,
-        player_pos: &PlayerPosition,
-        level: &Level,
-        mob_grid: &Grid2D<MobId>,
-        mana_color: &mut MagicColor,
-        fire_cooldown: f64,
-    ) -> PlayerMode {
-        let player_pos_ivec = player_pos.get_pos();
-
-        self.new_player_pos = None;
-
-        let facing = *player_facing as i32;
-        let standing_on_mob = !is_room_occupiable(player_pos_ivec + IVec2::new(0, 1), mob_grid);
-
-        if !is_supported_position(player_pos_ivec, level) && !standing_on_mob {
-            self.new_player_pos = None;
-            return Falling;
-        }
-
-        self.player_look();
-
-        let next_state = match self.last_key_pressed {
-            None => Idle,
-            Some(x) => {
-                match &x {
-                    KeyCode::A => {
-                        //Turn around
-                        //self.look_rotation = 0.0;
-                        if self.look_rotation != 0.0 {
-                            self.new_player_look = Some((self.look_rotation, 1.0));
-                            Moving
-                        } else {
-                            Turning(-1.0)
-                        }
-                    }
-                    KeyCode::D => {
-                        //Turn around
-                        //self.look_rotation = 0.0;
-                        if self.look_rotation != 0.0 {
-                            self.new_player_look = Some((self.look_rotation, 1.0));
-                            Moving
-                        } else {
-                            Turning(1.0)
-                        }
-                    }
-                    KeyCode::W => {
-                        // Move forward
-                        if self.look_rotation != 0.0 {
-                            self.new_player_look = Some((self.look_rotation, 1.0));
-                            Moving
-                        } else {
-                            if let Some(new_pos) = try_move(
-                                player_pos_ivec,
-                                MoveDirection::WalkForward,
-                                facing,
-                                level,
-                                &mob_grid,
-                            ) {
-                                self.new_player_pos = Some((new_pos.x, new_pos.y));
-                                Moving
-                            } else {
-                                Idle
-                            }
-                        }
-                    }
-                    KeyCode::S => {
-                        // Move backwards
-                        if self.look_rotation != 0.0 {
-                            self.new_player_look = Some((self.look_rotation, 1.0));
-                            Moving
-                        } else {
-                            if let Some(new_pos) = try_move(
-                                player_pos_ivec,
-                                MoveDirection::WalkBackward,
-                                facing,
-                                level,
-                                &mob_grid,
-                            ) {
-                                self.new_player_pos = Some((new_pos.x, new_pos.y));
-                                Moving
-                            } else {
-                                Idle
-                            }
-                        }
-                    }
-                    KeyCode::Q => {
-                        // Move up
-                        if let Some(new_pos) = try_move(
-                            player_pos_ivec,
-                            MoveDirection::ClimbUp,
-                            facing,
-                            level,
-                            &mob_grid,
-                        ) {
-                            self.new_player_pos = Some((new_pos.x, new_pos.y));
-                            Moving
-                        } else {
-                            Idle
-                        }
-                    }
-                    KeyCode::E => {
-                        // Move down
-                        if let Some(new_pos) = try_move(
-                            player_pos_ivec,
-                            MoveDirection::ClimbDown,
-                            facing,
-                            level,
-                            &mob_grid,
-                        ) {
-                            self.new_player_pos = Some((new_pos.x, new_pos.y));
-                            Moving
-                        } else {
-                            Idle
-                        }
-                    }
-                    KeyCode::Space => {
-                        // attack
-                        if self.fire_cooldown == 0.0 {
-                            self.fire_cooldown = fire_cooldown;
-                            let shoot_dir =
-                                calculate_view_dir(self.look_rotation, *player_facing).normalize();
-                            let ppos = player_pos.get_pos_dvec() + DVec2::from((0.5, 0.5)); // center in square
-                            bullets.new_bullet(ppos, shoot_dir, combat::PLAYER_HIT_DISTANCE + 0.01, *mana_color);
-                        }
-                        Idle
-                    }
-                    KeyCode::Left => {
-                        *mana_color = match mana_color {
-                            White => Black,
-                            Black => White,
-                        };
-                        Idle
-                    }
-                    _ => Idle,
-                }
+impl GameSprites {
+    fn new(images: &ImageLoader, archetypes: &ArchetypeRegistry, spell_book: &SpellBook) -> Self {
+        let id = |name: &str| {
+            if images.get_id(name).is_none() {
+                eprintln!("Warning: asset {} is not in the asset manifest, using a placeholder", name);
             }
+            images.get_id_or_placeholder(name)
         };
-
-        if next_state == Moving {
-            self.lerp = 0.0;
-        }
-
-        next_state
-    }
-
-    fn do_moving_state(
-        &mut self,
-        player_pos: &mut PlayerPosition,
-        player_world_coord: &mut DVec2,
-        mob_grid: &mut Grid2D<MobId>,
-        mana_color: &mut MagicColor,
-        level: &Level,
-    ) -> PlayerMode {
-        let frame_time = get_frame_time();
-        let next_state_from_move = match self.new_player_pos {
-            None => Idle,
-            Some(x) => {
-                let p = player_pos.get_pos_ituple();
-                let begin_pos = world_space_centered_coord(p, 0.0, 0.0);
-                let final_pos = world_space_centered_coord(x, 0.0, 0.0);
-                let v = final_pos - begin_pos;
-                self.lerp += (frame_time / 0.25) as f64;
-                self.lerp = self.lerp.min(1.0);
-                let upc = begin_pos + self.lerp * v;
-                *player_world_coord = DVec2::from(level::apply_boundary_conditions_f64(
-                    upc,
-                    level.grid.get_size(),
-                ));
-                if self.lerp == 1.0 {
-                    let nc =
-                        level::apply_boundary_conditions_i32(IVec2::from(x), level.grid.get_size());
-                    let res = player_pos.set_pos(nc, mob_grid);
-                    if res.is_err() {
-                        eprintln!("Moved player to occupied mob position");
-                    }
-                    Idle
-                } else {
-                    if let Some(x) = get_last_key_pressed() {
-                        if x == KeyCode::Left {
-                            *mana_color = mana_color.get_opposite();
-                        }
-                    }
-                    Moving
-                }
+        let mut mobs = HashMap::new();
+        for a in archetypes.archetypes.iter() {
+            let sprite = id(&a.sprite);
+            let mut scale = images.get_scale(sprite);
+            if let Some((x, y)) = a.scale {
+                scale.x = x;
+                scale.y = y;
             }
-        };
-
-        let look_rate: f32 = PI as f32 / 0.3;
-        let next_state_from_look = match self.new_player_look {
-            None => Idle,
-            Some((start_look, ref mut lerp)) => {
-                *lerp = (*lerp - (frame_time * look_rate) as f64).clamp(0.0, 1.0);
-                self.look_rotation = start_look * *lerp;
-                if *lerp == 0.0 {
-                    Idle
-                } else {
-                    Moving
-                }
-            }
-        };
-
-        if next_state_from_move == Moving || next_state_from_look == Moving {
-            Moving
-        } else {
-            self.new_player_look = None;
-            self.new_player_pos = None;
-            Idle
+            mobs.insert(a.id.clone(), (sprite, scale));
+        }
+        let mut spells = HashMap::new();
+        for s in spell_book.spells.iter() {
+            spells.insert(s.id.clone(), (id(&s.light_sprite), id(&s.dark_sprite)));
+        }
+        GameSprites {
+            mobs,
+            spells,
+            light_bullet: id("light_bullet"),
+            dark_bullet: id("dark_bullet"),
+            space_ship: id("space_ship"),
+            start_screen: id("start_screen"),
+            win_screen: id("win_screen"),
+            fail_screen: id("fail_screen"),
+            damage: id("damage"),
+            heal: id("heal"),
         }
     }
 
-    fn do_falling_state(
-        &mut self,
-        player_pos: &mut PlayerPosition,
-        player_world_coord: &mut DVec2,
-        mob_grid: &mut Grid2D<MobId>,
-        level: &Level,
-    ) -> PlayerMode {
-        let player_icoords = player_pos.get_pos_ituple();
-        let player_pos_ivec = IVec2::from(player_icoords);
-
-        self.player_look();
-
-        match self.new_player_pos {
+    // Mobs from a session saved with an archetype that is gone get the placeholder
+    fn mob(&self, archetype: &str, images: &ImageLoader) -> (SpriteId, DVec4) {
+        match self.mobs.get(archetype) {
+            Some(x) => *x,
             None => {
-                if has_floor(player_pos_ivec, level).is_some() || // Fall stopped by floor
-                    !is_room_occupiable(player_pos_ivec + IVec2::new(0, 1), mob_grid)
-                {
-                    // Fall stopped by mob
-                    Idle
-                } else {
-                    self.new_player_pos = Some((player_icoords.0, player_icoords.1 + 1)); // Fall down one tile
-                    self.lerp = 0.0;
-                    Falling
-                }
-            }
-            Some(x) => {
-                let p = player_pos.get_pos_ituple();
-                let begin_pos = world_space_centered_coord(p, 0.0, 0.0);
-                let final_pos = world_space_centered_coord(x, 0.0, 0.0);
-                let v = final_pos - begin_pos;
-                self.lerp += (get_frame_time() / 0.125) as f64;
-                self.lerp = self.lerp.min(1.0);
-                let upc = begin_pos + self.lerp * v;
-                *player_world_coord = DVec2::from(level::apply_boundary_conditions_f64(
-                    upc,
-                    level.grid.get_size(),
-                ));
-                if self.lerp == 1.0 {
-                    let nc =
-                        level::apply_boundary_conditions_i32(IVec2::from(x), level.grid.get_size());
-                    let res = player_pos.set_pos(nc, mob_grid);
-                    if res.is_err() {
-                        eprintln!("Player fell through occupied mob position");
-                    }
-                    self.new_player_pos = None;
-                }
-                Falling
+                let sprite = images.get_id_or_placeholder(archetype);
+                (sprite, images.get_scale(sprite))
             }
         }
     }
 }
 
-fn move_bullets(
-    bullet: &mut Bullet,
-    last_frame_time: f64,
-    world: &Level,
-    mob_grid: &Grid2D<MobId>,
-    collisions: &mut Vec<Collision>,
-) {
-    let last_state = bullet.moving;
-    let ws = world.grid.get_size();
-    debug_assert!(last_state.is_some());
-    match &last_state {
-        None => {
-            bullet.is_alive = false;
-        }
-        Some((start, end, lerp)) => {
-            let total_move_distance = end.distance(*start);
-            let move_this_frame = bullet.move_speed * last_frame_time;
-            let new_lerp = (lerp + (move_this_frame / total_move_distance)).clamp(0.0, 1.0);
-            let new_pos =
-                apply_boundary_conditions_f64(start.lerp(*end, new_lerp), world.grid.get_size());
-
-            // Check for hit with entity
-            let new_pos_ivec = new_pos.as_ivec2();
-            let mob_hit_by_bullet = mob_at_cell(new_pos_ivec, mob_grid);
-
-            match mob_hit_by_bullet {
-                MobId::NoMob => {}
-                MobId::Mob(_) => {
-                    let dist = new_pos.distance(world_space_centered_coord((new_pos_ivec.x, new_pos_ivec.y), 0.0, 0.0));
-                    if dist < mob::MONSTER_HIT_DISTANCE
-                    {
-                        bullet.is_alive = false;
-                        collisions.push(Collision::new_with_bullet(
-                            mob_hit_by_bullet.clone(),
-                            bullet.get_color(),
-                        ));
-                    }
-                }
-                MobId::Player => {
-                    let dist = new_pos.distance(world_space_centered_coord((new_pos_ivec.x, new_pos_ivec.y), 0.0, 0.0));
-                    if dist < combat::PLAYER_HIT_DISTANCE
-                    {
-                        collisions.push(Collision::new_with_bullet(
-                            MobId::Player,
-                            bullet.get_color(),
-                        ));
-                        bullet.is_alive = false;
-                    }
-                }
-            }
-
-            // Check for wall hit or end of movement
-            let hit_wall = player_movement::is_wall(new_pos.as_ivec2(), &world);
-            if new_lerp >= 1.0 || hit_wall {
-                bullet.is_alive = false;
-                bullet.moving = None;
-            } else {
-                bullet.moving = Some((*start, *end, new_lerp));
-                bullet.set_pos(new_pos, ws);
-            }
-        }
+fn mana_color_srpite_id(magic_color: MagicColor, game_sprites: &GameSprites) -> SpriteId {
+    match magic_color {
+        White => game_sprites.light_bullet,
+        Black => game_sprites.dark_bullet,
     }
 }
 
-fn mana_color_srpite_id(magic_color: MagicColor) -> usize {
-    match magic_color {
-        White => 1,
-        Black => 2,
+// Spells that are gone from spells.json fall back to the plain bullet sprites
+fn spell_sprite_id(spell: Option<&String>, magic_color: MagicColor, game_sprites: &GameSprites) -> SpriteId {
+    match spell.and_then(|x| game_sprites.spells.get(x)) {
+        None => mana_color_srpite_id(magic_color, game_sprites),
+        Some((light, dark)) => match magic_color {
+            White => *light,
+            Black => *dark,
+        },
     }
 }
 
 fn render_sprite_full_screen(
-    sprite_id: usize,
+    sprite_id: SpriteId,
     sprite_manager: &mut Sprites,
     sprite_images: &ImageLoader,
     first_person_view: &mut FirstPersonViewer,
+    screen: &ScreenTexture,
     screen_size: (f32, f32),
 ) {
     sprite_manager.clear_sprites();
@@ -504,18 +206,58 @@ fn render_sprite_full_screen(
         DVec2::new(1.0, 1.0),
         DVec2::new(1.0, 0.0),
         1.0,
-        1.0,
-    ); //world_width as f64);
+        (1, 1),
+        Topology::Clamp,
+    );
     first_person_view.reset_z_buffer();
-    first_person_view.render(screen_size);
+    screen.render(first_person_view, screen_size);
     sprite_manager.clear_sprites();
 }
 
+// Adds the score to the level's high score table and saves it.  Levels without a file, like the
+// ones in replays, don't keep high scores.
+fn record_high_score(world: &Level, score: &Score) -> Option<(HighScores, Option<usize>)> {
+    let filename = HighScores::filename_for(world.get_filename()?);
+    let mut table = match HighScores::load_from_file(&filename) {
+        Ok(x) => x,
+        Err(e) => {
+            // Don't overwrite a table we failed to read
+            println!("Failed to load high scores ({})", e);
+            return None;
+        }
+    };
+    let place = table.add(score);
+    if place.is_some() {
+        match table.save_to_file(&filename) {
+            Ok(_) => println!("Saved high score to {}", filename),
+            Err(e) => println!("Failed to save high scores ({})", e),
+        }
+    }
+    Some((table, place))
+}
+
+fn draw_high_scores(table: &HighScores, place: Option<usize>, score: &Score, screen_size: (f32, f32)) {
+    let font_size = 30.0 * (screen_size.0 / 800.0);
+    let x = screen_size.0 * 0.3;
+    let mut y = screen_size.1 * 0.3;
+    let summary = format!("Score: {}  Best chain: {}  Absorbed: {}", score.points, score.max_chain, score.absorbed);
+    draw_text(summary.as_str(), font_size * 0.1, y, font_size, color::GOLD);
+    y += font_size * 1.5;
+    draw_text("High scores", x, y, font_size, color::WHITE);
+    for (i, entry) in table.scores.iter().enumerate() {
+        y += font_size;
+        let line = format!("{:2}. {:8}  chain {}", i + 1, entry.points, entry.max_chain);
+        let line_color = if place == Some(i) { color::GOLD } else { color::WHITE };
+        draw_text(line.as_str(), x, y, font_size, line_color);
+    }
+}
+
 fn draw_damage_ui(
     ui_sprites: &mut Sprites,
     damage_ui_state: &mut Vec<DamageUIState>,
     last_frame_time: f64,
     sprite_images: &ImageLoader,
+    game_sprites: &GameSprites,
     first_person_view: &mut FirstPersonViewer) {
     ui_sprites.clear_sprites();
 
@@ -527,7 +269,7 @@ fn draw_damage_ui(
                     hit.timer = (hit.timer - last_frame_time).max(0.0);
                     ui_sprites.add_sprite(
                         DVec2::new(0.0, 1.0),
-                        (12, White),
+                        (game_sprites.damage, White),
                         DVec4::new(scale, scale, 0.0, 0.0),
                     );
                 }
@@ -536,7 +278,7 @@ fn draw_damage_ui(
                     hit.timer = (hit.timer - last_frame_time).max(0.0);
                     ui_sprites.add_sprite(
                         DVec2::new(0.0, 1.0),
-                        (13, White),
+                        (game_sprites.heal, White),
                         DVec4::new(scale, scale, 0.0, 0.0),
                     );
                 }
@@ -553,68 +295,46 @@ fn draw_damage_ui(
             DVec2::new(2.0, 1.0),
             DVec2::new(-1.0, 0.0),
             1.0,
-            2.0,
+            (2, 1),
+            Topology::Clamp,
         );
     }
 }
 
-fn draw_first_person_frame(
-    player_state: &PlayerState,
-    player_facing: f64,
-    max_ray_distance: f64,
-    world: &Level,
-    pos: DVec2,
-    plane_scale: f64,
-    sprite_images: &ImageLoader,
-    hide_floors_and_ceiling: bool,
-    hide_walls: bool,
-    line_width_scale: f64,
-    first_person_view: &mut FirstPersonViewer,
-    sprite_manager: &mut Sprites,
-) {
-    let world_size = world.grid.get_size();
-
-    // Draw frame
-    let view_dir = calculate_view_dir(player_state.look_rotation, player_facing);
-    let texture_bindings = RoomTextureBindings {
-        floor: 7,
-        wall: 9,
-        ceiling: 8,
+// Input for the next tick.  A replay being played back replaces the live input, and every tick
+// that runs goes into the recording.
+fn next_tick_input(
+    live_input: &InputSnapshot,
+    playback: &mut Option<ReplayPlayer>,
+    recording: &mut Option<Replay>,
+) -> InputSnapshot {
+    let replayed = match playback {
+        None => None,
+        Some(x) => x.next_input(),
     };
-    let wall_bindings = WallTextureBindings {
-        left: WallTextureBinding {
-            sprite_id: 10,
-            repeat_speed: 16.0,
-            pin: true,
-        },
-        right: WallTextureBinding {
-            sprite_id: 11,
-            repeat_speed: 16.0,
-            pin: true,
-        },
+    let input = match replayed {
+        Some(x) => x,
+        None => {
+            if playback.is_some() {
+                println!("Replay finished, back to live input");
+                *playback = None;
+            }
+            live_input.clone()
+        }
     };
-    first_person_view.draw_view(
-        max_ray_distance,
-        &world,
-        pos,
-        view_dir,
-        plane_scale,
-        &texture_bindings,
-        &wall_bindings,
-        &sprite_images,
-        hide_floors_and_ceiling,
-        hide_walls,
-        line_width_scale,
-    );
-    sprite_manager.draw_sprites(
-        max_ray_distance,
-        &sprite_images,
-        first_person_view,
-        pos,
-        view_dir,
-        player_facing * plane_scale,
-        world_size.0 as f64,
-    );
+    if let Some(x) = recording {
+        x.record(&input);
+    }
+    input
+}
+
+fn save_recording(recording: &mut Option<Replay>) {
+    if let Some(x) = recording {
+        match x.save_to_file(REPLAY_FILE) {
+            Ok(_) => println!("Saved replay of {} ticks to {}", x.tick_count(), REPLAY_FILE),
+            Err(e) => println!("Failed to save replay ({})", e),
+        }
+    }
 }
 
 #[macroquad::main("BasicShapes")]
@@ -622,61 +342,86 @@ async fn main() {
     // Load images
     let mut sprite_images = ImageLoader::new();
 
-    let mut game_image_files = vec![
-        "sprites/Bones_shadow1_1.png".to_string(), //0
-        "sprites/light.png".to_string(),           //1
-        "sprites/dark.png".to_string(),            //2
-        "sprites/space_ship.png".to_string(),      //3
-        "sprites/startscreen.png".to_string(),     //4
-        "sprites/win.png".to_string(),             //5
-        "sprites/fail.png".to_string(),            //6
-        "sprites/steelfloor7.png".to_string(),     //7
-        "sprites/lightwall.png".to_string(),       //8
-        "sprites/pezwall.png".to_string(),         //9
-        "sprites/sf5.png".to_string(),             //10
-        "sprites/sf6.png".to_string(),             //11
-        "sprites/damage.png".to_string(),          //12
-        "sprites/heal.png".to_string(),            //13
-    ];
-
-    let mut flavor_image_files = vec![
-        "sprites/lamp.png".to_string(),
-        "sprites/Tentacle_plant_shadow1_1.png".to_string(),
-    ];
-
-    let mut flavor_sprite_scaling: Vec<DVec4> = Vec::new();
-    flavor_sprite_scaling.push(DVec4::new(0.2, 0.2, 0.0, 0.0));
-    flavor_sprite_scaling.push(DVec4::new(0.6, 0.6, 0.0, 0.0));
-
-    let mut sprite_image_files: Vec<String> = Vec::new();
-    let flavor_sprites_start_index = game_image_files.len();
-    sprite_image_files.append(&mut game_image_files);
-    sprite_image_files.append(&mut flavor_image_files);
-
-    sprite_images
-        .load_image_list(&sprite_image_files)
-        .await
-        .expect("Failed to load sprite images");
+    let asset_manifest = match AssetManifest::load_from_file("assets.json") {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("Warning: failed to load asset manifest ({}), using placeholders", e);
+            AssetManifest { assets: Vec::new() }
+        }
+    };
+    sprite_images.load_manifest(&asset_manifest).await;
+    let archetypes = match ArchetypeRegistry::load_from_file("mobs.json") {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("Warning: failed to load mob archetypes ({}), only the original monster is available", e);
+            ArchetypeRegistry::default()
+        }
+    };
+    let spells = match SpellBook::load_from_file("spells.json") {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("Warning: failed to load spells ({}), only the bolt is available", e);
+            SpellBook::default()
+        }
+    };
     let mut sprite_manager = sprites::Sprites::new();
     let mut ui_sprites = sprites::Sprites::new();
 
-    // Create mob manager
-    let mut mobs = Mobs::new();
-    let mut bullets = Bullets::new();
-
     let max_ray_distance: f64 = 16.0;
-    let (world_width, world_height) = (16usize, 64usize);
-    let mut world = Level::new(Some("level.json"), world_width, world_height);
-    let world_size = ucoords_to_dvec2(world.grid.get_size());
 
-    // Mob grid
-    let mut mob_grid: Grid2D<MobId> = Grid2D::new(world_width, world_height);
-    mob_grid.zero();
-
-    // Populate world with mobs
-    for m in &world.mob_list {
-        mobs.new_monster(IVec2::from(*m), &mut mob_grid, MagicColor::White);
+    // Either play back a replay given on the command line, or start a new game and record it
+    let args: Vec<String> = std::env::args().collect();
+    let replay = match args.iter().position(|x| x == "--replay") {
+        None => None,
+        Some(i) => match args.get(i + 1).map(|x| Replay::load_from_file(x)) {
+            None => {
+                println!("--replay needs a replay file");
+                None
+            }
+            Some(Err(e)) => {
+                println!("Failed to load replay ({})", e);
+                None
+            }
+            Some(Ok(x)) => match x.get_level() {
+                Ok(level) => Some((x, level)),
+                Err(e) => {
+                    println!("Failed to load replay level ({})", e);
+                    None
+                }
+            },
+        },
+    };
+    let (world, archetypes, spells, seed, mut playback, mut recording) = match replay {
+        Some((replay, level)) => {
+            println!("Playing back replay of {} ticks", replay.tick_count());
+            (level, replay.archetypes.clone(), replay.spells.clone(), replay.seed, Some(ReplayPlayer::new(replay)), None)
+        }
+        None => {
+            let world = Level::new(Some("level.json"), NEW_LEVEL_SIZE.0, NEW_LEVEL_SIZE.1);
+            let seed = GameRng::seed_from_time();
+            let recording = Replay::new(&world, &archetypes, &spells, seed).ok();
+            (world, archetypes, spells, seed, None, recording)
+        }
+    };
+    let game_sprites = GameSprites::new(&sprite_images, &archetypes, &spells);
+    for name in world.missing_assets(&sprite_images) {
+        println!("Level uses asset {} which is not in the asset manifest, using a placeholder", name);
+    }
+    for spawn in world.mob_list.iter() {
+        if archetypes.get(&spawn.archetype).is_none() {
+            println!("Level uses mob archetype {} which is not in mobs.json, the mob at {:?} is left out", spawn.archetype, spawn.pos);
+        }
     }
+    let mut world_size = ucoords_to_dvec2(world.grid.get_size());
+
+    // Player map, sized from the level actually loaded
+    let mut player_map = level::PlayerMap::new(world.grid.get_size(), world.grid.get_topology());
+
+    // Gameplay state, everything else here is drawing and UI
+    let mut sim = Simulation::new(world, &archetypes, seed);
+    sim.set_spells(spells.clone());
+    let mut timestep = FixedTimestep::new();
+    let mut input = InputSnapshot::default();
 
     // Camera plane scaling factor
     let plane_scale: f64 = -1.15;
@@ -687,45 +432,22 @@ async fn main() {
     // Set up low resolution renderer
     let mut first_person_view = fpv::FirstPersonViewer::new(RENDER_WIDTH, RENDER_HEIGHT);
     let mut first_person_view_horizontal = fpv::FirstPersonViewer::new(RENDER_WIDTH, RENDER_HEIGHT);
-
-    // Translate player starting position to world vector coords.
-    // These are the gameplay variables, the others should not be modified directly
-    let mut player_pos = player_movement::PlayerPosition::new(world.player_start, &mut mob_grid);
-    let mut player_facing: f64 = 1.0;
-    let mut mana_color: MagicColor = White;
-    let player_max_hp: f64 = 639.0;
-    let mut player_hp: f64 = player_max_hp;
-    let fire_cooldown = 1.0;
+    let screen = fpv::ScreenTexture::new(RENDER_WIDTH, RENDER_HEIGHT);
 
     // UI state
     let mut damage_ui_state: Vec<DamageUIState> = Vec::new();
+    let mut high_scores_recorded = false;
+    let mut high_scores: Option<(HighScores, Option<usize>)> = None;
 
     // Level editor
     let mut level_editor = level::LevelEditor::new();
 
-    // Player map
-    let mut player_map = level::PlayerMap::new(world.grid.get_size());
-
-    let mut game_state = GameState::Start;
-    let mut player_state = PlayerState {
-        last_key_pressed: None,
-        mode: Idle,
-        look_rotation: 0.0,
-        new_player_pos: None,
-        new_player_look: None,
-        lerp: 0.0,
-        fire_cooldown: 0.0,
-    };
-    let mut horizontal_player_state = HorizontalPlayerState {
-        turn_direction: 0.0,
-        look_rotation: 0.0,
-        lerp: 0.0,
+    let mut game_state = if playback.is_some() {
+        GameState::FirstPerson
+    } else {
+        GameState::Start
     };
 
-    // Array to store collisions
-    let mut collisions: Vec<Collision> = Vec::with_capacity(16);
-    let mut new_bullets: Vec<(DVec2, DVec2, f64, MagicColor)> = Vec::new();
-
     let mut full_screen_mode = false;
     set_fullscreen(full_screen_mode);
 
@@ -735,34 +457,110 @@ async fn main() {
         let last_frame_time = get_frame_time() as f64; // Check if game only calls once per frame
 
         // Handle player view
-        let mut pos = world_space_centered_coord(player_pos.get_pos_ituple(), 0.0, -0.0);
-        let dir = player_facing * DVec2::from((-1.0, 0.0));
+        let pos = sim.player_draw_coord(timestep.alpha());
+        let dir = sim.player_facing * DVec2::from((-1.0, 0.0));
 
         // Check for win condition
-        if player_pos.get_pos() == IVec2::from(ucoords_to_icoords(world.win_room)) {
+        if sim.has_won() {
             game_state = GameState::Win;
         }
 
         // Check for death
-        if player_hp <= 0.0 {
+        if sim.is_dead() {
             game_state = GameState::Dead;
         }
 
+        // The game is over, keep the replay
+        if game_state == GameState::Win || game_state == GameState::Dead {
+            save_recording(&mut recording);
+            recording = None;
+        }
+
+        // Replays don't go on the high score table
+        if game_state == GameState::Win && !high_scores_recorded {
+            high_scores_recorded = true;
+            if playback.is_none() {
+                high_scores = record_high_score(&sim.world, &sim.score);
+            }
+        }
+
         if let Some(k) = get_last_key_pressed() {
             if k == KeyCode::F4 {
                 full_screen_mode = !full_screen_mode;
                 set_fullscreen(full_screen_mode);
             }
+
+            let in_game = game_state == GameState::FirstPerson || game_state == FirstPersonHorizonal;
+            if k == KeyCode::F5 && in_game {
+                let saved = SessionSnapshot::new(
+                    &sim.world,
+                    sim.player_snapshot(),
+                    player_map.get_grid(),
+                    &sim.mobs.mob_list,
+                    &sim.bullets.bullet_list,
+                    &sim.rng,
+                )
+                .and_then(|x| x.save_to_file(SESSION_FILE));
+                match saved {
+                    Ok(_) => println!("Saved session to {}", SESSION_FILE),
+                    Err(e) => println!("Failed to save session ({})", e),
+                }
+            }
+
+            if k == KeyCode::F7 {
+                save_recording(&mut recording);
+            }
+
+            if k == KeyCode::F6 && game_state != GameState::LevelEditor {
+                let loaded = SessionSnapshot::load_from_file(SESSION_FILE)
+                    .and_then(|x| x.get_level().map(|level| (x, level)));
+                match loaded {
+                    Err(e) => println!("Failed to load session ({})", e),
+                    Ok((snapshot, level)) => {
+                        let size = level.grid.get_size();
+                        let rng = match snapshot.rng {
+                            Some(x) => x,
+                            None => GameRng::new(GameRng::seed_from_time()),
+                        };
+                        match Simulation::from_session(level, &snapshot.player, snapshot.mobs, snapshot.bullets, rng) {
+                            Err(e) => println!("Failed to load session ({})", e),
+                            Ok(loaded_sim) => {
+                                world_size = ucoords_to_dvec2(size);
+                                sim = loaded_sim;
+                                sim.set_spells(spells.clone());
+                                if recording.is_some() || playback.is_some() {
+                                    println!("Loading a session ends the replay");
+                                }
+                                recording = None;
+                                playback = None;
+                                timestep = FixedTimestep::new();
+                                input = InputSnapshot::default();
+                                player_map = level::PlayerMap::from_grid(snapshot.visited);
+                                damage_ui_state.clear();
+                                high_scores_recorded = false;
+                                high_scores = None;
+                                game_state = if snapshot.player.horizontal_view {
+                                    FirstPersonHorizonal
+                                } else {
+                                    GameState::FirstPerson
+                                };
+                                println!("Loaded session from {}", SESSION_FILE);
+                            }
+                        }
+                    }
+                }
+            }
         }
 
         match game_state {
             GameState::Start => {
                 clear_background(BLACK);
                 render_sprite_full_screen(
-                    4,
+                    game_sprites.start_screen,
                     &mut sprite_manager,
                     &sprite_images,
                     &mut first_person_view,
+                    &screen,
                     screen_size,
                 );
 
@@ -776,27 +574,32 @@ async fn main() {
             GameState::Win => {
                 clear_background(BLACK);
                 render_sprite_full_screen(
-                    5,
+                    game_sprites.win_screen,
                     &mut sprite_manager,
                     &sprite_images,
                     &mut first_person_view,
+                    &screen,
                     screen_size,
                 );
+                if let Some((table, place)) = &high_scores {
+                    draw_high_scores(table, *place, &sim.score, screen_size);
+                }
             }
 
             GameState::Dead => {
                 clear_background(BLACK);
                 render_sprite_full_screen(
-                    6,
+                    game_sprites.fail_screen,
                     &mut sprite_manager,
                     &sprite_images,
                     &mut first_person_view,
+                    &screen,
                     screen_size,
                 );
             }
 
             GameState::Debug => {
-                if let Some((p, d)) = debug_view.draw_debug_view(&mut world, screen_size) {
+                if let Some((p, d)) = debug_view.draw_debug_view(&mut sim.world, screen_size) {
                     //pos = p; // These need fixing
                     //dir = d.normalize();
                     //plane = dir.perp() * plane_scale;
@@ -806,199 +609,117 @@ async fn main() {
             }
 
             GameState::FirstPersonHorizonal => {
-                let h_world_size = 2 * (max_ray_distance as i32) + 1;
-                let mut h_world = Level::new(None, h_world_size as usize, 1);
-                let mut h_world_floor: Vec<Option<SpriteId>> =
-                    Vec::with_capacity(h_world_size as usize);
-                let mut h_world_ceiling: Vec<Option<SpriteId>> =
-                    Vec::with_capacity(h_world_size as usize);
-                h_world_floor.resize_with(h_world_size as usize, Default::default);
-                h_world_ceiling.resize_with(h_world_size as usize, Default::default);
-                h_world.grid.zero();
-                let h_pos = world_space_centered_coord((max_ray_distance as i32, 0), 0.0, 0.0);
-
-                let wall_bindings = WallTextureBindings {
-                    left: WallTextureBinding {
-                        sprite_id: 10,
-                        repeat_speed: 16.0,
-                        pin: true,
-                    },
-                    right: WallTextureBinding {
-                        sprite_id: 11,
-                        repeat_speed: 16.0,
-                        pin: true,
-                    },
-                };
-
-                // Calculate view
-                let base_angle: f64 = if player_facing < 0.0 { PI } else { 0.0 };
-                let done_turning = horizontal_player_state.player_do_180();
-                let view_dir =
-                    calculate_view_dir(base_angle + horizontal_player_state.look_rotation, 1.0);
-                let h_facing = view_dir.x.signum() as i32;
-
-                // Populate floor array
-                let current_pos = player_pos.get_pos();
-                let current_pos_world =
-                    world_space_centered_coord(<(i32, i32)>::from(current_pos), 0.0, 0.0);
-                for i in 0..h_world_size / 2 {
-                    let x = current_pos.x + h_facing * i;
-                    let floor_cell = world
-                        .grid
-                        .get_cell_at_grid_coords_int(IVec2::from((x, current_pos.y + 1)));
-                    let floor_cell: Option<SpriteId> = match floor_cell {
-                        Some(x) => match x {
-                            WallGridCell::Empty => None,
-                            WallGridCell::Wall => Some(7),
-                        },
-                        None => None,
-                    };
-                    h_world_floor[(h_pos.x as i32 + (h_facing * i)) as usize] = floor_cell;
-
-                    // Ceiling
-                    let ceiling_cell = world
-                        .grid
-                        .get_cell_at_grid_coords_int(IVec2::from((x, current_pos.y - 1)));
-                    let ceiling_cell: Option<SpriteId> = match ceiling_cell {
-                        Some(x) => match x {
-                            WallGridCell::Empty => None,
-                            WallGridCell::Wall => Some(8),
-                        },
-                        None => None,
-                    };
-                    h_world_ceiling[(h_pos.x as i32 + (h_facing * i)) as usize] = ceiling_cell;
+                // Gameplay waits while the player turns around, this only advances the turn
+                for _ in 0..timestep.advance(last_frame_time) {
+                    if sim.is_turning() {
+                        let tick_input = next_tick_input(&InputSnapshot::default(), &mut playback, &mut recording);
+                        sim.tick(&tick_input, TICK_TIME);
+                    }
                 }
 
-                // Draw floors, ceiling, and walls
-                first_person_view_horizontal.reset_image_buffer([0, 0, 0, 255]);
-                first_person_view_horizontal.draw_view_horizontal(
-                    max_ray_distance,
-                    &h_world,
-                    h_pos,
-                    view_dir,
-                    0.5 / horizontal_line_scale,
-                    None,
-                    &h_world_floor,
-                    &h_world_ceiling,
-                    &sprite_images,
-                    false,
-                    (0.5) / plane_scale.abs(),
-                    &wall_bindings,
-                    current_pos_world.y,
-                    world_size.as_vec2(),
-                );
-
-                // Re-render old view
-                first_person_view.reset_image_buffer([0, 0, 0, 0]);
-                let vertical_look_dir = if view_dir.x < 0.0 { 1.0 } else { -1.0 };
-                draw_first_person_frame(
-                    &player_state,
-                    vertical_look_dir,
-                    max_ray_distance,
-                    &world,
+                draw_horizontal_frame(
+                    sim.player_state.look_rotation,
+                    sim.player_state.turn_rotation(),
+                    sim.player_facing,
+                    sim.player_pos.get_pos(),
                     pos,
+                    max_ray_distance,
+                    &sim.world,
                     plane_scale,
+                    horizontal_line_scale,
                     &sprite_images,
-                    true,
-                    true,
-                    0.5,
                     &mut first_person_view,
+                    &mut first_person_view_horizontal,
                     &mut sprite_manager,
                 );
 
-                // draw vertical scene render
-                h_world
-                    .grid
-                    .set_cell_at_grid_coords_int(IVec2::new(17, 0), WallGridCell::Wall);
-                h_world
-                    .grid
-                    .set_cell_at_grid_coords_int(IVec2::new(15, 0), WallGridCell::Wall);
-                first_person_view_horizontal.draw_view_horizontal(
-                    max_ray_distance,
-                    &h_world,
-                    h_pos,
-                    view_dir,
-                    0.5 / horizontal_line_scale, // If this is less than 1, we need to scale the line width of the output render by the same ampunt
-                    Some(&first_person_view),
-                    &h_world_floor,
-                    &h_world_ceiling,
-                    &sprite_images,
-                    true,
-                    0.5,
-                    &wall_bindings,
-                    current_pos_world.y,
-                    world_size.as_vec2(),
-                );
-
                 // Draw damage indicator
                 draw_damage_ui(
                     &mut ui_sprites,
                     &mut damage_ui_state,
                     last_frame_time,
                     &sprite_images,
+                    &game_sprites,
                     &mut first_person_view_horizontal);
 
-                first_person_view_horizontal.render(screen_size);
+                screen.render(&first_person_view_horizontal, screen_size);
 
                 // Draw FPS meter
                 let fps = get_fps();
                 draw_text(format!("{}", fps).as_str(), 20.0, 400.0, 30.0, DARKGRAY);
 
-                if done_turning {
-                    player_facing = -1.0 * player_facing;
-                    player_state.mode = Idle;
+                if !sim.is_turning() {
                     game_state = GameState::FirstPerson;
                 }
             }
 
             GameState::FirstPerson => {
-                // Read last keypress
+                // Read input
                 let last_key_pressed = get_last_key_pressed();
-                player_state.last_key_pressed = last_key_pressed;
+                let command = match last_key_pressed {
+                    None => None,
+                    Some(x) => match x {
+                        KeyCode::A => Some(PlayerCommand::TurnLeft),
+                        KeyCode::D => Some(PlayerCommand::TurnRight),
+                        KeyCode::W => Some(PlayerCommand::WalkForward),
+                        KeyCode::S => Some(PlayerCommand::WalkBackward),
+                        KeyCode::Q => Some(PlayerCommand::ClimbUp),
+                        KeyCode::E => Some(PlayerCommand::ClimbDown),
+                        KeyCode::Space => Some(PlayerCommand::Fire),
+                        KeyCode::Left => Some(PlayerCommand::SwapColor),
+                        KeyCode::Right => Some(PlayerCommand::Release),
+                        KeyCode::Key1 => Some(PlayerCommand::SelectSpell(0)),
+                        KeyCode::Key2 => Some(PlayerCommand::SelectSpell(1)),
+                        KeyCode::Key3 => Some(PlayerCommand::SelectSpell(2)),
+                        KeyCode::Key4 => Some(PlayerCommand::SelectSpell(3)),
+                        KeyCode::Key5 => Some(PlayerCommand::SelectSpell(4)),
+                        KeyCode::Key6 => Some(PlayerCommand::SelectSpell(5)),
+                        KeyCode::Key7 => Some(PlayerCommand::SelectSpell(6)),
+                        KeyCode::Key8 => Some(PlayerCommand::SelectSpell(7)),
+                        KeyCode::Key9 => Some(PlayerCommand::SelectSpell(8)),
+                        KeyCode::LeftShift => Some(PlayerCommand::LookHorizontal),
+                        _ => None,
+                    },
+                };
 
-                if last_key_pressed == Some(KeyCode::LeftShift) {
-                    game_state = GameState::FirstPersonHorizonal;
+                // A key pressed on a frame with no tick due waits for the next tick
+                if command.is_some() {
+                    input.command = command;
                 }
-
-                // Cooldown player attack
-                player_state.fire_cooldown = player_state.fire_cooldown.clamp(0.0, fire_cooldown);
+                input.look_up = is_key_down(KeyCode::Up);
+                input.look_down = is_key_down(KeyCode::Down);
 
                 // Update map
-                player_map.add_marker(player_pos.get_pos());
-
-                // Execute state machine
-                player_state.mode = match player_state.mode {
-                    Idle => player_state.do_idle_state(
-                        &mut mobs,
-                        &mut bullets,
-                        &mut player_facing,
-                        &player_pos,
-                        &world,
-                        &mob_grid,
-                        &mut mana_color,
-                        fire_cooldown,
-                    ),
-                    Moving => player_state.do_moving_state(
-                        &mut player_pos,
-                        &mut pos,
-                        &mut mob_grid,
-                        &mut mana_color,
-                        &mut world,
-                    ),
-                    Falling => player_state.do_falling_state(
-                        &mut player_pos,
-                        &mut pos,
-                        &mut mob_grid,
-                        &mut world,
-                    ),
-                    Turning(direction) => {
-                        game_state = GameState::FirstPersonHorizonal;
-                        horizontal_player_state.turn_direction = direction;
-                        horizontal_player_state.lerp = 0.0;
-                        horizontal_player_state.look_rotation = 0.0;
-                        Turning(direction)
+                player_map.add_marker(sim.player_pos.get_pos());
+
+                // Advance the game in fixed ticks
+                let mut events: Vec<SimEvent> = Vec::new();
+                for _ in 0..timestep.advance(last_frame_time) {
+                    let tick_input = next_tick_input(&input, &mut playback, &mut recording);
+                    events.extend(sim.tick(&tick_input, TICK_TIME));
+                    input.command = None;
+                }
+                for event in events {
+                    match event {
+                        SimEvent::PlayerHit => {
+                            damage_ui_state.push(DamageUIState::new(DamageIndicator::PlayerHit))
+                        }
+                        SimEvent::PlayerHeal => {
+                            damage_ui_state.push(DamageUIState::new(DamageIndicator::PlayerHeal))
+                        }
+                        SimEvent::HazardHit => {
+                            if damage_ui_state.is_empty() {
+                                damage_ui_state.push(DamageUIState::new(DamageIndicator::PlayerHit))
+                            }
+                        }
                     }
-                };
+                }
+                if sim.is_turning() {
+                    game_state = GameState::FirstPersonHorizonal;
+                }
+                let alpha = timestep.alpha();
+                let pos = sim.player_draw_coord(alpha);
+                let world = &sim.world;
 
                 match last_key_pressed {
                     None => {}
@@ -1007,27 +728,29 @@ async fn main() {
                             game_state = GameState::PlayerMap;
                         }
                         KeyCode::F8 => {
+                            // Edits are not part of the replay
+                            if recording.is_some() || playback.is_some() {
+                                println!("Editing the level ends the replay");
+                            }
+                            recording = None;
+                            playback = None;
                             game_state = GameState::LevelEditor;
                         }
                         _ => {}
                     },
                 }
 
-                // Delete mobs marked as dead
-                mobs.delete_dead_mobs(&mut mob_grid);
-                bullets.delete_dead_bullets();
-
                 // Update sprites.  Not really efficient but whatever
                 sprite_manager.clear_sprites();
-                for m in mobs.mob_list.iter() {
-                    let m = m.borrow();
+                for m in sim.mobs.mob_list.values() {
                     match &m.mob_type {
                         MobType::Monster(_) => {
-                            let shields = m.hp / MONSTER_HP;
-                            let monster_scaling = DVec4::new(0.6, 0.6, 0.0, shields);
+                            let shields = m.hp / m.max_hp;
+                            let (sprite_id, mut monster_scaling) = game_sprites.mob(&m.archetype, &sprite_images);
+                            monster_scaling.w = shields;
                             sprite_manager.add_sprite(
-                                m.get_pos(),
-                                (0, m.get_color()),
+                                interpolate(m.get_last_pos(), m.get_pos(), alpha),
+                                (sprite_id, m.get_color()),
                                 monster_scaling,
                             )
                         }
@@ -1035,217 +758,44 @@ async fn main() {
                 }
 
                 // Add bullet sprites
-                for b in bullets.bullet_list.iter() {
-                    let bullet_scaling = DVec4::new(0.1, 0.1, 0.0, 0.0);
-                    let sprite_type = (mana_color_srpite_id(b.get_color()), b.get_color());
-                    sprite_manager.add_sprite(b.get_pos(), sprite_type, bullet_scaling)
+                for b in sim.bullets.bullet_list.values() {
+                    let sprite_id = spell_sprite_id(b.spell.as_ref(), b.get_color(), &game_sprites);
+                    let bullet_scaling = sprite_images.get_scale(sprite_id);
+                    let sprite_type = (sprite_id, b.get_color());
+                    let bullet_pos = interpolate(b.get_last_pos(), b.get_pos(), alpha);
+                    sprite_manager.add_sprite(bullet_pos, sprite_type, bullet_scaling)
+                }
+                for f in sim.flashes.iter() {
+                    let sprite_id = spell_sprite_id(Some(&f.spell), f.color, &game_sprites);
+                    sprite_manager.add_sprite(f.pos, (sprite_id, f.color), sprite_images.get_scale(sprite_id))
                 }
 
                 // Add win room sprite
                 sprite_manager.add_sprite(
                     world_space_centered_coord(ucoords_to_icoords(world.win_room), 0.0, 0.1),
-                    (3, MagicColor::Black),
-                    DVec4::new(0.9, 0.9, 0.0, 0.0),
+                    (game_sprites.space_ship, MagicColor::Black),
+                    sprite_images.get_scale(game_sprites.space_ship),
                 );
 
                 // Add flavor sprites
                 if let Some(flavor_sprites) = &world.flavor_sprites {
-                    for &(x, y, sprite_id) in flavor_sprites {
-                        let sid = sprite_id + flavor_sprites_start_index;
-                        if sprite_images.check_image_index(sid) {
-                            // Don't crash for missing flavor images
-                            let flavor_scaling =
-                                if let Some(sca) = flavor_sprite_scaling.get(sprite_id) {
-                                    *sca
-                                } else {
-                                    DVec4::new(0.1, 0.1, 0.0, 0.0)
-                                };
-                            sprite_manager.add_sprite(
-                                DVec2::new(x, y),
-                                (sid, White),
-                                flavor_scaling,
-                            )
-                        }
+                    for (x, y, name) in flavor_sprites {
+                        // Missing flavor images show up as placeholders
+                        let sid = sprite_images.get_id_or_placeholder(name);
+                        sprite_manager.add_sprite(
+                            DVec2::new(*x, *y),
+                            (sid, White),
+                            sprite_images.get_scale(sid),
+                        )
                     }
                 }
 
-                // Animate mobs
-                for m in mobs.mob_list.iter() {
-                    let (is_monster, mut can_attack, can_change_color, mut can_move) = {
-                        let mob_type = &mut m.borrow_mut();
-                        match &mut mob_type.mob_type {
-                            MobType::Monster(monster) => {
-                                monster.update(last_frame_time);
-                                (
-                                    true,
-                                    monster.can_attack(),
-                                    monster.can_change_color(),
-                                    monster.can_move(),
-                                )
-                            }
-                        }
-                    };
-
-                    if can_move && can_attack {
-                        // decide on one or the other
-                        can_move = rand::random();
-                        can_attack = !can_move;
-                    }
-
-                    if is_monster && can_move {
-                        let mob_type = &mut m.borrow_mut();
-                        let mob_pos = mob_type.get_pos().as_ivec2();
-                        let dv: [(i32, i32); 4] = [(-1, 0), (1, 0), (0, 1), (0, -1)];
-                        let mut room_choices: Vec<IVec2> = Vec::new();
-                        for v in dv {
-                            let v = IVec2::from(v) + mob_pos;
-                            if is_room_occupiable(v, &mob_grid) && !is_wall(v, &world) {
-                                room_choices.push(v);
-                            }
-                        }
-                        if !room_choices.is_empty() {
-                            let random_room: usize =
-                                rand::thread_rng().gen_range(0..room_choices.len());
-                            let new_room = room_choices[random_room];
-                            mob_type.set_pos_centered(new_room.as_dvec2(), world.grid.get_size()); // Set new mob pos
-                            let old_mobid = mob_grid
-                                .get_cell_at_grid_coords_int(mob_pos)
-                                .unwrap()
-                                .clone();
-                            mob_grid.set_cell_at_grid_coords_int(new_room, old_mobid);
-                            mob_grid.set_cell_at_grid_coords_int(mob_pos, MobId::NoMob);
-                            let move_speed_modifier =
-                                if mob_type.get_pos().distance(player_pos.get_pos_dvec()) <= 3.0 {
-                                    0.5f64
-                                } else {
-                                    1.0f64
-                                };
-                            match &mut mob_type.mob_type {
-                                MobType::Monster(x) => {
-                                    x.start_move_cooldown(move_speed_modifier);
-                                }
-                            }
-                        }
-                    }
-
-                    // Change the enemy color if we can and are the same as the player
-                    let mut change_color: Option<MagicColor> = None;
-                    if can_change_color {
-                        let mob_type = &m.borrow();
-                        match &mob_type.mob_type {
-                            MobType::Monster(_) => {
-                                if mana_color == mob_type.get_color() {
-                                    if can_attack {
-                                        change_color = Some(mob_type.get_color().get_opposite());
-                                    }
-                                }
-                            }
-                        }
-                    }
-
-                    if let Some(new_color) = change_color {
-                        let mob_type = &mut m.borrow_mut();
-                        match &mut mob_type.mob_type {
-                            MobType::Monster(_) => {
-                                mob_type.set_color(new_color);
-                            }
-                        }
-                    }
-
-                    if change_color.is_some() {
-                        let mob_type = &mut m.borrow_mut();
-                        match &mut mob_type.mob_type {
-                            MobType::Monster(monster) => {
-                                monster.start_color_change_cooldown();
-                            }
-                        }
-                    }
-
-                    let mut fire: Option<(DVec2, DVec2, f64, MagicColor)> = None;
-                    if can_attack {
-                        let mob_type = &m.borrow();
-                        match &mob_type.mob_type {
-                            MobType::Monster(_) => {
-                                // Check if line of sight blocked by wall
-                                if let Some((_, dir_wall)) =
-                                    mob_type.has_line_of_sight_with_bc(pos, &world.grid)
-                                {
-                                    // Check if another monster blocks line of sight.
-                                    let x = mob_type.has_line_of_sight_with_bc(pos, &mob_grid);
-                                    if let Some((y, dir)) = x {
-                                        if let Some(hit) = mob_grid.get_cell_at_grid_coords_int(y) {
-                                            match hit {
-                                                MobId::NoMob => {}
-                                                MobId::Mob(_) => {}
-                                                MobId::Player => {
-                                                    if dir.dot(dir_wall) > 0.0 {
-                                                        fire = Some((
-                                                            mob_type.get_pos(),
-                                                            dir.normalize(),
-                                                            mob::MONSTER_HIT_DISTANCE + 0.01,
-                                                            mob_type.get_color(),
-                                                        ));
-                                                    }
-                                                }
-                                            }
-                                        }
-                                    }
-                                }
-                            }
-                        }
-                    }
-
-                    if fire.is_some() {
-                        let mob_type = &mut m.borrow_mut();
-                        match &mut mob_type.mob_type {
-                            MobType::Monster(monster) => {
-                                monster.start_attack_cooldown();
-                            }
-                        }
-                    }
-
-                    if let Some(x) = fire {
-                        new_bullets.push(x)
-                    }
-                }
-
-                // Animate Bullets
-                for bullet in bullets.bullet_list.iter_mut() {
-                    move_bullets(
-                        bullet,
-                        last_frame_time,
-                        &world,
-                        &mob_grid,
-                        &mut collisions,
-                    );
-                }
-
-                // Create new bullets
-                for (pos, dir, offset,color) in new_bullets.iter() {
-                    bullets.new_bullet(*pos, *dir, *offset, *color);
-                }
-                new_bullets.clear();
-
-                // Handle collisions
-                for c in collisions.iter() {
-                    match c.damage_target(&mut player_hp, player_max_hp, mana_color) {
-                        DamageIndicator::PlayerHit => {
-                            damage_ui_state.push(DamageUIState::new(DamageIndicator::PlayerHit))
-                        }
-                        DamageIndicator::PlayerHeal => {
-                            damage_ui_state.push(DamageUIState::new(DamageIndicator::PlayerHeal))
-                        }
-                        DamageIndicator::Other => {}
-                    }
-                }
-                collisions.clear();
-
                 // Draw frame
                 draw_first_person_frame(
-                    &player_state,
-                    player_facing,
+                    sim.player_state.look_rotation,
+                    sim.player_facing,
                     max_ray_distance,
-                    &world,
+                    world,
                     pos,
                     plane_scale,
                     &sprite_images,
@@ -1262,23 +812,24 @@ async fn main() {
                     &mut damage_ui_state,
                     last_frame_time,
                     &sprite_images,
+                    &game_sprites,
                     &mut first_person_view);
 
-                first_person_view.render(screen_size);
+                screen.render(&first_person_view, screen_size);
 
                 // Draw FPS meter
                 let fps = get_fps();
                 draw_text(format!("{}", fps).as_str(), 20.0, 400.0, 30.0, DARKGRAY);
 
                 // Show UI
-                let (ui_color, mana_color_string) = match mana_color {
+                let (ui_color, mana_color_string) = match sim.mana_color {
                     White => (color::WHITE, "Light"),
                     Black => (color::DARKPURPLE, "Void"),
                 };
                 let font_size = 30.0 * (screen_size.0 / 800.0);
                 let font_y_spacing = font_size * 0.6;
                 let font_y_padding = font_size * 0.05;
-                let health_string = format!("HP: {}/{}", player_hp as i32, player_max_hp as i32);
+                let health_string = format!("HP: {}/{}", sim.player_hp as i32, PLAYER_MAX_HP as i32);
                 let mana_string = format!("Mana type: {}", mana_color_string);
                 draw_text(
                     health_string.as_str(),
@@ -1294,22 +845,65 @@ async fn main() {
                     font_size,
                     ui_color,
                 );
-
-                // Decrease weapon cooldown
-                player_state.fire_cooldown -= last_frame_time;
-
-                // Actions to set when transitioning to other game states
-                if game_state == FirstPersonHorizonal {
-                    horizontal_player_state.lerp = 0.0;
-                    horizontal_player_state.look_rotation = 0.0;
-                }
+                let score_string = format!("Score: {}", sim.score.points);
+                let chain_string = match sim.score.link_progress() {
+                    None => format!("Chain: {}", sim.score.chain),
+                    Some((link_color, kills)) => {
+                        let link_color_string = match link_color {
+                            White => "Light",
+                            Black => "Void",
+                        };
+                        format!("Chain: {} ({}/{} {})", sim.score.chain, kills, CHAIN_LENGTH, link_color_string)
+                    }
+                };
+                draw_text(
+                    score_string.as_str(),
+                    font_size * 0.1,
+                    3.0 * (font_y_spacing + font_y_padding),
+                    font_size,
+                    ui_color,
+                );
+                draw_text(
+                    chain_string.as_str(),
+                    font_size * 0.1,
+                    4.0 * (font_y_spacing + font_y_padding),
+                    font_size,
+                    ui_color,
+                );
+                let polarity_string = format!("Polarity: {}/{}", sim.polarity, POLARITY_MAX);
+                draw_text(
+                    polarity_string.as_str(),
+                    font_size * 0.1,
+                    5.0 * (font_y_spacing + font_y_padding),
+                    font_size,
+                    ui_color,
+                );
+                let spell_string = match sim.spells.spells.get(sim.selected_spell) {
+                    None => format!("Mana: {}/{}", sim.mana as i32, PLAYER_MAX_MANA as i32),
+                    Some(spell) => format!(
+                        "Spell {}: {} ({})  Mana: {}/{}",
+                        sim.selected_spell + 1,
+                        spell.id,
+                        spell.mana_cost as i32,
+                        sim.mana as i32,
+                        PLAYER_MAX_MANA as i32
+                    ),
+                };
+                draw_text(
+                    spell_string.as_str(),
+                    font_size * 0.1,
+                    6.0 * (font_y_spacing + font_y_padding),
+                    font_size,
+                    ui_color,
+                );
             }
 
             GameState::LevelEditor => {
                 let (new_position, new_state) = level_editor.draw_editor(
-                    &mut world,
-                    &mut mobs,
-                    &mut mob_grid,
+                    &mut sim.world,
+                    &mut sim.mobs,
+                    &mut sim.mob_grid,
+                    &archetypes,
                     screen_size,
                     pos,
                     dir,
@@ -1320,7 +914,7 @@ async fn main() {
             }
 
             GameState::PlayerMap => {
-                if let Some(x) = player_map.draw_map(screen_size, player_pos.get_pos_dvec()) {
+                if let Some(x) = player_map.draw_map(screen_size, sim.player_pos.get_pos_dvec()) {
                     game_state = x;
                 }
             }
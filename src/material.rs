@@ -0,0 +1,77 @@
+use serde::{Deserialize, Serialize};
+
+use crate::fpv::SpriteId;
+use crate::image::ImageLoader;
+
+pub type MaterialId = usize;
+
+// Texture set for one room, by asset name.  Floor and ceiling are the top and bottom faces of a
+// solid cell, wall is the face seen head on, left and right are the side walls of an open room.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct Material {
+    pub floor: String,
+    pub ceiling: String,
+    pub wall: String,
+    pub left: String,
+    pub right: String,
+}
+
+// The same texture set with names looked up, this is what the renderers use
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub struct MaterialSprites {
+    pub floor: SpriteId,
+    pub ceiling: SpriteId,
+    pub wall: SpriteId,
+    pub left: SpriteId,
+    pub right: SpriteId,
+}
+
+// Used when a level does not provide its own material table.  Floor, ceiling, wall, left, right.
+const DEFAULT_MATERIALS: [[&str; 5]; 4] = [
+    ["steel_floor", "light_wall", "pez_wall", "sf5", "sf6"],
+    ["steel_floor", "light_wall", "pez_wall", "sf1", "sf8"],
+    ["pn1", "pn1", "pn1", "pn1", "pn1"],
+    ["steel_floor", "pez_wall", "light_wall", "sf8", "sf1"],
+];
+
+pub fn default_materials() -> Vec<Material> {
+    DEFAULT_MATERIALS.iter().map(|m| Material::new(m)).collect()
+}
+
+pub fn default_material_count() -> usize {
+    DEFAULT_MATERIALS.len()
+}
+
+impl Material {
+    fn new(names: &[&str; 5]) -> Self {
+        Material {
+            floor: names[0].to_string(),
+            ceiling: names[1].to_string(),
+            wall: names[2].to_string(),
+            left: names[3].to_string(),
+            right: names[4].to_string(),
+        }
+    }
+
+    pub fn names(&self) -> [&str; 5] {
+        [self.floor.as_str(), self.ceiling.as_str(), self.wall.as_str(), self.left.as_str(), self.right.as_str()]
+    }
+
+    // Names missing from the manifest resolve to the placeholder, Level::missing_assets reports them
+    pub fn resolve(&self, images: &ImageLoader) -> MaterialSprites {
+        let id = |name: &str| images.get_id_or_placeholder(name);
+        MaterialSprites {
+            floor: id(&self.floor),
+            ceiling: id(&self.ceiling),
+            wall: id(&self.wall),
+            left: id(&self.left),
+            right: id(&self.right),
+        }
+    }
+}
+
+impl Default for Material {
+    fn default() -> Self {
+        Material::new(&DEFAULT_MATERIALS[0])
+    }
+}
@@ -1,27 +1,23 @@
-use std::cell::RefCell;
-use std::ops::Neg;
-use std::rc::Rc;
-
 use macroquad::math::{DVec2, IVec2};
-use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde::{Deserialize, Serialize};
 use serde::de::DeserializeOwned;
 
-use crate::grid2d::{Grid2D, WallGridCell};
-use crate::level::{apply_boundary_conditions_f64, ucoords_to_dvec2};
+use crate::arena::{Arena, Handle};
+use crate::behavior::{Behavior, BehaviorState};
+use crate::archetype::{Archetype, ArchetypeRegistry, BulletPattern, ColorBehavior, DEFAULT_ARCHETYPE};
+use crate::grid2d::{Grid2D, Topology, WallGridCell};
+use crate::level::{apply_boundary_conditions_f64, periodic_images};
+use crate::pathfinding::{Locomotion, PatrolRoute};
 use crate::raycaster::cast_ray;
+use crate::spell::Spell;
 
 type AliveDead = bool;
 
-pub const MONSTER_HP:f64 = 100.0;
-const MONSTER_MOVE_COOLDOWN: f64 = 6.0;
-const MONSTER_ATTACK_COOLDOWN: f64 = 6.0;
-const MONSTER_COLOR_CHANGE_COOLDOWN: f64 = 4.0;
-
-const MONSTER_LINE_OF_SIGHT: f64 = 12.0;
+pub const MONSTER_FALL_SPEED: f64 = 5.0; // Rooms per second, walking monsters fall at least this fast
 
 pub const MONSTER_HIT_DISTANCE: f64 = 0.4;
 
-#[derive(Copy, Clone, Debug, PartialEq)]
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub enum MagicColor {
     White,
     Black
@@ -36,17 +32,111 @@ impl MagicColor {
     }
 }
 
+// Behavior overrides for a single spawn, anything left out uses the archetype's stats
+#[derive(Copy, Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct MonsterParams {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub move_cooldown: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attack_cooldown: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub color_change_cooldown: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub line_of_sight: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub move_speed: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub locomotion: Option<Locomotion>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub behavior: Option<Behavior>
+}
+
+fn default_archetype() -> String {
+    DEFAULT_ARCHETYPE.to_string()
+}
+
+// One mob placed in a level file
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct MobSpawn {
+    pub pos: (i32, i32),
+    pub color: MagicColor,
+    #[serde(default = "default_archetype")]
+    pub archetype: String, // Id in mobs.json
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hp: Option<f64>, // Starting and maximum HP
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub params: Option<MonsterParams>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub patrol: Option<Vec<(i32, i32)>> // Rooms visited in turn while the player is out of sight
+}
+
+impl MobSpawn {
+    pub fn new(pos: IVec2, color: MagicColor) -> Self {
+        MobSpawn {
+            pos: pos.into(),
+            color,
+            archetype: default_archetype(),
+            hp: None,
+            params: None,
+            patrol: None
+        }
+    }
+
+    pub fn get_pos(&self) -> IVec2 {
+        IVec2::from(self.pos)
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
 pub struct MonsterState{
     last_move_time: f64, // time since last move completed
     last_attack_time: f64,
     last_color_change_time: f64,
+    move_cooldown: f64,
+    attack_cooldown: f64,
+    color_change_cooldown: f64,
+    #[serde(default)]
+    pub locomotion: Locomotion,
+    #[serde(default)]
+    pub patrol: Option<PatrolRoute>,
+    #[serde(default)]
+    pub bullet_pattern: BulletPattern,
+    #[serde(default)]
+    pub color_behavior: ColorBehavior,
+    #[serde(default)]
+    pub behavior: Behavior,
+    #[serde(default)]
+    pub state: BehaviorState,
+    #[serde(default)]
+    pub last_seen: Option<IVec2>, // Where the player was last sensed, cleared once checked
 }
 
 impl MonsterState {
-    pub fn update(&mut self, last_frame_time: f64) {
-        self.last_move_time = (self.last_move_time - last_frame_time).clamp(0.0, MONSTER_MOVE_COOLDOWN);
-        self.last_attack_time = (self.last_attack_time - last_frame_time).clamp(0.0, MONSTER_ATTACK_COOLDOWN);
-        self.last_color_change_time = (self.last_color_change_time - last_frame_time).clamp(0.0, MONSTER_COLOR_CHANGE_COOLDOWN);
+    pub fn new(archetype: &Archetype, params: &MonsterParams, patrol: Option<PatrolRoute>) -> Self {
+        let move_cooldown = params.move_cooldown.unwrap_or(archetype.move_cooldown);
+        let attack_cooldown = params.attack_cooldown.unwrap_or(archetype.attack_cooldown);
+        let color_change_cooldown = params.color_change_cooldown.unwrap_or(archetype.color_change_cooldown);
+        MonsterState {
+            last_move_time: move_cooldown,
+            last_attack_time: attack_cooldown,
+            last_color_change_time: color_change_cooldown,
+            move_cooldown,
+            attack_cooldown,
+            color_change_cooldown,
+            locomotion: params.locomotion.unwrap_or(archetype.locomotion),
+            patrol,
+            bullet_pattern: archetype.bullet_pattern,
+            color_behavior: archetype.color_behavior,
+            behavior: params.behavior.unwrap_or(archetype.behavior),
+            state: BehaviorState::Idle,
+            last_seen: None,
+        }
+    }
+
+    pub fn update(&mut self, dt: f64) {
+        self.last_move_time = (self.last_move_time - dt).clamp(0.0, self.move_cooldown);
+        self.last_attack_time = (self.last_attack_time - dt).clamp(0.0, self.attack_cooldown);
+        self.last_color_change_time = (self.last_color_change_time - dt).clamp(0.0, self.color_change_cooldown);
     }
 
     pub fn can_attack(&self) -> bool {
@@ -62,29 +152,38 @@ impl MonsterState {
     }
 
     pub fn start_attack_cooldown(&mut self) {
-        self.last_attack_time = MONSTER_ATTACK_COOLDOWN;
+        self.last_attack_time = self.attack_cooldown;
     }
 
     pub fn start_color_change_cooldown(&mut self) {
-        self.last_color_change_time = MONSTER_COLOR_CHANGE_COOLDOWN;
+        self.last_color_change_time = self.color_change_cooldown;
     }
 
     pub fn start_move_cooldown(&mut self, modifier: f64) {
-        self.last_move_time = MONSTER_MOVE_COOLDOWN * modifier;
+        self.last_move_time = self.move_cooldown * modifier;
     }
 }
 
+#[derive(Clone, Serialize, Deserialize)]
 pub enum MobType {
     Monster(MonsterState),
 }
+
+#[derive(Clone, Serialize, Deserialize)]
 pub struct MobData {
     pub is_alive: AliveDead,
+    #[serde(default = "default_archetype")]
+    pub archetype: String,
     pub hp: f64,
+    pub max_hp: f64,
     pub moving: Option<(DVec2, DVec2, f64)>, // start coord, end coord, lerp
     pub move_speed: f64,
     pos: DVec2,
+    #[serde(skip)]
+    last_pos: Option<DVec2>, // Position at the start of the tick, for drawing between ticks
     pub mob_type: MobType,
-    color: MagicColor
+    color: MagicColor,
+    line_of_sight: f64
 }
 
 impl MobData {
@@ -92,81 +191,95 @@ impl MobData {
         self.pos
     }
 
-    pub fn set_pos(&mut self, pos: DVec2, world_size: (usize, usize)) {
-        self.pos = apply_boundary_conditions_f64(pos, world_size);
+    pub fn set_pos(&mut self, pos: DVec2, world_size: (usize, usize), topology: Topology) {
+        self.pos = apply_boundary_conditions_f64(pos, world_size, topology);
     }
 
-    pub fn set_pos_centered(&mut self, pos: DVec2, world_size: (usize, usize)) {
-        self.pos = apply_boundary_conditions_f64(pos + DVec2::new(0.5, 0.5), world_size);
+    pub fn get_last_pos(&self) -> DVec2 {
+        self.last_pos.unwrap_or(self.pos)
     }
 
+    pub fn start_tick(&mut self) {
+        self.last_pos = Some(self.pos);
+    }
 
-    pub fn has_line_of_sight_with_bc<T>(&self, target: DVec2, grid: &Grid2D<T>) -> Option<(IVec2, DVec2)> // hit coord, direction
-        where T: Default + Clone + Serialize + DeserializeOwned + Into<WallGridCell>{
-        let ws = ucoords_to_dvec2(grid.get_size());
-        let target = apply_boundary_conditions_f64(target, grid.get_size());
-        let (right, left) = if self.pos.x < target.x { // target to our right
-            let target_left = DVec2::new((ws.x - target.x).neg(), target.y);
-            ((self.has_line_of_sight(target, grid), target - self.pos), // shoot right
-             (self.has_line_of_sight(target_left, grid), target_left - self.pos)) // shot left
-        } else { //target to our left;
-            let target_right = DVec2::new(ws.x + target.x, target.y);
-            ((self.has_line_of_sight(target_right, grid), target_right - self.pos), // shoot right
-             (self.has_line_of_sight(target, grid), target - self.pos)) // shoot left
-        };
+    // Starts moving to the room next to this one.  The end is the copy of the room across any
+    // wrapped edge that is closest, so the mob takes the short way.
+    pub fn start_move(&mut self, room: IVec2, world_size: (usize, usize), topology: Topology) {
+        let center = room.as_dvec2() + DVec2::new(0.5, 0.5);
+        let start = self.pos;
+        let mut end = center;
+        for image in periodic_images(center, world_size, topology) {
+            if image.distance(start) < end.distance(start) {
+                end = image;
+            }
+        }
+        self.moving = Some((start, end, 0.0));
+    }
 
-        let left: Option<(IVec2, DVec2)>  = if left.0.is_some() {
-            Some((left.0.unwrap(), left.1))
-        } else {
-            None
-        };
+    // Moves distance along the current move, true once it has arrived
+    pub fn advance_move(&mut self, distance: f64, world_size: (usize, usize), topology: Topology) -> bool {
+        match self.moving {
+            None => {true}
+            Some((start, end, lerp)) => {
+                let mut lerp = lerp + distance / start.distance(end).max(1e-9);
+                if lerp > 1.0 - 1e-9 {
+                    lerp = 1.0; // Don't leave a sliver of the move for rounding errors
+                }
+                self.set_pos(start.lerp(end, lerp), world_size, topology);
+                if lerp == 1.0 {
+                    self.moving = None;
+                    true
+                } else {
+                    self.moving = Some((start, end, lerp));
+                    false
+                }
+            }
+        }
+    }
 
-        let right: Option<(IVec2, DVec2)> = if right.0.is_some() {
-            Some((right.0.unwrap(), right.1))
-        } else {
-            None
-        };
+    // Rooms the mob takes up, both ends of a move until it arrives
+    pub fn occupied_rooms(&self, world_size: (usize, usize), topology: Topology) -> Vec<IVec2> {
+        match self.moving {
+            None => {vec![self.pos.as_ivec2()]}
+            Some((start, end, _)) => {
+                let end = apply_boundary_conditions_f64(end, world_size, topology);
+                vec![start.as_ivec2(), end.as_ivec2()]
+            }
+        }
+    }
 
-        let mut los = left;
+    pub fn set_pos_centered(&mut self, pos: DVec2, world_size: (usize, usize), topology: Topology) {
+        self.pos = apply_boundary_conditions_f64(pos + DVec2::new(0.5, 0.5), world_size, topology);
+    }
 
-        if let Some(r) = right {
-            if let Some(l) = los {
-                let dist_to_right = self.pos.distance_squared(r.0.as_dvec2());
-                let dist_to_left = self.pos.distance_squared(l.0.as_dvec2());
-                if dist_to_left < dist_to_right {
-                    los = Some(l);
-                } else {
-                    los = Some(r);
+
+    pub fn has_line_of_sight_with_bc<T>(&self, target: DVec2, grid: &Grid2D<T>) -> Option<(IVec2, DVec2)> // hit coord, direction
+        where T: Default + Clone + Serialize + DeserializeOwned + Into<WallGridCell>{
+        let target = apply_boundary_conditions_f64(target, grid.get_size(), grid.get_topology());
+
+        // Look at every copy of the target across the world boundaries, keep the closest visible one
+        let mut los: Option<(IVec2, DVec2)> = None;
+        for image in periodic_images(target, grid.get_size(), grid.get_topology()) {
+            let dir = image - self.pos;
+            if dir.length_squared() < 1e-9 {
+                continue;
+            }
+            if let Some(hit) = self.has_line_of_sight(image, grid) {
+                let closer = match los {
+                    None => {true}
+                    Some((_, los_dir)) => {dir.length_squared() < los_dir.length_squared()}
+                };
+                if closer {
+                    los = Some((hit, dir));
                 }
-            } else { // los is None, use right
-                los = Some(r);
             }
         }
         los
     }
     pub fn has_line_of_sight<T>(&self, target: DVec2, grid: &Grid2D<T>) -> Option<IVec2>
         where T: Default + Clone + Serialize + DeserializeOwned + Into<WallGridCell> {
-        let sight_vector = target - self.pos;
-        let dir = sight_vector.normalize();
-        let (_, _, _, coord) = cast_ray(grid, &self.pos, &dir, MONSTER_LINE_OF_SIGHT);
-        let hit_coord = coord.as_dvec2() + DVec2::new(0.5, 0.5); // Find center of coordinate hit
-        let hit_distance= hit_coord.distance(self.pos);
-        let sight_distance = sight_vector.length();
-
-        let has_los = if hit_distance <= sight_distance {
-            if sight_distance - hit_distance < 0.2 { // fudge factor
-                true
-            } else{
-                false
-            }
-        } else {
-            true
-        };
-        if has_los {
-            Some(coord)
-        } else {
-            None
-        }
+        line_of_sight(self.pos, target, self.line_of_sight, grid)
     }
 
     pub fn set_color(&mut self, color: MagicColor) {
@@ -176,14 +289,18 @@ impl MobData {
     pub fn get_color(&self) -> MagicColor {
         self.color
     }
+
+    pub fn get_line_of_sight(&self) -> f64 {
+        self.line_of_sight
+    }
 }
 
-pub type Mob = Rc<RefCell<Box<MobData>>>;
-#[derive(Default, Clone)]
+// What stands in a room of the mob grid.  Mobs are referred to by their handle in Mobs::mob_list.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
 pub enum MobId {
     #[default]
     NoMob,
-    Mob(Mob),
+    Mob(Handle),
     Player
 }
 
@@ -197,28 +314,30 @@ impl From<MobId> for WallGridCell {
     }
 }
 
-impl Serialize for MobId {
-    fn serialize<S>(&self, _: S) -> Result<S::Ok, S::Error> where S: Serializer {
-        todo!()
-    }
-}
-
-impl<'de> Deserialize<'de> for MobId {
-    fn deserialize<D>(_: D) -> Result<Self, D::Error> where D: Deserializer<'de> {
-        todo!()
-    }
-
-    fn deserialize_in_place<D>(_: D, _: &mut Self) -> Result<(), D::Error> where D: Deserializer<'de> {
-        todo!()
-    }
+#[derive(Copy, Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum BulletKind {
+    #[default]
+    Straight,
+    Homing // Steers toward the closest mob in sight
 }
 
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Bullet {
     pub is_alive: AliveDead,
     pub moving: Option<(DVec2, DVec2, f64)>, // start coord, end coord, lerp
     pub move_speed: f64,
     pos: DVec2,
-    color: MagicColor
+    #[serde(skip)]
+    last_pos: Option<DVec2>, // Position before the last move, for drawing between ticks
+    color: MagicColor,
+    #[serde(default)]
+    pub shooter: MobId, // Who fired it, kills only score for the player
+    #[serde(default)]
+    pub kind: BulletKind,
+    #[serde(default)]
+    pub damage: Option<f64>, // Damage to mobs, the fixed amount when not fired by a spell
+    #[serde(default)]
+    pub spell: Option<String> // Id of the spell that fired it, for drawing
 }
 
 impl Bullet {
@@ -234,45 +353,71 @@ impl Bullet {
         self.pos
     }
 
-    pub fn set_pos(&mut self, pos: DVec2, world_size: (usize, usize)) {
-        self.pos = apply_boundary_conditions_f64(pos, world_size);
+    pub fn get_last_pos(&self) -> DVec2 {
+        self.last_pos.unwrap_or(self.pos)
+    }
+
+    pub fn set_pos(&mut self, pos: DVec2, world_size: (usize, usize), topology: Topology) {
+        self.last_pos = Some(self.pos);
+        self.pos = apply_boundary_conditions_f64(pos, world_size, topology);
     }
 }
 
 pub struct Bullets {
-    pub bullet_list: Vec<Bullet>
+    pub bullet_list: Arena<Bullet>
 }
 
 impl Bullets {
     pub fn new() -> Self {
         Bullets {
-            bullet_list: Vec::new()
+            bullet_list: Arena::new()
         }
     }
 
-    pub fn new_bullet(&mut self, pos: DVec2, dir: DVec2, offset_in_dir: f64, color: MagicColor) -> usize {
-        let float_speed = 2.0; // In world coordinates per second
-        let max_lifetime = 5.0;
+    pub fn new_bullet(&mut self, pos: DVec2, dir: DVec2, offset_in_dir: f64, color: MagicColor, shooter: MobId) -> Handle {
+        let bullet = Self::make_bullet(pos, dir, offset_in_dir, color, shooter, 2.0, 5.0);
+        self.bullet_list.insert(bullet)
+    }
+
+    pub fn new_spell_bullet(&mut self, pos: DVec2, dir: DVec2, offset_in_dir: f64, color: MagicColor, spell: &Spell) -> Handle {
+        let mut bullet = Self::make_bullet(pos, dir, offset_in_dir, color, MobId::Player, spell.speed, spell.lifetime);
+        bullet.damage = Some(spell.damage);
+        bullet.spell = Some(spell.id.clone());
+        self.bullet_list.insert(bullet)
+    }
+
+    // float_speed is in world coordinates per second
+    fn make_bullet(pos: DVec2, dir: DVec2, offset_in_dir: f64, color: MagicColor, shooter: MobId, float_speed: f64, max_lifetime: f64) -> Bullet {
         let dir_vec = dir.normalize();
 
         let pos =
             pos + offset_in_dir*dir_vec;
         let end_pos = float_speed*max_lifetime*dir_vec + pos;
 
-        let bullet = Bullet {
+        Bullet {
             is_alive: true,
             moving: Some((pos, end_pos, 0.0)),
             move_speed: float_speed,
             pos,
+            last_pos: None,
             color,
-        };
+            shooter,
+            kind: BulletKind::Straight,
+            damage: None,
+            spell: None,
+        }
+    }
 
-        self.bullet_list.push(bullet);
-        self.bullet_list.len() - 1
+    pub fn new_homing_bullet(&mut self, pos: DVec2, dir: DVec2, offset_in_dir: f64, color: MagicColor, shooter: MobId) -> Handle {
+        let handle = self.new_bullet(pos, dir, offset_in_dir, color, shooter);
+        if let Some(x) = self.bullet_list.get_mut(handle) {
+            x.kind = BulletKind::Homing;
+        }
+        handle
     }
 
     pub fn delete_dead_bullets(&mut self) {
-        self.bullet_list.retain_mut(|x| {
+        self.bullet_list.retain(|_, x| {
             x.is_alive
         });
     }
@@ -281,61 +426,81 @@ impl Bullets {
 
 
 pub struct Mobs {
-    pub mob_list: Vec<Mob>
+    pub mob_list: Arena<MobData>
 }
 
 impl Mobs {
     pub fn new() -> Self {
         Mobs {
-            mob_list: Vec::new()
+            mob_list: Arena::new()
+        }
+    }
+
+    // Clears the grid and puts every living mob back in the cells it occupies
+    pub fn rebuild_mob_grid(&self, mob_grid: &mut Grid2D<MobId>) {
+        mob_grid.zero();
+        for (handle, m) in self.mob_list.iter() {
+            if m.is_alive {
+                for room in m.occupied_rooms(mob_grid.get_size(), mob_grid.get_topology()) {
+                    mob_grid.set_cell_at_grid_coords_int(room, MobId::Mob(handle));
+                }
+            }
         }
     }
 
     pub fn delete_dead_mobs(&mut self, mob_grid: &mut Grid2D<MobId>) {
-        let mut dead_mobs: Vec<usize> = Vec::new(); // TODO: create outside game loop
-        for (i, mob) in self.mob_list.iter().enumerate() {
-            let mob = mob.borrow();
-            if !mob.is_alive {
-                dead_mobs.push(i);
-                match  &mob.mob_type {
-                    MobType::Monster(_) => {
-                        mob_grid.set_cell_at_grid_coords_int(mob.pos.as_ivec2(), MobId::NoMob);
+        self.mob_list.retain(|handle, mob| {
+            if mob.is_alive {
+                return true;
+            }
+            match &mob.mob_type {
+                MobType::Monster(_) => {
+                    for room in mob.occupied_rooms(mob_grid.get_size(), mob_grid.get_topology()) {
+                        if mob_at_cell(room, mob_grid) == MobId::Mob(handle) {
+                            mob_grid.set_cell_at_grid_coords_int(room, MobId::NoMob);
+                        }
                     }
                 }
             }
-        }
-        dead_mobs.sort();
-        for i in dead_mobs.iter().rev() {
-            self.mob_list.swap_remove(*i);
-        }
+            false
+        });
     }
-    pub fn new_monster(&mut self, pos: IVec2, mob_grid: &mut Grid2D<MobId>, color: MagicColor) -> bool {
+
+    // Spawns a mob of the spawn's archetype, false if the room is taken or the archetype unknown
+    pub fn new_monster(&mut self, spawn: &MobSpawn, archetypes: &ArchetypeRegistry, mob_grid: &mut Grid2D<MobId>) -> bool {
+        let pos = spawn.get_pos();
+        let archetype = match archetypes.get(&spawn.archetype) {
+            None => {return false;}
+            Some(x) => {x}
+        };
         if let Some(m) = mob_grid.get_cell_at_grid_coords_int(pos) {
             match m {
                 MobId::NoMob => { // No mob here
-                    let float_speed = 1.0; // In world coordinates per second
+                    let params = spawn.params.unwrap_or_default();
+                    let float_speed = params.move_speed.unwrap_or(archetype.move_speed); // In world coordinates per second
                     let offset = DVec2::from((0.5, 0.5));
                     let real_pos = pos.as_dvec2() + offset;
+                    let hp = spawn.hp.unwrap_or(archetype.hp);
 
+                    let patrol = spawn.patrol.as_ref()
+                        .map(|p| PatrolRoute::new(p.iter().map(|x| IVec2::from(*x)).collect()));
+                    let mob_type = MobType::Monster(MonsterState::new(archetype, &params, patrol));
                     let mob = MobData {
                         is_alive: true,
-                        hp: MONSTER_HP,
+                        archetype: archetype.id.clone(),
+                        hp,
+                        max_hp: hp,
                         moving: None,
                         move_speed: float_speed,
                         pos: real_pos,
-                        color,
-                        mob_type: MobType::Monster(
-                            MonsterState {
-                                last_move_time: MONSTER_MOVE_COOLDOWN,
-                                last_attack_time: MONSTER_ATTACK_COOLDOWN,
-                                last_color_change_time: MONSTER_COLOR_CHANGE_COOLDOWN,
-                            }
-                        )
+                        last_pos: None,
+                        color: spawn.color,
+                        mob_type,
+                        line_of_sight: params.line_of_sight.unwrap_or(archetype.line_of_sight)
                     };
 
-                    let new_mob = Rc::new(RefCell::new(Box::new(mob)));
-                    mob_grid.set_cell_at_grid_coords_int(pos, MobId::Mob(new_mob.clone()));
-                    self.mob_list.push(new_mob);
+                    let handle = self.mob_list.insert(mob);
+                    mob_grid.set_cell_at_grid_coords_int(pos, MobId::Mob(handle));
                     return true;
                 }
                 _ => {}
@@ -345,9 +510,36 @@ impl Mobs {
     }
 }
 
+// Can target be seen from pos, without looking across world boundaries.  Gives the room the sight
+// line ends in.
+pub fn line_of_sight<T>(pos: DVec2, target: DVec2, max_distance: f64, grid: &Grid2D<T>) -> Option<IVec2>
+    where T: Default + Clone + Serialize + DeserializeOwned + Into<WallGridCell> {
+    let sight_vector = target - pos;
+    let dir = sight_vector.normalize();
+    let (_, _, _, coord) = cast_ray(grid, &pos, &dir, max_distance);
+    let hit_coord = coord.as_dvec2() + DVec2::new(0.5, 0.5); // Find center of coordinate hit
+    let hit_distance= hit_coord.distance(pos);
+    let sight_distance = sight_vector.length();
+
+    let has_los = if hit_distance <= sight_distance {
+        if sight_distance - hit_distance < 0.2 { // fudge factor
+            true
+        } else{
+            false
+        }
+    } else {
+        true
+    };
+    if has_los {
+        Some(coord)
+    } else {
+        None
+    }
+}
+
 pub fn mob_at_cell(pos: IVec2, grid: &Grid2D<MobId>) -> MobId {
     if let Some(x) = grid.get_cell_at_grid_coords_int(pos) {
-        x.clone()
+        *x
     } else {
         MobId::NoMob
     }
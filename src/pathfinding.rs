@@ -0,0 +1,395 @@
+use std::collections::{HashMap, VecDeque};
+
+use macroquad::math::IVec2;
+use serde::{Deserialize, Serialize};
+
+use crate::grid2d::Grid2D;
+use crate::level::{apply_boundary_conditions_i32, Level};
+use crate::mob::{mob_at_cell, MagicColor, MobId};
+use crate::player_movement::{can_climb_down, can_climb_up, has_floor, is_hazard, is_supported_position, is_wall};
+
+// How a mob gets from room to room
+#[derive(Copy, Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum Locomotion {
+    #[default]
+    Flying,  // Any open room next to it
+    Walking  // Same rules as the player: needs support, climbs ladders and shafts, falls into pits
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum PathGoal {
+    Pursue(IVec2), // Get next to an occupied room, usually the player
+    Flee(IVec2),   // Get as far from it as possible
+    Reach(IVec2)   // Stand in the room, patrol waypoints
+}
+
+// Waypoints visited in order, starting over after the last one
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PatrolRoute {
+    waypoints: Vec<IVec2>,
+    next: usize
+}
+
+impl PatrolRoute {
+    pub fn new(waypoints: Vec<IVec2>) -> Self {
+        PatrolRoute {
+            waypoints,
+            next: 0
+        }
+    }
+
+    // Waypoint to head for from pos, moves on to the following one once pos is reached
+    pub fn target(&mut self, pos: IVec2) -> Option<IVec2> {
+        if self.waypoints.is_empty() {
+            return None;
+        }
+        if self.waypoints[self.next] == pos {
+            self.advance();
+        }
+        Some(self.waypoints[self.next])
+    }
+
+    // Gives up on the current waypoint
+    pub fn advance(&mut self) {
+        if !self.waypoints.is_empty() {
+            self.next = (self.next + 1) % self.waypoints.len();
+        }
+    }
+}
+
+fn is_occupied(pos: IVec2, mob_grid: &Grid2D<MobId>) -> bool {
+    match mob_at_cell(pos, mob_grid) {
+        MobId::NoMob => {false}
+        MobId::Mob(_) => {true}
+        MobId::Player => {true}
+    }
+}
+
+// A walking mob with nothing holding it up drops a room at a time until it lands
+pub fn is_falling(pos: IVec2, locomotion: Locomotion, color: MagicColor, level: &Level, mob_grid: &Grid2D<MobId>) -> bool {
+    match locomotion {
+        Locomotion::Flying => {false}
+        Locomotion::Walking => {
+            !is_supported_position(pos, level, color) && !is_occupied(pos + IVec2::new(0, 1), mob_grid)
+        }
+    }
+}
+
+// Rooms a mob at pos can move to in one step, ignoring other mobs.  Positions have the level's
+// boundary conditions applied so the same room always has the same coordinates.
+pub fn neighbors(pos: IVec2, locomotion: Locomotion, color: MagicColor, level: &Level, mob_grid: &Grid2D<MobId>) -> Vec<IVec2> {
+    let left = pos + IVec2::new(-1, 0);
+    let right = pos + IVec2::new(1, 0);
+    let down = pos + IVec2::new(0, 1);
+    let up = pos + IVec2::new(0, -1);
+
+    let mut rooms: Vec<IVec2> = Vec::new();
+    match locomotion {
+        Locomotion::Flying => {
+            for v in [left, right, down, up] {
+                if !is_wall(v, level, color) {
+                    rooms.push(v);
+                }
+            }
+        }
+        Locomotion::Walking => {
+            if is_falling(pos, locomotion, color, level, mob_grid) {
+                if !is_wall(down, level, color) {
+                    rooms.push(down);
+                }
+            } else {
+                for v in [left, right] {
+                    if !is_wall(v, level, color) {
+                        rooms.push(v);
+                    }
+                }
+                if !is_wall(down, level, color) && (can_climb_down(pos, level, color) || has_floor(pos, level, color).is_none()) {
+                    rooms.push(down);
+                }
+                if can_climb_up(pos, level, color) {
+                    rooms.push(up);
+                }
+            }
+            // Walking mobs know better than to step into a hazard
+            rooms.retain(|v| !is_hazard(*v, level));
+        }
+    }
+
+    let world_size = level.grid.get_size();
+    let topology = level.grid.get_topology();
+    rooms.iter()
+        .map(|v| apply_boundary_conditions_i32(*v, world_size, topology))
+        .filter(|v| *v != pos) // Mirrored edges fold back onto the same room
+        .collect()
+}
+
+// Breadth first search over free rooms, stops early once the target is found and goes no further
+// than max_steps rooms from start if given.  The target may be occupied.  Returns the rooms in the
+// order they were reached and the room each was reached from, only rooms the search got to are
+// kept so a short search stays cheap on a big level.
+fn search(start: IVec2, target: Option<IVec2>, max_steps: Option<usize>, locomotion: Locomotion, color: MagicColor, level: &Level, mob_grid: &Grid2D<MobId>) -> (Vec<IVec2>, HashMap<IVec2, IVec2>) {
+    let start = apply_boundary_conditions_i32(start, level.grid.get_size(), level.grid.get_topology());
+
+    let mut came_from: HashMap<IVec2, IVec2> = HashMap::new();
+    came_from.insert(start, start);
+
+    let mut order: Vec<IVec2> = vec![start];
+    let mut open: VecDeque<(IVec2, usize)> = VecDeque::new();
+    open.push_back((start, 0));
+    while let Some((pos, steps)) = open.pop_front() {
+        if max_steps.is_some_and(|x| steps >= x) {
+            continue;
+        }
+        for v in neighbors(pos, locomotion, color, level, mob_grid) {
+            if came_from.contains_key(&v) {
+                continue;
+            }
+            let is_target = target == Some(v);
+            if is_occupied(v, mob_grid) && !is_target {
+                continue;
+            }
+            came_from.insert(v, pos);
+            order.push(v);
+            if is_target {
+                return (order, came_from);
+            }
+            open.push_back((v, steps + 1));
+        }
+    }
+    (order, came_from)
+}
+
+fn walk_back(start: IVec2, end: IVec2, came_from: &HashMap<IVec2, IVec2>) -> Vec<IVec2> {
+    let mut path: Vec<IVec2> = Vec::new();
+    let mut pos = end;
+    while pos != start {
+        path.push(pos);
+        match came_from.get(&pos) {
+            Some(x) => {pos = *x;}
+            None => {break;}
+        }
+    }
+    path.reverse();
+    path
+}
+
+// Shortest path from start to target, without start and ending with target.  Empty if they're
+// the same room, None if the target can't be reached.
+pub fn find_path(start: IVec2, target: IVec2, locomotion: Locomotion, color: MagicColor, level: &Level, mob_grid: &Grid2D<MobId>) -> Option<Vec<IVec2>> {
+    find_path_within(start, target, None, locomotion, color, level, mob_grid)
+}
+
+// Same as find_path, but gives up on targets more than max_steps rooms away
+pub fn find_path_within(start: IVec2, target: IVec2, max_steps: Option<usize>, locomotion: Locomotion, color: MagicColor, level: &Level, mob_grid: &Grid2D<MobId>) -> Option<Vec<IVec2>> {
+    let world_size = level.grid.get_size();
+    let topology = level.grid.get_topology();
+    let start = apply_boundary_conditions_i32(start, world_size, topology);
+    let target = apply_boundary_conditions_i32(target, world_size, topology);
+    let (_, came_from) = search(start, Some(target), max_steps, locomotion, color, level, mob_grid);
+    if came_from.contains_key(&target) {
+        Some(walk_back(start, target, &came_from))
+    } else {
+        None
+    }
+}
+
+// Steps from every open room to pos, as a mob of that color that flies would count them
+fn distance_field(pos: IVec2, color: MagicColor, level: &Level) -> Grid2D<i32> {
+    let (width, height) = level.grid.get_size();
+    let topology = level.grid.get_topology();
+    let mut distance: Grid2D<i32> = Grid2D::new(width, height);
+    distance.set_topology(topology);
+    distance.zero();
+
+    let pos = apply_boundary_conditions_i32(pos, (width, height), topology);
+    let mut seen: Grid2D<bool> = Grid2D::new(width, height);
+    seen.set_topology(topology);
+    seen.zero();
+    seen.set_cell_at_grid_coords_int(pos, true);
+
+    // Mobs don't stop the threat from coming, only walls do
+    let mut no_mobs: Grid2D<MobId> = Grid2D::new(width, height);
+    no_mobs.set_topology(topology);
+    no_mobs.zero();
+
+    let mut open: VecDeque<IVec2> = VecDeque::new();
+    open.push_back(pos);
+    while let Some(p) = open.pop_front() {
+        let d = *distance.get_cell_at_grid_coords_int(p).unwrap();
+        for v in neighbors(p, Locomotion::Flying, color, level, &no_mobs) {
+            if seen.get_cell_at_grid_coords_int(v) == Some(&false) {
+                seen.set_cell_at_grid_coords_int(v, true);
+                distance.set_cell_at_grid_coords_int(v, d + 1);
+                open.push_back(v);
+            }
+        }
+    }
+
+    // Rooms the threat can't get to at all are the safest
+    for y in 0..height as i32 {
+        for x in 0..width as i32 {
+            let v = IVec2::new(x, y);
+            if seen.get_cell_at_grid_coords_int(v) == Some(&false) {
+                distance.set_cell_at_grid_coords_int(v, i32::MAX);
+            }
+        }
+    }
+    distance
+}
+
+// Room to move to next for the goal, None to stay put
+pub fn next_step(start: IVec2, goal: PathGoal, locomotion: Locomotion, color: MagicColor, level: &Level, mob_grid: &Grid2D<MobId>) -> Option<IVec2> {
+    match goal {
+        PathGoal::Pursue(target) => {
+            let path = find_path(start, target, locomotion, color, level, mob_grid)?;
+            if path.len() > 1 {
+                Some(path[0])
+            } else {
+                None // Already next to it
+            }
+        }
+        PathGoal::Reach(target) => {
+            let path = find_path(start, target, locomotion, color, level, mob_grid)?;
+            path.first().copied()
+        }
+        PathGoal::Flee(threat) => {
+            let distance = distance_field(threat, color, level);
+            let (order, came_from) = search(start, None, None, locomotion, color, level, mob_grid);
+            // Rooms are in the order reached, so ties go to the closest one
+            let mut best = order[0];
+            let mut best_distance = *distance.get_cell_at_grid_coords_int(best).unwrap_or(&0);
+            for v in order.iter() {
+                let d = *distance.get_cell_at_grid_coords_int(*v).unwrap_or(&0);
+                if d > best_distance {
+                    best = *v;
+                    best_distance = d;
+                }
+            }
+            walk_back(order[0], best, &came_from).first().copied()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::grid2d::Topology;
+
+    fn empty_mobs(level: &Level) -> Grid2D<MobId> {
+        let (width, height) = level.grid.get_size();
+        let mut mob_grid: Grid2D<MobId> = Grid2D::new(width, height);
+        mob_grid.set_topology(level.grid.get_topology());
+        mob_grid.zero();
+        mob_grid
+    }
+
+    #[test]
+    fn test_walkers_climb_and_flyers_fly() {
+        let level = Level::from_rows(&[
+            "#######",
+            "#.....#",
+            "#..##H#",
+            "#.....#",
+            "#######",
+        ]).unwrap();
+        let mob_grid = empty_mobs(&level);
+        let start = IVec2::new(1, 3);
+
+        // A flyer goes straight up the open shaft
+        let path = find_path(start, IVec2::new(1, 1), Locomotion::Flying, MagicColor::White, &level, &mob_grid).unwrap();
+        assert_eq!(path.len(), 2);
+
+        // A walker can't brace itself in a shaft two rooms wide, so it takes the ladder
+        let top = IVec2::new(3, 1);
+        let path = find_path(start, top, Locomotion::Walking, MagicColor::White, &level, &mob_grid).unwrap();
+        assert!(path.contains(&IVec2::new(5, 2)));
+        assert_eq!(path.last(), Some(&top));
+        assert!(find_path(start, IVec2::new(1, 1), Locomotion::Walking, MagicColor::White, &level, &mob_grid).is_none());
+    }
+
+    #[test]
+    fn test_find_path_within() {
+        let level = Level::from_rows(&[
+            "#######",
+            "#.....#",
+            "#######",
+        ]).unwrap();
+        let mob_grid = empty_mobs(&level);
+        let (start, end) = (IVec2::new(1, 1), IVec2::new(5, 1));
+        let path = find_path_within(start, end, Some(4), Locomotion::Flying, MagicColor::White, &level, &mob_grid).unwrap();
+        assert_eq!(path.len(), 4);
+        assert!(find_path_within(start, end, Some(3), Locomotion::Flying, MagicColor::White, &level, &mob_grid).is_none());
+        assert_eq!(find_path_within(start, start, Some(0), Locomotion::Flying, MagicColor::White, &level, &mob_grid), Some(vec![]));
+    }
+
+    #[test]
+    fn test_walkers_fall() {
+        let level = Level::from_rows(&[
+            "#####",
+            "#...#",
+            "#..##",
+            "#..##",
+            "#####",
+        ]).unwrap();
+        let mob_grid = empty_mobs(&level);
+        let start = IVec2::new(3, 1);
+
+        // Walking off the ledge is a one way trip
+        let path = find_path(start, IVec2::new(1, 3), Locomotion::Walking, MagicColor::White, &level, &mob_grid).unwrap();
+        assert_eq!(path, vec![IVec2::new(2, 1), IVec2::new(2, 2), IVec2::new(2, 3), IVec2::new(1, 3)]);
+        assert!(find_path(IVec2::new(1, 3), start, Locomotion::Walking, MagicColor::White, &level, &mob_grid).is_none());
+        assert!(is_falling(IVec2::new(2, 2), Locomotion::Walking, MagicColor::White, &level, &mob_grid));
+        assert!(!is_falling(IVec2::new(2, 2), Locomotion::Flying, MagicColor::White, &level, &mob_grid));
+
+        // Hazards are never part of a walker's path
+        let level = Level::from_rows(&[
+            "#####",
+            "#...#",
+            "#.^.#",
+            "#####",
+        ]).unwrap();
+        let mob_grid = empty_mobs(&level);
+        assert!(find_path(IVec2::new(1, 1), IVec2::new(2, 2), Locomotion::Walking, MagicColor::White, &level, &mob_grid).is_none());
+    }
+
+    #[test]
+    fn test_path_wraps() {
+        let mut level = Level::from_rows(&[
+            "########",
+            "..#.....",
+            "########",
+        ]).unwrap();
+        level.grid.set_topology(Topology::WrapX);
+        let mob_grid = empty_mobs(&level);
+
+        // The wall is in the way, so the only way round is across the edge
+        let path = find_path(IVec2::new(1, 1), IVec2::new(6, 1), Locomotion::Walking, MagicColor::White, &level, &mob_grid).unwrap();
+        assert_eq!(path, vec![IVec2::new(0, 1), IVec2::new(7, 1), IVec2::new(6, 1)]);
+    }
+
+    #[test]
+    fn test_goals() {
+        let level = Level::from_rows(&[
+            "#########",
+            "#.......#",
+            "#########",
+        ]).unwrap();
+        let mut mob_grid = empty_mobs(&level);
+        let player = IVec2::new(4, 1);
+        mob_grid.set_cell_at_grid_coords_int(player, MobId::Player);
+
+        let pursue = PathGoal::Pursue(player);
+        assert_eq!(next_step(IVec2::new(1, 1), pursue, Locomotion::Walking, MagicColor::White, &level, &mob_grid), Some(IVec2::new(2, 1)));
+        assert_eq!(next_step(IVec2::new(3, 1), pursue, Locomotion::Walking, MagicColor::White, &level, &mob_grid), None);
+
+        let flee = PathGoal::Flee(player);
+        assert_eq!(next_step(IVec2::new(5, 1), flee, Locomotion::Walking, MagicColor::White, &level, &mob_grid), Some(IVec2::new(6, 1)));
+        assert_eq!(next_step(IVec2::new(7, 1), flee, Locomotion::Walking, MagicColor::White, &level, &mob_grid), None);
+
+        let mut route = PatrolRoute::new(vec![IVec2::new(1, 1), IVec2::new(7, 1)]);
+        assert_eq!(route.target(IVec2::new(2, 1)), Some(IVec2::new(1, 1)));
+        assert_eq!(route.target(IVec2::new(1, 1)), Some(IVec2::new(7, 1)));
+        // The player is in the way
+        assert_eq!(next_step(IVec2::new(1, 1), PathGoal::Reach(IVec2::new(7, 1)), Locomotion::Walking, MagicColor::White, &level, &mob_grid), None);
+    }
+}
@@ -23,6 +23,6 @@ mod tests {
         assert_eq!(wrap_double_norm(0.0), 0.0);
         assert_eq!(wrap_double_norm(1.0), 0.0);
         assert_eq!(wrap_double_norm(6.5), 0.5);
-        assert_eq!(wrap_double_norm(-6.4), 0.6);
+        assert!((wrap_double_norm(-6.4) - 0.6).abs() < 1e-12);
     }
 }
@@ -1,7 +1,7 @@
 use macroquad::math::{DVec2, IVec2};
 use crate::grid2d::{Grid2D, WallGridCell};
 use crate::level::Level;
-use crate::mob::MobId;
+use crate::mob::{MagicColor, MobId};
 
 pub struct PlayerPosition {
     pos: IVec2
@@ -58,7 +58,17 @@ impl PlayerPosition {
     }
 }
 
-pub fn has_floor(pos: IVec2, level: &Level ) -> Option<IVec2> {
+// Phased cells are walls for the color they are solid for, and open to the other one
+pub fn is_solid(cell: WallGridCell, color: MagicColor) -> bool {
+    match cell {
+        WallGridCell::Wall => {true}
+        WallGridCell::WhitePhase => {color == MagicColor::White}
+        WallGridCell::BlackPhase => {color == MagicColor::Black}
+        _ => {false}
+    }
+}
+
+pub fn has_floor(pos: IVec2, level: &Level, color: MagicColor) -> Option<IVec2> {
     let down_pos = pos + IVec2::from((0, 1));
     let cell = level.grid.get_cell_at_grid_coords_int(down_pos);
     match cell {
@@ -67,12 +77,23 @@ pub fn has_floor(pos: IVec2, level: &Level ) -> Option<IVec2> {
             match x {
                 WallGridCell::Empty => {None}
                 WallGridCell::Wall => {Some(down_pos)}
+                WallGridCell::Ladder => {Some(down_pos)} // Can stand on top of a ladder
+                WallGridCell::Water => {None}
+                WallGridCell::Hazard => {None}
+                WallGridCell::Ledge => {Some(down_pos)}
+                WallGridCell::WhitePhase | WallGridCell::BlackPhase => {
+                    if is_solid(*x, color) {
+                        Some(down_pos)
+                    } else {
+                        None
+                    }
+                }
             }
         }
     }
 }
 
-pub fn has_ceiling(pos: IVec2, level: &Level ) -> bool {
+pub fn has_ceiling(pos: IVec2, level: &Level, color: MagicColor) -> bool {
     let up_pos = pos + IVec2::from((0, -1));
     let cell = level.grid.get_cell_at_grid_coords_int(up_pos);
     match cell {
@@ -81,65 +102,107 @@ pub fn has_ceiling(pos: IVec2, level: &Level ) -> bool {
             match x {
                 WallGridCell::Empty => {false}
                 WallGridCell::Wall => {true}
+                WallGridCell::Ladder => {false}
+                WallGridCell::Water => {false}
+                WallGridCell::Hazard => {false}
+                WallGridCell::Ledge => {false} // One way, can pass through from below
+                WallGridCell::WhitePhase | WallGridCell::BlackPhase => {is_solid(*x, color)}
             }
         }
     }
 }
 
-pub fn can_stem(pos: IVec2, level: &Level) -> bool {
+// Can the player hold on inside this cell without any walls around?
+pub fn is_climbable(pos: IVec2, level: &Level) -> bool {
+    if let Some(x) = level.grid.get_cell_at_grid_coords_int(pos) {
+        match x {
+            WallGridCell::Empty => {false}
+            WallGridCell::Wall => {false}
+            WallGridCell::Ladder => {true}
+            WallGridCell::Water => {true}
+            WallGridCell::Hazard => {false}
+            WallGridCell::Ledge => {false}
+            WallGridCell::WhitePhase => {false}
+            WallGridCell::BlackPhase => {false}
+        }
+    } else {
+        false
+    }
+}
+
+pub fn is_hazard(pos: IVec2, level: &Level) -> bool {
+    level.grid.get_cell_at_grid_coords_int(pos) == Some(&WallGridCell::Hazard)
+}
+
+pub fn can_stem(pos: IVec2, level: &Level, color: MagicColor) -> bool {
     let left_pos = pos + IVec2::from((-1, 0));
     let right_pos = pos + IVec2::from((1, 0));
-    let left_cell = *level.grid.get_cell_at_grid_coords_int(left_pos).unwrap();
-    let right_cell = *level.grid.get_cell_at_grid_coords_int(right_pos).unwrap();
+    // Outside a clamped edge counts as solid, same as is_wall
+    let left_cell = *level.grid.get_cell_at_grid_coords_int(left_pos).unwrap_or(&WallGridCell::Wall);
+    let right_cell = *level.grid.get_cell_at_grid_coords_int(right_pos).unwrap_or(&WallGridCell::Wall);
 
-    if left_cell == WallGridCell::Wall && right_cell == WallGridCell::Wall {
+    if is_solid(left_cell, color) && is_solid(right_cell, color) {
         true
     } else {
         false
     }
 }
 
-pub fn can_straddle_drop(pos: IVec2, level: &Level) -> Option<bool> {
-    if has_floor(pos, level).is_some() { // Not a pit
+pub fn can_straddle_drop(pos: IVec2, level: &Level, color: MagicColor) -> Option<bool> {
+    if has_floor(pos, level, color).is_some() { // Not a pit
         return None;
     }
 
     let down_pos = pos + IVec2::from((0, 1));
-    Some(can_stem(down_pos, level))
+    Some(can_stem(down_pos, level, color))
 }
 
-pub fn can_climb_up(pos: IVec2, level: &Level) -> bool {
-    if has_ceiling(pos, level) {
+pub fn can_climb_up(pos: IVec2, level: &Level, color: MagicColor) -> bool {
+    if has_ceiling(pos, level, color) {
         return false;
     }
 
     let up_pos = pos + IVec2::from((0, -1));
-    can_stem(up_pos, level) || is_supported_position(up_pos, level)
+    can_stem(up_pos, level, color) || is_supported_position(up_pos, level, color)
 }
 
-pub fn can_climb_down(pos: IVec2, level: &Level) -> bool {
-    if has_floor(pos, level).is_some() {
+pub fn can_climb_down(pos: IVec2, level: &Level, color: MagicColor) -> bool {
+    let down = pos + IVec2::from((0, 1));
+    if is_climbable(down, level) {
+        return true;
+    }
+
+    if has_floor(pos, level, color).is_some() {
         return false;
     }
 
-    let down = pos + IVec2::from((0, 1));
-    can_stem(down, level)
+    can_stem(down, level, color)
 }
 
 // Is the position supported, or is a fall guaranteed?
-pub fn is_supported_position(pos: IVec2, level: &Level) -> bool {
-    if let Some(x) = can_straddle_drop(pos, level) {
-        return x || can_stem(pos, level);
+pub fn is_supported_position(pos: IVec2, level: &Level, color: MagicColor) -> bool {
+    // Hanging on to a ledge from below works, dropping into one from above is blocked by has_floor
+    if is_climbable(pos, level) || level.grid.get_cell_at_grid_coords_int(pos) == Some(&WallGridCell::Ledge) {
+        return true;
+    }
+
+    if let Some(x) = can_straddle_drop(pos, level, color) {
+        return x || can_stem(pos, level, color);
     }
 
     true // Not a drop
 }
 
-pub fn is_wall(pos: IVec2, level: &Level) -> bool {
+pub fn is_wall(pos: IVec2, level: &Level, color: MagicColor) -> bool {
     if let Some(x) = level.grid.get_cell_at_grid_coords_int(pos) {
         match x {
             WallGridCell::Empty => {false}
             WallGridCell::Wall => {true}
+            WallGridCell::Ladder => {false}
+            WallGridCell::Water => {false}
+            WallGridCell::Hazard => {false}
+            WallGridCell::Ledge => {false}
+            WallGridCell::WhitePhase | WallGridCell::BlackPhase => {is_solid(*x, color)}
         }
     } else {
         true
@@ -166,12 +229,12 @@ pub fn is_room_occupiable(pos: IVec2, mob_grid: &Grid2D<MobId>) -> bool {
     }
 }
 
-pub fn try_move(pos: IVec2, dir: MoveDirection, facing: i32, level: &Level, mob_grid: &Grid2D<MobId>) -> Option<IVec2> {
+pub fn try_move(pos: IVec2, dir: MoveDirection, facing: i32, color: MagicColor, level: &Level, mob_grid: &Grid2D<MobId>) -> Option<IVec2> {
     assert_eq!(facing.abs(), 1);
     match dir {
         MoveDirection::WalkForward => {
             let new_pos = pos - IVec2::from((facing, 0));
-            if !is_wall(new_pos, level) && is_room_occupiable(new_pos, mob_grid) {
+            if !is_wall(new_pos, level, color) && is_room_occupiable(new_pos, mob_grid) {
                 Some(new_pos)
             } else {
                 None
@@ -179,14 +242,14 @@ pub fn try_move(pos: IVec2, dir: MoveDirection, facing: i32, level: &Level, mob_
         }
         MoveDirection::WalkBackward => {
             let new_pos = pos + IVec2::from((facing, 0));
-            if !is_wall(new_pos, level) && is_room_occupiable(new_pos, mob_grid) {
+            if !is_wall(new_pos, level, color) && is_room_occupiable(new_pos, mob_grid) {
                 Some(new_pos)
             } else {
                 None
             }
         }
         MoveDirection::ClimbUp => {
-            if can_climb_up(pos, level) {
+            if can_climb_up(pos, level, color) {
                 let new_pos = pos + IVec2::from((0, -1));
                 if is_room_occupiable(new_pos, mob_grid) {
                     Some(new_pos)
@@ -198,7 +261,7 @@ pub fn try_move(pos: IVec2, dir: MoveDirection, facing: i32, level: &Level, mob_
             }
         }
         MoveDirection::ClimbDown => {
-            if can_climb_down(pos, level) || !has_floor(pos, level).is_some() {
+            if can_climb_down(pos, level, color) || !has_floor(pos, level, color).is_some() {
                 let new_pos = pos + IVec2::from((0, 1));
                 if is_room_occupiable(new_pos, mob_grid) {
                     Some(new_pos)
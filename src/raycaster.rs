@@ -65,7 +65,11 @@ pub fn cast_ray<T>(grid: &Grid2D<T>, start: &DVec2, ray_dir: &DVec2, max_ray_dis
 
         let cell = grid.get_cell_at_grid_coords_int(IVec2{x: map_x, y:map_y});
         match cell {
-            None => {hit = true;}
+            // Outside a clamped edge, which is as solid as a wall
+            None => {
+                hit = true;
+                cell_hit_type = WallGridCell::Wall;
+            }
             Some(x) => {
                 match x.clone().into() {
                     WallGridCell::Empty => {}
@@ -73,14 +77,33 @@ pub fn cast_ray<T>(grid: &Grid2D<T>, start: &DVec2, ray_dir: &DVec2, max_ray_dis
                         hit = true;
                         cell_hit_type = x.clone().into();
                     }
+                    // See-through cells, rays continue
+                    WallGridCell::Ladder => {}
+                    WallGridCell::Water => {}
+                    WallGridCell::Hazard => {}
+                    WallGridCell::Ledge => {}
+                    WallGridCell::WhitePhase => {} // Phased cells are drawn translucent
+                    WallGridCell::BlackPhase => {}
                 }
             }
         }
 
-        let current_position = side_dist_x.min(side_dist_y) * *ray_dir;
-        if current_position.length() >= max_ray_distance {
-            cell_hit_type = WallGridCell::Empty;
-            break;
+        // A wall counts once the ray enters its cell within range, other cells end the search once
+        // the ray leaves them past the range, since the next cell would be entered out of range.
+        if hit {
+            let entered_at = if side == HitSide::Vertical {
+                side_dist_x - delta_dist_x
+            } else {
+                side_dist_y - delta_dist_y
+            };
+            if (entered_at * *ray_dir).length() > max_ray_distance {
+                cell_hit_type = WallGridCell::Empty;
+            }
+        } else {
+            let current_position = side_dist_x.min(side_dist_y) * *ray_dir;
+            if current_position.length() >= max_ray_distance {
+                break;
+            }
         }
     }
 
@@ -91,4 +114,38 @@ pub fn cast_ray<T>(grid: &Grid2D<T>, start: &DVec2, ray_dir: &DVec2, max_ray_dis
     };
 
     (perp_wall_distance, cell_hit_type, side, IVec2::from((map_x, map_y)))
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::grid2d::Topology;
+
+    #[test]
+    fn test_clamped_edge_is_wall() {
+        let mut grid: Grid2D<WallGridCell> = Grid2D::new(4, 4);
+        grid.zero();
+        grid.set_topology(Topology::Clamp);
+        let start = DVec2::new(1.5, 2.5);
+
+        let (distance, cell, side, coord) = cast_ray(&grid, &start, &DVec2::new(-1.0, 0.0), 10.0);
+        assert_eq!(cell, WallGridCell::Wall);
+        assert!(side == HitSide::Vertical);
+        assert_eq!(coord, IVec2::new(-1, 2));
+        assert!((distance - 1.5).abs() < 1e-9);
+
+        let (distance, cell, _, coord) = cast_ray(&grid, &start, &DVec2::new(0.0, -1.0), 10.0);
+        assert_eq!(cell, WallGridCell::Wall);
+        assert_eq!(coord, IVec2::new(1, -1));
+        assert!((distance - 2.5).abs() < 1e-9);
+
+        // Out of range, the edge isn't reached
+        let (_, cell, _, _) = cast_ray(&grid, &start, &DVec2::new(0.0, -1.0), 2.0);
+        assert_eq!(cell, WallGridCell::Empty);
+
+        // Wrapping edges carry on across
+        grid.set_topology(Topology::Torus);
+        let (_, cell, _, _) = cast_ray(&grid, &start, &DVec2::new(-1.0, 0.0), 10.0);
+        assert_eq!(cell, WallGridCell::Empty);
+    }
+}
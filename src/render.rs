@@ -0,0 +1,304 @@
+use std::f64::consts::PI;
+
+use macroquad::math::{DVec2, IVec2};
+
+use crate::fpv::{FirstPersonViewer, SpriteId, WallTextureBinding, WallTextureBindings};
+use crate::grid2d::WallGridCell;
+use crate::image::ImageLoader;
+use crate::level::{ucoords_to_dvec2, world_space_centered_coord, Level};
+use crate::simulation::calculate_view_dir;
+use crate::sprites::Sprites;
+
+// Frame drawing for the game views.  These only touch the CPU buffers of the viewers, showing
+// the result on screen is up to the caller.
+
+pub fn draw_first_person_frame(
+    look_rotation: f64,
+    player_facing: f64,
+    max_ray_distance: f64,
+    world: &Level,
+    pos: DVec2,
+    plane_scale: f64,
+    sprite_images: &ImageLoader,
+    hide_floors_and_ceiling: bool,
+    hide_walls: bool,
+    line_width_scale: f64,
+    first_person_view: &mut FirstPersonViewer,
+    sprite_manager: &mut Sprites,
+) {
+    let world_size = world.grid.get_size();
+
+    // Draw frame
+    let view_dir = calculate_view_dir(look_rotation, player_facing);
+    let wall_bindings = WallTextureBindings {
+        left: WallTextureBinding {
+            repeat_speed: 16.0,
+            pin: true,
+        },
+        right: WallTextureBinding {
+            repeat_speed: 16.0,
+            pin: true,
+        },
+    };
+    first_person_view.draw_view(
+        max_ray_distance,
+        &world,
+        pos,
+        view_dir,
+        plane_scale,
+        &wall_bindings,
+        &sprite_images,
+        hide_floors_and_ceiling,
+        hide_walls,
+        line_width_scale,
+    );
+    sprite_manager.draw_sprites(
+        max_ray_distance,
+        &sprite_images,
+        first_person_view,
+        pos,
+        view_dir,
+        player_facing * plane_scale,
+        world_size,
+        world.grid.get_topology(),
+    );
+}
+
+// Side on view used while turning around, with the first person view folded into it
+pub fn draw_horizontal_frame(
+    look_rotation: f64,
+    turn_rotation: f64,
+    player_facing: f64,
+    player_cell: IVec2,
+    pos: DVec2,
+    max_ray_distance: f64,
+    world: &Level,
+    plane_scale: f64,
+    horizontal_line_scale: f64,
+    sprite_images: &ImageLoader,
+    first_person_view: &mut FirstPersonViewer,
+    first_person_view_horizontal: &mut FirstPersonViewer,
+    sprite_manager: &mut Sprites,
+) {
+    let world_size = ucoords_to_dvec2(world.grid.get_size());
+    let h_world_size = 2 * (max_ray_distance as i32) + 1;
+    let mut h_world = Level::new(None, h_world_size as usize, 1);
+    let materials = world.resolve_materials(sprite_images);
+    let mut h_world_floor: Vec<Option<SpriteId>> =
+        Vec::with_capacity(h_world_size as usize);
+    let mut h_world_ceiling: Vec<Option<SpriteId>> =
+        Vec::with_capacity(h_world_size as usize);
+    h_world_floor.resize_with(h_world_size as usize, Default::default);
+    h_world_ceiling.resize_with(h_world_size as usize, Default::default);
+    h_world.grid.zero();
+    let h_pos = world_space_centered_coord((max_ray_distance as i32, 0), 0.0, 0.0);
+
+    let wall_bindings = WallTextureBindings {
+        left: WallTextureBinding {
+            repeat_speed: 16.0,
+            pin: true,
+        },
+        right: WallTextureBinding {
+            repeat_speed: 16.0,
+            pin: true,
+        },
+    };
+
+    // Calculate view
+    let base_angle: f64 = if player_facing < 0.0 { PI } else { 0.0 };
+    let view_dir =
+        calculate_view_dir(base_angle + turn_rotation, 1.0);
+    let h_facing = view_dir.x.signum() as i32;
+
+    // Populate floor array
+    let current_pos = player_cell;
+    let current_pos_world =
+        world_space_centered_coord(<(i32, i32)>::from(current_pos), 0.0, 0.0);
+    for i in 0..h_world_size / 2 {
+        let x = current_pos.x + h_facing * i;
+        let floor_pos = IVec2::from((x, current_pos.y + 1));
+        let floor_cell = world.grid.get_cell_at_grid_coords_int(floor_pos);
+        let floor_cell: Option<SpriteId> = match floor_cell {
+            Some(x) => match x {
+                WallGridCell::Wall | WallGridCell::Ledge => {
+                    Some(world.get_material(floor_pos, &materials).floor)
+                }
+                _ => None,
+            },
+            None => None,
+        };
+        h_world_floor[(h_pos.x as i32 + (h_facing * i)) as usize] = floor_cell;
+
+        // Ceiling
+        let ceiling_pos = IVec2::from((x, current_pos.y - 1));
+        let ceiling_cell = world.grid.get_cell_at_grid_coords_int(ceiling_pos);
+        let ceiling_cell: Option<SpriteId> = match ceiling_cell {
+            Some(x) => match x {
+                WallGridCell::Wall => Some(world.get_material(ceiling_pos, &materials).ceiling),
+                _ => None,
+            },
+            None => None,
+        };
+        h_world_ceiling[(h_pos.x as i32 + (h_facing * i)) as usize] = ceiling_cell;
+    }
+
+    // Draw floors, ceiling, and walls
+    first_person_view_horizontal.reset_image_buffer([0, 0, 0, 255]);
+    first_person_view_horizontal.draw_view_horizontal(
+        max_ray_distance,
+        &h_world,
+        h_pos,
+        view_dir,
+        0.5 / horizontal_line_scale,
+        None,
+        &h_world_floor,
+        &h_world_ceiling,
+        sprite_images,
+        false,
+        (0.5) / plane_scale.abs(),
+        &wall_bindings,
+        world.get_material(current_pos, &materials).left,
+        current_pos_world.y,
+        world_size.as_vec2(),
+    );
+
+    // Re-render old view
+    first_person_view.reset_image_buffer([0, 0, 0, 0]);
+    let vertical_look_dir = if view_dir.x < 0.0 { 1.0 } else { -1.0 };
+    draw_first_person_frame(
+        look_rotation,
+        vertical_look_dir,
+        max_ray_distance,
+        world,
+        pos,
+        plane_scale,
+        sprite_images,
+        true,
+        true,
+        0.5,
+        first_person_view,
+        sprite_manager,
+    );
+
+    // draw vertical scene render
+    h_world.grid.set_cell_at_grid_coords_int(
+        IVec2::new(h_pos.x as i32 + 1, 0),
+        WallGridCell::Wall,
+    );
+    h_world.grid.set_cell_at_grid_coords_int(
+        IVec2::new(h_pos.x as i32 - 1, 0),
+        WallGridCell::Wall,
+    );
+    first_person_view_horizontal.draw_view_horizontal(
+        max_ray_distance,
+        &h_world,
+        h_pos,
+        view_dir,
+        0.5 / horizontal_line_scale, // If this is less than 1, we need to scale the line width of the output render by the same ampunt
+        Some(&*first_person_view),
+        &h_world_floor,
+        &h_world_ceiling,
+        sprite_images,
+        true,
+        0.5,
+        &wall_bindings,
+        world.get_material(current_pos, &materials).left,
+        current_pos_world.y,
+        world_size.as_vec2(),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assets::AssetManifest;
+    use crate::image::load_png;
+    use crate::mob::MagicColor;
+
+    const GOLDEN_DIR: &str = "golden";
+
+    // Small frames keep the reference images and the test quick
+    const FRAME_SIZE: (u16, u16) = (160, 120);
+
+    // A copy of the top of the game's level, kept apart so editing level.json doesn't move the goldens
+    fn load_game() -> (Level, ImageLoader) {
+        let mut world = Level::new(None, 1, 1);
+        world.load_from_file(&format!("{}/level.json", GOLDEN_DIR)).unwrap();
+        let mut images = ImageLoader::new();
+        images.load_manifest_from_disk(&AssetManifest::load_from_file("assets.json").unwrap());
+        (world, images)
+    }
+
+    // The sprites the game always shows, the win room and the level's flavor sprites
+    fn level_sprites(world: &Level, images: &ImageLoader) -> Sprites {
+        let mut sprites = Sprites::new();
+        let ship = images.get_id_or_placeholder("space_ship");
+        sprites.add_sprite(
+            world_space_centered_coord((world.win_room.0 as i32, world.win_room.1 as i32), 0.0, 0.1),
+            (ship, MagicColor::Black),
+            images.get_scale(ship),
+        );
+        if let Some(flavor_sprites) = &world.flavor_sprites {
+            for (x, y, name) in flavor_sprites {
+                let sid = images.get_id_or_placeholder(name);
+                sprites.add_sprite(DVec2::new(*x, *y), (sid, MagicColor::White), images.get_scale(sid));
+            }
+        }
+        sprites
+    }
+
+    // Compares a frame with its reference image in the golden folder.  After an intended change
+    // to the renderer, run the tests with UPDATE_GOLDEN=1 to write new reference images.
+    fn check_golden(name: &str, view: &FirstPersonViewer) {
+        let path = format!("{}/{}.png", GOLDEN_DIR, name);
+        if std::env::var("UPDATE_GOLDEN").is_ok() {
+            view.save_png(&path).unwrap();
+            return;
+        }
+
+        let golden = match load_png(&path) {
+            Ok(x) => x,
+            Err(e) => panic!("{} (run with UPDATE_GOLDEN=1 to create it)", e),
+        };
+        let frame = &view.render_image;
+        assert_eq!((golden.width, golden.height), (frame.width, frame.height), "{} changed size", path);
+
+        // Allow a few pixels to land differently with another platform's floating point math
+        let differing = golden.get_image_data().iter().zip(frame.get_image_data().iter())
+            .filter(|(a, b)| a.iter().zip(b.iter()).any(|(x, y)| x.abs_diff(*y) > 2))
+            .count();
+        if differing * 1000 > frame.get_image_data().len() {
+            let actual_path = format!("{}/{}.actual.png", GOLDEN_DIR, name);
+            let _ = view.save_png(&actual_path);
+            panic!("{} pixels differ from {}, the new frame is in {}", differing, path, actual_path);
+        }
+    }
+
+    #[test]
+    fn test_golden_frames() {
+        let (world, images) = load_game();
+        let start = world_space_centered_coord((world.player_start.0 as i32, world.player_start.1 as i32), 0.0, 0.0);
+
+        // (name, position, facing, look rotation)
+        let cameras = [
+            ("start", start, 1.0, 0.0),
+            ("start_back_look_up", start, -1.0, 0.6),
+            ("cave_look_down", world_space_centered_coord((6, 12), 0.0, 0.0), 1.0, -0.6),
+        ];
+        for (name, pos, facing, look) in cameras {
+            let mut view = FirstPersonViewer::new(FRAME_SIZE.0, FRAME_SIZE.1);
+            let mut sprites = level_sprites(&world, &images);
+            draw_first_person_frame(look, facing, 16.0, &world, pos, -1.15, &images, false, false, 0.5, &mut view, &mut sprites);
+            check_golden(name, &view);
+        }
+
+        // Part way through turning around
+        let mut view = FirstPersonViewer::new(FRAME_SIZE.0, FRAME_SIZE.1);
+        let mut view_horizontal = FirstPersonViewer::new(FRAME_SIZE.0, FRAME_SIZE.1);
+        let mut sprites = level_sprites(&world, &images);
+        let cell = IVec2::new(world.player_start.0 as i32, world.player_start.1 as i32);
+        draw_horizontal_frame(0.0, PI / 3.0, 1.0, cell, start, 16.0, &world, -1.15, 0.5, &images,
+                              &mut view, &mut view_horizontal, &mut sprites);
+        check_golden("start_turning", &view_horizontal);
+    }
+}
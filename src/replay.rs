@@ -0,0 +1,168 @@
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, Error, ErrorKind, Write};
+
+use serde::{Deserialize, Serialize};
+
+use crate::archetype::ArchetypeRegistry;
+use crate::level::Level;
+use crate::simulation::InputSnapshot;
+use crate::spell::SpellBook;
+
+// Bump when the replay layout or the way mobs act changes (AI, movement, update order), old replays are refused rather than migrated
+pub const REPLAY_VERSION: u32 = 5;
+
+// Consecutive ticks with the same input are stored once
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ReplayStep {
+    pub ticks: u32,
+    pub input: InputSnapshot
+}
+
+// A game from its first tick: the level, the mob archetypes, the seed and the input of every tick
+// that ran.  Playing the inputs back into a simulation built from the same level, archetypes and
+// seed repeats the game exactly.
+#[derive(Serialize, Deserialize)]
+pub struct Replay {
+    pub version: u32,
+    pub seed: u64,
+    level: serde_json::Value, // Level file contents, so level migrations apply to old replays too
+    pub archetypes: ArchetypeRegistry,
+    pub spells: SpellBook,
+    pub steps: Vec<ReplayStep>
+}
+
+impl Replay {
+    pub fn new(level: &Level, archetypes: &ArchetypeRegistry, spells: &SpellBook, seed: u64) -> Result<Self, Error> {
+        let level = serde_json::to_value(level)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e.to_string()))?;
+        Ok(Replay {
+            version: REPLAY_VERSION,
+            seed,
+            level,
+            archetypes: archetypes.clone(),
+            spells: spells.clone(),
+            steps: Vec::new()
+        })
+    }
+
+    pub fn get_level(&self) -> Result<Level, Error> {
+        Level::from_json_value(self.level.clone())
+    }
+
+    pub fn record(&mut self, input: &InputSnapshot) {
+        match self.steps.last_mut() {
+            Some(step) if step.input == *input => {step.ticks += 1;}
+            _ => {self.steps.push(ReplayStep {ticks: 1, input: input.clone()});}
+        }
+    }
+
+    pub fn tick_count(&self) -> usize {
+        self.steps.iter().map(|s| s.ticks as usize).sum()
+    }
+
+    pub fn save_to_file(&self, filename: &str) -> Result<(), Error> {
+        let mut writer = BufWriter::new(File::create(filename)?);
+        serde_json::to_writer(&mut writer, self)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    pub fn load_from_file(filename: &str) -> Result<Self, Error> {
+        let reader = BufReader::new(OpenOptions::new().read(true).open(filename)?);
+        let replay: Replay = serde_json::from_reader(reader)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, format!("{}: {}", filename, e)))?;
+        if replay.version != REPLAY_VERSION {
+            return Err(Error::new(ErrorKind::InvalidData, format!(
+                "{}: replay version {} can't be played by this game (expects {})",
+                filename, replay.version, REPLAY_VERSION)));
+        }
+        Ok(replay)
+    }
+}
+
+// Hands out a replay's inputs one tick at a time
+pub struct ReplayPlayer {
+    steps: Vec<ReplayStep>,
+    step: usize,
+    tick: u32
+}
+
+impl ReplayPlayer {
+    pub fn new(replay: Replay) -> Self {
+        ReplayPlayer {
+            steps: replay.steps,
+            step: 0,
+            tick: 0
+        }
+    }
+
+    // Input for the next tick, None once the replay has run out
+    pub fn next_input(&mut self) -> Option<InputSnapshot> {
+        while let Some(step) = self.steps.get(self.step) {
+            if self.tick < step.ticks {
+                self.tick += 1;
+                return Some(step.input.clone());
+            }
+            self.step += 1;
+            self.tick = 0;
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mob::{MagicColor, MobSpawn};
+    use crate::simulation::{PlayerCommand, Simulation, TICK_TIME};
+    use macroquad::math::IVec2;
+
+    #[test]
+    fn test_replay_repeats_game() {
+        let mut level = Level::from_rows(&[
+            "##########",
+            "#........#",
+            "#.H......#",
+            "#.H......#",
+            "##########",
+        ]).unwrap();
+        level.player_start = (8, 1);
+        level.win_room = (1, 1);
+        for (x, y) in [(4, 2), (6, 3), (3, 3)] {
+            level.mob_list.push(MobSpawn::new(IVec2::new(x, y), MagicColor::Black));
+        }
+
+        let seed = 1234;
+        let archetypes = ArchetypeRegistry::default();
+        let mut recording = Replay::new(&level, &archetypes, &SpellBook::default(), seed).unwrap();
+        let mut sim = Simulation::new(level, &archetypes, seed);
+        let commands = [PlayerCommand::Fire, PlayerCommand::SwapColor, PlayerCommand::WalkForward, PlayerCommand::Fire];
+        for tick in 0..600 {
+            let input = InputSnapshot {
+                command: if tick % 90 == 0 {Some(commands[(tick / 90) % commands.len()])} else {None},
+                ..Default::default()
+            };
+            recording.record(&input);
+            sim.tick(&input, TICK_TIME);
+        }
+        assert_eq!(recording.tick_count(), 600);
+
+        let text = serde_json::to_string(&recording).unwrap();
+        let loaded: Replay = serde_json::from_str(&text).unwrap();
+        let mut replayed = Simulation::new(loaded.get_level().unwrap(), &loaded.archetypes, loaded.seed);
+        let mut player = ReplayPlayer::new(loaded);
+        while let Some(input) = player.next_input() {
+            replayed.tick(&input, TICK_TIME);
+        }
+
+        assert_eq!(replayed.player_pos.get_pos(), sim.player_pos.get_pos());
+        assert_eq!(replayed.player_hp, sim.player_hp);
+        assert_eq!(replayed.rng, sim.rng);
+        let mob_positions = |s: &Simulation| -> Vec<(f64, f64, f64)> {
+            s.mobs.mob_list.values().map(|m| {
+                (m.get_pos().x, m.get_pos().y, m.hp)
+            }).collect()
+        };
+        assert_eq!(mob_positions(&replayed), mob_positions(&sim));
+    }
+}
@@ -0,0 +1,178 @@
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, Error, ErrorKind, Write};
+
+use serde::{Deserialize, Serialize};
+
+use crate::mob::MagicColor;
+
+pub const KILL_POINTS: u64 = 100;
+pub const ABSORB_POINTS: u64 = 10; // Same color bullet soaked up by the player
+pub const CHAIN_LENGTH: u32 = 3; // Kills of one color that make up a link of the chain
+const FIRST_LINK_BONUS: u64 = 100;
+const MAX_LINK_BONUS: u64 = 25600;
+const HIGH_SCORE_COUNT: usize = 10;
+
+// Points for one game.  Kills chain the way they do in Ikaruga: every three kills in a row of the
+// same color add a link to the chain, and each link is worth twice the one before.  Three kills
+// that don't share a color break the chain.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Score {
+    pub points: u64,
+    pub chain: u32, // Links in the current chain
+    pub max_chain: u32,
+    pub absorbed: u32,
+    link_color: Option<MagicColor>,
+    link_kills: u32 // Kills toward the next link
+}
+
+impl Score {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Bonus for the link'th link of a chain
+    pub fn link_bonus(link: u32) -> u64 {
+        if link == 0 {
+            return 0;
+        }
+        (FIRST_LINK_BONUS << (link - 1).min(16)).min(MAX_LINK_BONUS)
+    }
+
+    pub fn record_kill(&mut self, color: MagicColor) {
+        self.points += KILL_POINTS;
+        if self.link_kills > 0 && self.link_color != Some(color) {
+            self.chain = 0;
+            self.link_kills = 0;
+        }
+        self.link_color = Some(color);
+        self.link_kills += 1;
+        if self.link_kills == CHAIN_LENGTH {
+            self.link_kills = 0;
+            self.chain += 1;
+            self.max_chain = self.max_chain.max(self.chain);
+            self.points += Self::link_bonus(self.chain);
+        }
+    }
+
+    pub fn record_absorb(&mut self) {
+        self.absorbed += 1;
+        self.points += ABSORB_POINTS;
+    }
+
+    // Color and number of kills of the link being built, for the HUD
+    pub fn link_progress(&self) -> Option<(MagicColor, u32)> {
+        match self.link_color {
+            Some(color) if self.link_kills > 0 => {Some((color, self.link_kills))}
+            _ => {None}
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct HighScore {
+    pub points: u64,
+    pub max_chain: u32,
+    pub absorbed: u32
+}
+
+// Best scores for one level, highest first
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct HighScores {
+    pub scores: Vec<HighScore>
+}
+
+impl HighScores {
+    // High scores are kept next to the level, level.json has level.scores.json
+    pub fn filename_for(level_filename: &str) -> String {
+        match level_filename.strip_suffix(".json") {
+            None => {format!("{}.scores.json", level_filename)}
+            Some(x) => {format!("{}.scores.json", x)}
+        }
+    }
+
+    // A missing file is an empty table, the first win creates it
+    pub fn load_from_file(filename: &str) -> Result<Self, Error> {
+        let file = match OpenOptions::new().read(true).open(filename) {
+            Ok(x) => {x}
+            Err(e) if e.kind() == ErrorKind::NotFound => {return Ok(HighScores::default());}
+            Err(e) => {return Err(e);}
+        };
+        serde_json::from_reader(BufReader::new(file))
+            .map_err(|e| Error::new(ErrorKind::InvalidData, format!("{}: {}", filename, e)))
+    }
+
+    pub fn save_to_file(&self, filename: &str) -> Result<(), Error> {
+        let mut writer = BufWriter::new(File::create(filename)?);
+        serde_json::to_writer_pretty(&mut writer, self)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    // Puts the score in the table, the place it got if it made the cut
+    pub fn add(&mut self, score: &Score) -> Option<usize> {
+        let entry = HighScore {
+            points: score.points,
+            max_chain: score.max_chain,
+            absorbed: score.absorbed
+        };
+        // Ties go to the older score
+        let place = self.scores.iter().position(|x| x.points < entry.points).unwrap_or(self.scores.len());
+        if place >= HIGH_SCORE_COUNT {
+            return None;
+        }
+        self.scores.insert(place, entry);
+        self.scores.truncate(HIGH_SCORE_COUNT);
+        Some(place)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chains() {
+        let mut score = Score::new();
+        for _ in 0..6 {
+            score.record_kill(MagicColor::White);
+        }
+        assert_eq!(score.chain, 2);
+        assert_eq!(score.points, 6 * KILL_POINTS + 100 + 200);
+
+        // A link can be a different color from the last one
+        for _ in 0..3 {
+            score.record_kill(MagicColor::Black);
+        }
+        assert_eq!(score.chain, 3);
+
+        // Mixing colors inside a link breaks the chain
+        score.record_kill(MagicColor::White);
+        score.record_kill(MagicColor::Black);
+        assert_eq!(score.chain, 0);
+        assert_eq!(score.max_chain, 3);
+        assert_eq!(score.link_progress(), Some((MagicColor::Black, 1)));
+
+        assert_eq!(Score::link_bonus(9), 25600);
+        assert_eq!(Score::link_bonus(20), 25600);
+    }
+
+    #[test]
+    fn test_high_scores() {
+        assert_eq!(HighScores::filename_for("level.json"), "level.scores.json");
+        let mut table = HighScores::default();
+        let mut score = Score::new();
+        for points in [500, 300, 900] {
+            score.points = points;
+            table.add(&score);
+        }
+        let points: Vec<u64> = table.scores.iter().map(|x| x.points).collect();
+        assert_eq!(points, vec![900, 500, 300]);
+
+        for _ in 0..10 {
+            table.add(&score);
+        }
+        assert_eq!(table.scores.len(), 10);
+        score.points = 100;
+        assert_eq!(table.add(&score), None);
+    }
+}
@@ -0,0 +1,203 @@
+use std::io::{Error, ErrorKind};
+
+use crate::simulation::{InputSnapshot, PlayerCommand, SimEvent, Simulation, TICK_TIME};
+
+// One step of a test script, a key press lasts a single tick
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum ScriptStep {
+    Press(PlayerCommand),
+    Wait(usize),
+}
+
+// Scripts are whitespace separated keys, using the same keys as the game (W S Q E A D Space Left
+// Right Shift, 1 to 9 to pick a spell), and "wait N" to let N ticks go by.  Anything after a # on a line is a comment.
+//
+//     W wait 20   # walk one room forward
+//     Q wait 20   # climb up
+pub fn parse_script(text: &str) -> Result<Vec<ScriptStep>, Error> {
+    let mut steps: Vec<ScriptStep> = Vec::new();
+    for (line_number, line) in text.lines().enumerate() {
+        let line = match line.find('#') {
+            None => line,
+            Some(x) => &line[..x],
+        };
+        let mut tokens = line.split_whitespace();
+        while let Some(token) = tokens.next() {
+            let step = match token {
+                "W" => ScriptStep::Press(PlayerCommand::WalkForward),
+                "S" => ScriptStep::Press(PlayerCommand::WalkBackward),
+                "Q" => ScriptStep::Press(PlayerCommand::ClimbUp),
+                "E" => ScriptStep::Press(PlayerCommand::ClimbDown),
+                "A" => ScriptStep::Press(PlayerCommand::TurnLeft),
+                "D" => ScriptStep::Press(PlayerCommand::TurnRight),
+                "Space" => ScriptStep::Press(PlayerCommand::Fire),
+                "Left" => ScriptStep::Press(PlayerCommand::SwapColor),
+                "Right" => ScriptStep::Press(PlayerCommand::Release),
+                "Shift" => ScriptStep::Press(PlayerCommand::LookHorizontal),
+                "1" | "2" | "3" | "4" | "5" | "6" | "7" | "8" | "9" => {
+                    ScriptStep::Press(PlayerCommand::SelectSpell(token.as_bytes()[0] - b'1'))
+                }
+                "wait" => match tokens.next().map(|x| x.parse::<usize>()) {
+                    Some(Ok(ticks)) => ScriptStep::Wait(ticks),
+                    _ => {
+                        return Err(Error::new(ErrorKind::InvalidData, format!(
+                            "line {}: wait needs a number of ticks", line_number + 1)));
+                    }
+                },
+                x => {
+                    return Err(Error::new(ErrorKind::InvalidData, format!(
+                        "line {}: unknown script step {}", line_number + 1, x)));
+                }
+            };
+            steps.push(step);
+        }
+    }
+    Ok(steps)
+}
+
+// Runs the steps one fixed tick at a time, returns everything that happened along the way
+pub fn run_script(sim: &mut Simulation, steps: &[ScriptStep]) -> Vec<SimEvent> {
+    let mut events: Vec<SimEvent> = Vec::new();
+    for step in steps {
+        match step {
+            ScriptStep::Press(command) => {
+                let input = InputSnapshot {
+                    command: Some(*command),
+                    ..Default::default()
+                };
+                events.extend(sim.tick(&input, TICK_TIME));
+            }
+            ScriptStep::Wait(ticks) => {
+                for _ in 0..*ticks {
+                    events.extend(sim.tick(&InputSnapshot::default(), TICK_TIME));
+                }
+            }
+        }
+    }
+    events
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::archetype::ArchetypeRegistry;
+    use crate::level::Level;
+    use crate::mob::{MagicColor, MobSpawn};
+    use crate::simulation::{PlayerMode, PLAYER_MAX_HP};
+    use macroquad::math::IVec2;
+
+    fn play(rows: &[&str], start: (usize, usize), script: &str) -> Simulation {
+        let mut level = Level::from_rows(rows).unwrap();
+        level.player_start = start;
+        level.win_room = (0, 0);
+        play_level(level, script)
+    }
+
+    fn play_level(level: Level, script: &str) -> Simulation {
+        let mut sim = Simulation::new(level, &ArchetypeRegistry::default(), 1);
+        run_script(&mut sim, &parse_script(script).unwrap());
+        sim
+    }
+
+    #[test]
+    fn test_parse_script() {
+        let steps = parse_script("W wait 3 # forward\nSpace Left 2").unwrap();
+        assert_eq!(steps, vec![
+            ScriptStep::Press(PlayerCommand::WalkForward),
+            ScriptStep::Wait(3),
+            ScriptStep::Press(PlayerCommand::Fire),
+            ScriptStep::Press(PlayerCommand::SwapColor),
+            ScriptStep::Press(PlayerCommand::SelectSpell(1)),
+        ]);
+        assert!(parse_script("W wait").is_err());
+        assert!(parse_script("X").is_err());
+    }
+
+    #[test]
+    fn test_climb_ladder() {
+        let rows = [
+            "#####",
+            "#...#",
+            "#H###",
+            "#H..#",
+            "#####",
+        ];
+        let sim = play(&rows, (3, 3), "W wait 20 W wait 20 Q wait 20 Q wait 20");
+        assert_eq!(sim.player_pos.get_pos(), IVec2::new(1, 1));
+        assert_eq!(sim.player_state.mode, PlayerMode::Idle);
+
+        // Nothing to hold on to without the ladder
+        let rows = [
+            "#####",
+            "#...#",
+            "#..##",
+            "#...#",
+            "#####",
+        ];
+        let sim = play(&rows, (3, 3), "W wait 20 W wait 20 Q wait 20");
+        assert_eq!(sim.player_pos.get_pos(), IVec2::new(1, 3));
+    }
+
+    #[test]
+    fn test_stem_up_shaft() {
+        // A shaft one room wide can be climbed by bracing against both walls
+        let rows = [
+            "####",
+            "##.#",
+            "##.#",
+            "#..#",
+            "####",
+        ];
+        let sim = play(&rows, (2, 3), "Q wait 20 Q wait 20");
+        assert_eq!(sim.player_pos.get_pos(), IVec2::new(2, 1));
+        assert_eq!(sim.player_state.mode, PlayerMode::Idle);
+    }
+
+    #[test]
+    fn test_straddle_and_fall() {
+        // A pit one room wide can be stepped over
+        let rows = [
+            "#####",
+            "#...#",
+            "##.##",
+            "##.##",
+            "#####",
+        ];
+        let sim = play(&rows, (3, 1), "W wait 20 W wait 20");
+        assert_eq!(sim.player_pos.get_pos(), IVec2::new(1, 1));
+
+        // But not a wider one, the player falls to the bottom
+        let rows = [
+            "#####",
+            "#...#",
+            "#..##",
+            "#..##",
+            "#####",
+        ];
+        let sim = play(&rows, (3, 1), "W wait 20");
+        assert_eq!(sim.player_state.mode, PlayerMode::Falling);
+        let sim = play(&rows, (3, 1), "W wait 20 wait 40");
+        assert_eq!(sim.player_pos.get_pos(), IVec2::new(2, 3));
+        assert_eq!(sim.player_state.mode, PlayerMode::Idle);
+    }
+
+    #[test]
+    fn test_shoot_mob_and_win() {
+        let mut level = Level::from_rows(&[
+            "#######",
+            "#.....#",
+            "#######",
+        ]).unwrap();
+        level.player_start = (4, 1);
+        level.win_room = (1, 1);
+        level.mob_list.push(MobSpawn::new(IVec2::new(2, 1), MagicColor::Black));
+
+        // Four light shots take down a void monster before it gets to act
+        let mut sim = play_level(level, "Space wait 60 Space wait 60 Space wait 60 Space wait 90");
+        assert_eq!(sim.living_mob_count(), 0);
+        assert_eq!(sim.player_hp, PLAYER_MAX_HP);
+
+        run_script(&mut sim, &parse_script("W wait 20 W wait 20 W wait 20").unwrap());
+        assert!(sim.has_won());
+    }
+}
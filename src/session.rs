@@ -0,0 +1,175 @@
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, Error, ErrorKind, Write};
+
+use serde::{Deserialize, Serialize};
+
+use crate::grid2d::{Grid2D, WallGridCell};
+use crate::level::Level;
+use crate::level_format;
+use crate::arena::Arena;
+use crate::mob::{Bullet, MagicColor, MobData};
+use crate::rng::GameRng;
+use crate::score::Score;
+use crate::simulation::PLAYER_MAX_MANA;
+
+// Bump when the snapshot layout changes, old snapshots are refused rather than migrated
+pub const SESSION_VERSION: u32 = 2;
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PlayerSnapshot {
+    pub pos: (i32, i32),
+    pub facing: f64,
+    pub hp: f64,
+    pub mana_color: MagicColor,
+    pub look_rotation: f64,
+    pub fire_cooldown: f64,
+    pub horizontal_view: bool,
+    #[serde(default)]
+    pub score: Score,
+    #[serde(default)]
+    pub polarity: u32,
+    #[serde(default)]
+    pub selected_spell: usize,
+    #[serde(default = "full_mana")]
+    pub mana: f64
+}
+
+fn full_mana() -> f64 {
+    PLAYER_MAX_MANA
+}
+
+// Everything needed to pick a game back up where it was saved.  Moves that were in progress
+// are not kept, the player resumes standing in their current room.
+#[derive(Serialize, Deserialize)]
+pub struct SessionSnapshot {
+    pub version: u32,
+    level: serde_json::Value, // Level file contents, so level migrations apply to old sessions too
+    pub player: PlayerSnapshot,
+    #[serde(with = "level_format::cell_rows")]
+    pub visited: Grid2D<WallGridCell>,
+    pub mobs: Arena<MobData>, // Saved with their handles, so the mob grid can be rebuilt as it was
+    pub bullets: Arena<Bullet>,
+    #[serde(default)]
+    pub rng: Option<GameRng> // Sessions saved before the game had its own generator get a new seed
+}
+
+impl SessionSnapshot {
+    pub fn new(level: &Level,
+               player: PlayerSnapshot,
+               visited: &Grid2D<WallGridCell>,
+               mobs: &Arena<MobData>,
+               bullets: &Arena<Bullet>,
+               rng: &GameRng) -> Result<Self, Error> {
+        let level = serde_json::to_value(level)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e.to_string()))?;
+        Ok(SessionSnapshot {
+            version: SESSION_VERSION,
+            level,
+            player,
+            visited: visited.clone(),
+            mobs: mobs.clone(),
+            bullets: bullets.clone(),
+            rng: Some(rng.clone())
+        })
+    }
+
+    pub fn get_level(&self) -> Result<Level, Error> {
+        Level::from_json_value(self.level.clone())
+    }
+
+    pub fn save_to_file(&self, filename: &str) -> Result<(), Error> {
+        let mut writer = BufWriter::new(File::create(filename)?);
+        serde_json::to_writer(&mut writer, self)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    pub fn load_from_file(filename: &str) -> Result<Self, Error> {
+        let reader = BufReader::new(OpenOptions::new().read(true).open(filename)?);
+        let snapshot: SessionSnapshot = serde_json::from_reader(reader)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, format!("{}: {}", filename, e)))?;
+        if snapshot.version != SESSION_VERSION {
+            return Err(Error::new(ErrorKind::InvalidData, format!(
+                "{}: session version {} can't be loaded by this game (expects {})",
+                filename, snapshot.version, SESSION_VERSION)));
+        }
+        Ok(snapshot)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::archetype::ArchetypeRegistry;
+    use crate::mob::{MobId, Mobs, MobSpawn};
+    use crate::simulation::Simulation;
+    use macroquad::math::IVec2;
+
+    #[test]
+    fn test_session_round_trip() {
+        let mut level = Level::from_rows(&[
+            "#####",
+            "#...#",
+            "#####",
+        ]).unwrap();
+        level.mob_list.push(MobSpawn::new(IVec2::new(3, 1), MagicColor::Black));
+
+        let mut mob_grid: Grid2D<MobId> = Grid2D::new(5, 3);
+        mob_grid.zero();
+        let mut mobs = Mobs::new();
+        for m in &level.mob_list {
+            mobs.new_monster(m, &ArchetypeRegistry::default(), &mut mob_grid);
+        }
+        let handle = mobs.mob_list.handles()[0];
+        mobs.mob_list.get_mut(handle).unwrap().hp = 42.0;
+
+        let player = PlayerSnapshot {
+            pos: (1, 1),
+            facing: -1.0,
+            hp: 100.0,
+            mana_color: MagicColor::Black,
+            look_rotation: 0.0,
+            fire_cooldown: 0.5,
+            horizontal_view: false,
+            score: Score::new(),
+            polarity: 3,
+            selected_spell: 0,
+            mana: 50.0
+        };
+        let mut visited: Grid2D<WallGridCell> = Grid2D::new(5, 3);
+        visited.zero();
+        visited.set_cell_at_grid_coords_int(IVec2::new(2, 1), WallGridCell::Wall);
+
+        let snapshot = SessionSnapshot::new(&level, player, &visited, &mobs.mob_list, &Arena::new(), &GameRng::new(7)).unwrap();
+        let text = serde_json::to_string(&snapshot).unwrap();
+        let loaded: SessionSnapshot = serde_json::from_str(&text).unwrap();
+
+        assert_eq!(loaded.get_level().unwrap().grid.get_cells(), level.grid.get_cells());
+        assert_eq!(loaded.player.pos, (1, 1));
+        assert_eq!(loaded.player.polarity, 3);
+        assert_eq!(loaded.rng, Some(GameRng::new(7)));
+        assert_eq!(loaded.visited.get_cell_at_grid_coords_int(IVec2::new(2, 1)), Some(&WallGridCell::Wall));
+
+        // The mob grid is rebuilt from the saved mobs, under the same handles
+        let mobs = Mobs { mob_list: loaded.mobs };
+        let mut mob_grid: Grid2D<MobId> = Grid2D::new(5, 3);
+        mobs.rebuild_mob_grid(&mut mob_grid);
+        match mob_grid.get_cell_at_grid_coords_int(IVec2::new(3, 1)) {
+            Some(MobId::Mob(h)) => {
+                assert_eq!(*h, handle);
+                let m = mobs.mob_list.get(*h).unwrap();
+                assert_eq!(m.hp, 42.0);
+                assert_eq!(m.get_color(), MagicColor::Black);
+            }
+            _ => {panic!("Mob was not placed back in the grid");}
+        }
+
+        // The player can't come back inside a mob's room or outside the level
+        let mut player = snapshot.player.clone();
+        for (pos, ok) in [((1, 1), true), ((3, 1), false), ((1, 5), false)] {
+            player.pos = pos;
+            let sim = Simulation::from_session(snapshot.get_level().unwrap(), &player, snapshot.mobs.clone(), Arena::new(), GameRng::new(7));
+            assert_eq!(sim.is_ok(), ok, "{:?}", pos);
+        }
+    }
+}
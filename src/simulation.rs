@@ -0,0 +1,1884 @@
+use std::f64::consts::PI;
+use std::io::{Error, ErrorKind};
+
+use macroquad::math::{DVec2, IVec2};
+use serde::{Deserialize, Serialize};
+
+use crate::archetype::{ArchetypeRegistry, BulletPattern, ColorBehavior};
+use crate::behavior::{self, Behavior, FireAt, Movement, Perception, HURT_ALLY_HP};
+use crate::combat::{self, Collision, DamageIndicator};
+use crate::grid2d::{Grid2D, WallGridCell};
+use crate::level::{self, apply_boundary_conditions_f64, ucoords_to_icoords, world_space_centered_coord, Level};
+use crate::arena::{Arena, Handle};
+use crate::mob::{self, line_of_sight, Bullet, BulletKind, Bullets, MagicColor, MobData, MobId, MobType, Mobs, MonsterState};
+use crate::pathfinding::{self, Locomotion, PathGoal};
+use crate::player_movement::{
+    has_floor, is_climbable, is_hazard, is_room_occupiable, is_supported_position, is_wall,
+    try_move, MoveDirection, PlayerPosition,
+};
+use crate::raycaster::cast_ray;
+use crate::rng::GameRng;
+use crate::score::Score;
+use crate::session::PlayerSnapshot;
+use crate::spell::{SpellBook, SpellKind};
+
+pub const PLAYER_MAX_HP: f64 = 639.0;
+pub const FIRE_COOLDOWN: f64 = 1.0;
+pub const PLAYER_MAX_MANA: f64 = 100.0;
+const MANA_REGEN: f64 = 10.0; // Per second
+const FLASH_TIME: f64 = 0.25; // Seconds a beam or burst stays visible
+pub const POLARITY_MAX: u32 = 8; // Absorbed bullets the polarity meter holds
+const RELEASE_SPREAD: f64 = 90.0; // Degrees the released shots fan out over
+const HOMING_TURN_RATE: f64 = 3.0; // Radians per second
+const HOMING_RANGE: f64 = 8.0;
+
+// The simulation always advances in steps of this size, whatever the frame rate
+pub const TICK_RATE: f64 = 60.0;
+pub const TICK_TIME: f64 = 1.0 / TICK_RATE;
+
+// Frame time beyond this is dropped, so after a long hitch the game slows down instead of
+// running a burst of catch up ticks
+const MAX_FRAME_TIME: f64 = 0.25;
+
+// Discrete player actions, one can be issued per tick
+#[derive(Copy, Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub enum PlayerCommand {
+    TurnLeft,
+    TurnRight,
+    WalkForward,
+    WalkBackward,
+    ClimbUp,
+    ClimbDown,
+    Fire,
+    SwapColor,
+    LookHorizontal,
+    Release,
+    SelectSpell(u8), // Index into the spell book
+}
+
+// Player input for one tick.  The front end fills this in from whatever it reads input from.
+#[derive(Clone, Default, PartialEq, Debug, Serialize, Deserialize)]
+pub struct InputSnapshot {
+    pub command: Option<PlayerCommand>, // Pressed this tick
+    pub look_up: bool,                  // Held down
+    pub look_down: bool,
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum PlayerMode {
+    Idle,
+    Moving,
+    Falling,
+    Turning(f64),
+}
+
+// Things that happened during a tick that the front end may want to show
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum SimEvent {
+    PlayerHit,
+    PlayerHeal,
+    HazardHit,
+}
+
+pub struct PlayerState {
+    pub mode: PlayerMode,
+    pub look_rotation: f64,
+    pub fire_cooldown: f64,
+
+    new_player_pos: Option<(i32, i32)>,
+    new_player_look: Option<(f64, f64)>,
+    lerp: f64,
+
+    // Turning around, the horizontal view shows this
+    turn_direction: f64,
+    turn_lerp: f64,
+}
+
+impl PlayerState {
+    fn new() -> Self {
+        PlayerState {
+            mode: PlayerMode::Idle,
+            look_rotation: 0.0,
+            fire_cooldown: 0.0,
+            new_player_pos: None,
+            new_player_look: None,
+            lerp: 0.0,
+            turn_direction: 0.0,
+            turn_lerp: 0.0,
+        }
+    }
+
+    // Extra rotation of the view while turning around
+    pub fn turn_rotation(&self) -> f64 {
+        self.turn_direction * PI * self.turn_lerp
+    }
+
+    fn player_look(&mut self, input: &InputSnapshot, dt: f64) {
+        let look_up_max: f64 = PI / 2.1;
+        let look_down_max: f64 = -PI / 2.1;
+        let look_speed: f64 = 1.5; // Time in seconds to cover range
+        let look_range: f64 = look_up_max - look_down_max;
+
+        if input.look_up {
+            self.look_rotation += look_range / look_speed * dt;
+            self.look_rotation = self.look_rotation.min(look_up_max);
+        } else if input.look_down {
+            self.look_rotation -= look_range / look_speed * dt;
+            self.look_rotation = self.look_rotation.max(look_down_max);
+        }
+    }
+
+    fn start_turn(&mut self, direction: f64) -> PlayerMode {
+        self.turn_direction = direction;
+        self.turn_lerp = 0.0;
+        PlayerMode::Turning(direction)
+    }
+}
+
+pub fn calculate_view_dir(rotation_angle: f64, player_facing: f64) -> DVec2 {
+    let rot2d = DVec2::from((rotation_angle.cos(), player_facing * rotation_angle.sin()));
+    let dir = player_facing * DVec2::from((-1.0, 0.0));
+    rot2d.rotate(dir)
+}
+
+// Turns variable frame times into a whole number of fixed ticks
+pub struct FixedTimestep {
+    accumulator: f64,
+}
+
+impl FixedTimestep {
+    pub fn new() -> Self {
+        FixedTimestep { accumulator: 0.0 }
+    }
+
+    // Number of ticks to run for a frame that took frame_time seconds
+    pub fn advance(&mut self, frame_time: f64) -> usize {
+        self.accumulator += frame_time.clamp(0.0, MAX_FRAME_TIME);
+        let ticks = (self.accumulator / TICK_TIME).floor();
+        self.accumulator -= ticks * TICK_TIME;
+        ticks as usize
+    }
+
+    // How far the frame is between the last tick and the next one, 0 to 1
+    pub fn alpha(&self) -> f64 {
+        (self.accumulator / TICK_TIME).clamp(0.0, 1.0)
+    }
+}
+
+// Blends between the positions before and after the last tick for drawing.  Jumps of more than a
+// cell, like wrapping around the edge of the world, are not blended.
+pub fn interpolate(last: DVec2, current: DVec2, alpha: f64) -> DVec2 {
+    if last.distance(current) > 1.0 {
+        current
+    } else {
+        last.lerp(current, alpha)
+    }
+}
+
+// Beams and bursts hit at once, these are left behind for a moment so there is something to see
+#[derive(Clone, Debug)]
+pub struct SpellFlash {
+    pub pos: DVec2,
+    pub color: MagicColor,
+    pub spell: String, // Spell id, for the sprite
+    time_left: f64,
+}
+
+// All of the gameplay state, advanced one tick at a time without touching the window or input
+pub struct Simulation {
+    pub world: Level,
+    pub mobs: Mobs,
+    pub bullets: Bullets,
+    pub mob_grid: Grid2D<MobId>,
+    pub player_pos: PlayerPosition,
+    pub player_state: PlayerState,
+    pub player_facing: f64,
+    pub player_world_coord: DVec2, // Player position with moves in progress, used for drawing
+    last_player_world_coord: DVec2, // Same, before the last tick
+    pub mana_color: MagicColor,
+    pub player_hp: f64,
+    pub score: Score,
+    pub polarity: u32, // Absorbed bullets waiting to be released
+    pub spells: SpellBook,
+    pub selected_spell: usize,
+    pub mana: f64,
+    pub flashes: Vec<SpellFlash>,
+    pub rng: GameRng, // All gameplay randomness comes from here, so a seed and the inputs replay a game
+
+    collisions: Vec<Collision>,
+    new_bullets: Vec<(DVec2, DVec2, f64, MagicColor, MobId)>,
+}
+
+impl Simulation {
+    pub fn new(world: Level, archetypes: &ArchetypeRegistry, seed: u64) -> Self {
+        let mut mob_grid = Self::new_mob_grid(&world);
+        let mut mobs = Mobs::new();
+        for m in &world.mob_list {
+            mobs.new_monster(m, archetypes, &mut mob_grid);
+        }
+        let player_pos = PlayerPosition::new(world.player_start, &mut mob_grid);
+        Self::with_state(world, mobs, Bullets::new(), mob_grid, player_pos, GameRng::new(seed))
+    }
+
+    // Picks a saved session back up, the mob grid is rebuilt from the saved mobs.  The player's
+    // room has to be in the level and free of mobs.
+    pub fn from_session(world: Level, player: &PlayerSnapshot, mobs: Arena<MobData>, bullets: Arena<Bullet>, rng: GameRng) -> Result<Self, Error> {
+        let mut mob_grid = Self::new_mob_grid(&world);
+        let mobs = Mobs { mob_list: mobs };
+        mobs.rebuild_mob_grid(&mut mob_grid);
+        match mob_grid.get_cell_at_grid_coords_int(IVec2::from(player.pos)) {
+            None => {
+                return Err(Error::new(ErrorKind::InvalidData, format!("player room {:?} is outside the level", player.pos)));
+            }
+            Some(MobId::NoMob) => {}
+            Some(_) => {
+                return Err(Error::new(ErrorKind::InvalidData, format!("player room {:?} is taken by a mob", player.pos)));
+            }
+        }
+        let player_pos = PlayerPosition::new((player.pos.0 as usize, player.pos.1 as usize), &mut mob_grid);
+
+        let mut sim = Self::with_state(world, mobs, Bullets { bullet_list: bullets }, mob_grid, player_pos, rng);
+        sim.player_facing = player.facing;
+        sim.player_hp = player.hp;
+        sim.mana_color = player.mana_color;
+        sim.player_state.look_rotation = player.look_rotation;
+        sim.player_state.fire_cooldown = player.fire_cooldown;
+        sim.score = player.score.clone();
+        sim.polarity = player.polarity.min(POLARITY_MAX);
+        sim.selected_spell = player.selected_spell;
+        sim.mana = player.mana.clamp(0.0, PLAYER_MAX_MANA);
+        if player.horizontal_view {
+            // The turn was not finished, so it starts over
+            sim.player_state.mode = sim.player_state.start_turn(1.0);
+        }
+        Ok(sim)
+    }
+
+    fn new_mob_grid(world: &Level) -> Grid2D<MobId> {
+        let (world_width, world_height) = world.grid.get_size();
+        let mut mob_grid: Grid2D<MobId> = Grid2D::new(world_width, world_height);
+        mob_grid.set_topology(world.grid.get_topology());
+        mob_grid.zero();
+        mob_grid
+    }
+
+    fn with_state(world: Level, mobs: Mobs, bullets: Bullets, mob_grid: Grid2D<MobId>, player_pos: PlayerPosition, rng: GameRng) -> Self {
+        let player_world_coord = world_space_centered_coord(player_pos.get_pos_ituple(), 0.0, 0.0);
+        Simulation {
+            world,
+            mobs,
+            bullets,
+            mob_grid,
+            player_pos,
+            player_state: PlayerState::new(),
+            player_facing: 1.0,
+            player_world_coord,
+            last_player_world_coord: player_world_coord,
+            mana_color: MagicColor::White,
+            player_hp: PLAYER_MAX_HP,
+            score: Score::new(),
+            polarity: 0,
+            spells: SpellBook::default(),
+            selected_spell: 0,
+            mana: PLAYER_MAX_MANA,
+            flashes: Vec::new(),
+            rng,
+            collisions: Vec::with_capacity(16),
+            new_bullets: Vec::new(),
+        }
+    }
+
+    // A session saved with a longer spell book may have a spell selected that is gone
+    pub fn set_spells(&mut self, spells: SpellBook) {
+        self.spells = spells;
+        if self.selected_spell >= self.spells.spells.len() {
+            self.selected_spell = 0;
+        }
+    }
+
+    pub fn player_snapshot(&self) -> PlayerSnapshot {
+        PlayerSnapshot {
+            pos: self.player_pos.get_pos_ituple(),
+            facing: self.player_facing,
+            hp: self.player_hp,
+            mana_color: self.mana_color,
+            look_rotation: self.player_state.look_rotation,
+            fire_cooldown: self.player_state.fire_cooldown,
+            horizontal_view: self.is_turning(),
+            score: self.score.clone(),
+            polarity: self.polarity,
+            selected_spell: self.selected_spell,
+            mana: self.mana,
+        }
+    }
+
+    pub fn is_turning(&self) -> bool {
+        match self.player_state.mode {
+            PlayerMode::Turning(_) => true,
+            _ => false,
+        }
+    }
+
+    // Where to draw the player, alpha is FixedTimestep::alpha
+    pub fn player_draw_coord(&self, alpha: f64) -> DVec2 {
+        interpolate(self.last_player_world_coord, self.player_world_coord, alpha)
+    }
+
+    pub fn has_won(&self) -> bool {
+        self.player_pos.get_pos() == IVec2::from(ucoords_to_icoords(self.world.win_room))
+    }
+
+    pub fn is_dead(&self) -> bool {
+        self.player_hp <= 0.0
+    }
+
+    pub fn living_mob_count(&self) -> usize {
+        self.mobs.mob_list.values().filter(|m| m.is_alive).count()
+    }
+
+    // Advances the game by dt seconds.  The rest of the world waits while the player turns around.
+    pub fn tick(&mut self, input: &InputSnapshot, dt: f64) -> Vec<SimEvent> {
+        let mut events: Vec<SimEvent> = Vec::new();
+        self.last_player_world_coord = self.player_world_coord;
+        self.player_world_coord = world_space_centered_coord(self.player_pos.get_pos_ituple(), 0.0, 0.0);
+
+        if let PlayerMode::Turning(_) = self.player_state.mode {
+            self.player_state.mode = self.do_turning_state(dt);
+            return events;
+        }
+
+        // Cooldown player attack
+        self.player_state.fire_cooldown = self.player_state.fire_cooldown.max(0.0);
+        self.flashes.retain_mut(|x| {
+            x.time_left -= dt;
+            x.time_left > 0.0
+        });
+
+        // Execute state machine
+        self.player_state.mode = match self.player_state.mode {
+            PlayerMode::Idle => self.do_idle_state(input, dt),
+            PlayerMode::Moving => self.do_moving_state(input, dt),
+            PlayerMode::Falling => self.do_falling_state(input, dt),
+            PlayerMode::Turning(direction) => PlayerMode::Turning(direction),
+        };
+
+        // Delete mobs marked as dead
+        self.mobs.delete_dead_mobs(&mut self.mob_grid);
+        self.bullets.delete_dead_bullets();
+
+        self.update_mobs(dt);
+
+        // Animate Bullets
+        let player_center = world_space_centered_coord(self.player_pos.get_pos_ituple(), 0.0, 0.0);
+        let mut paths: Vec<(Handle, BulletPath)> = Vec::new();
+        for (h, bullet) in self.bullets.bullet_list.iter_mut() {
+            if let Some(path) = plan_bullet(bullet, dt, &self.world, &self.mobs, player_center) {
+                paths.push((h, path));
+            }
+        }
+        if self.world.bullet_collisions {
+            resolve_bullet_collisions(&mut self.bullets, &mut paths, &self.world);
+        }
+        for (h, path) in paths.iter() {
+            if let Some(bullet) = self.bullets.bullet_list.get_mut(*h) {
+                finish_bullet(bullet, path, &self.world, &mut self.collisions);
+            }
+        }
+
+        // Create new bullets
+        for (pos, dir, offset, color, shooter) in self.new_bullets.iter() {
+            self.bullets.new_bullet(*pos, *dir, *offset, *color, *shooter);
+        }
+        self.new_bullets.clear();
+
+        // Hazard cells hurt while the player stands in them
+        if is_hazard(self.player_pos.get_pos(), &self.world) {
+            self.player_hp = (self.player_hp - combat::HAZARD_DAMAGE_PER_SECOND * dt).clamp(0.0, PLAYER_MAX_HP);
+            events.push(SimEvent::HazardHit);
+        }
+
+        // Handle collisions, only what the player does to the mobs scores
+        for c in self.collisions.iter() {
+            let by_mob = c.shooter != MobId::Player;
+            match c.damage_target(&mut self.mobs, &mut self.player_hp, PLAYER_MAX_HP, self.mana_color) {
+                DamageIndicator::PlayerHit => events.push(SimEvent::PlayerHit),
+                DamageIndicator::PlayerHeal => {
+                    if by_mob {
+                        self.score.record_absorb();
+                        self.polarity = (self.polarity + 1).min(POLARITY_MAX);
+                    }
+                    events.push(SimEvent::PlayerHeal)
+                }
+                DamageIndicator::MobKilled(color) => {
+                    if !by_mob {
+                        self.score.record_kill(color);
+                    }
+                }
+                DamageIndicator::Other => {}
+            }
+        }
+        self.collisions.clear();
+
+        // Decrease weapon cooldown
+        self.player_state.fire_cooldown -= dt;
+        self.mana = (self.mana + MANA_REGEN * dt).min(PLAYER_MAX_MANA);
+
+        events
+    }
+
+    fn do_idle_state(&mut self, input: &InputSnapshot, dt: f64) -> PlayerMode {
+        let state = &mut self.player_state;
+        let player_pos_ivec = self.player_pos.get_pos();
+        let level = &self.world;
+        let mob_grid = &self.mob_grid;
+        let color = self.mana_color;
+
+        state.new_player_pos = None;
+        let mut cast: Option<(DVec2, DVec2)> = None; // Where from and which way
+
+        let facing = self.player_facing as i32;
+        let standing_on_mob = !is_room_occupiable(player_pos_ivec + IVec2::new(0, 1), mob_grid);
+
+        if !is_supported_position(player_pos_ivec, level, color) && !standing_on_mob {
+            state.new_player_pos = None;
+            return PlayerMode::Falling;
+        }
+
+        state.player_look(input, dt);
+
+        let try_move_to = |state: &mut PlayerState, dir: MoveDirection| {
+            if let Some(new_pos) = try_move(player_pos_ivec, dir, facing, color, level, mob_grid) {
+                state.new_player_pos = Some((new_pos.x, new_pos.y));
+                PlayerMode::Moving
+            } else {
+                PlayerMode::Idle
+            }
+        };
+
+        let next_state = match input.command {
+            None => PlayerMode::Idle,
+            Some(x) => {
+                match x {
+                    PlayerCommand::TurnLeft | PlayerCommand::TurnRight => {
+                        // Level the view before turning around
+                        if state.look_rotation != 0.0 {
+                            state.new_player_look = Some((state.look_rotation, 1.0));
+                            PlayerMode::Moving
+                        } else if x == PlayerCommand::TurnLeft {
+                            state.start_turn(-1.0)
+                        } else {
+                            state.start_turn(1.0)
+                        }
+                    }
+                    PlayerCommand::WalkForward => {
+                        if state.look_rotation != 0.0 {
+                            state.new_player_look = Some((state.look_rotation, 1.0));
+                            PlayerMode::Moving
+                        } else {
+                            try_move_to(state, MoveDirection::WalkForward)
+                        }
+                    }
+                    PlayerCommand::WalkBackward => {
+                        if state.look_rotation != 0.0 {
+                            state.new_player_look = Some((state.look_rotation, 1.0));
+                            PlayerMode::Moving
+                        } else {
+                            try_move_to(state, MoveDirection::WalkBackward)
+                        }
+                    }
+                    PlayerCommand::ClimbUp => try_move_to(state, MoveDirection::ClimbUp),
+                    PlayerCommand::ClimbDown => try_move_to(state, MoveDirection::ClimbDown),
+                    PlayerCommand::Fire => {
+                        if let Some(spell) = self.spells.spells.get(self.selected_spell) {
+                            if state.fire_cooldown == 0.0 && self.mana >= spell.mana_cost {
+                                state.fire_cooldown = spell.cooldown;
+                                self.mana -= spell.mana_cost;
+                                let shoot_dir = calculate_view_dir(state.look_rotation, self.player_facing).normalize();
+                                let ppos = self.player_pos.get_pos_dvec() + DVec2::from((0.5, 0.5)); // center in square
+                                cast = Some((ppos, shoot_dir));
+                            }
+                        }
+                        PlayerMode::Idle
+                    }
+                    PlayerCommand::SelectSpell(i) => {
+                        if (i as usize) < self.spells.spells.len() {
+                            self.selected_spell = i as usize;
+                        }
+                        PlayerMode::Idle
+                    }
+                    PlayerCommand::Release => {
+                        // Every absorbed bullet goes back out at once, homing and in the current color
+                        if state.fire_cooldown == 0.0 && self.polarity > 0 {
+                            state.fire_cooldown = FIRE_COOLDOWN;
+                            let shoot_dir = calculate_view_dir(state.look_rotation, self.player_facing).normalize();
+                            let ppos = self.player_pos.get_pos_dvec() + DVec2::from((0.5, 0.5)); // center in square
+                            let pattern = BulletPattern::Spread {count: self.polarity, angle: RELEASE_SPREAD};
+                            for dir in bullet_directions(shoot_dir, pattern) {
+                                self.bullets.new_homing_bullet(ppos, dir, combat::PLAYER_HIT_DISTANCE + 0.01, self.mana_color, MobId::Player);
+                            }
+                            self.polarity = 0;
+                        }
+                        PlayerMode::Idle
+                    }
+                    PlayerCommand::SwapColor => {
+                        if can_take_color(&[player_pos_ivec], color.get_opposite(), level) {
+                            self.mana_color = color.get_opposite();
+                        }
+                        PlayerMode::Idle
+                    }
+                    PlayerCommand::LookHorizontal => {
+                        // Turns the same way as last time, the horizontal view always ends facing the other way
+                        let direction = state.turn_direction;
+                        state.start_turn(direction)
+                    }
+                }
+            }
+        };
+
+        if next_state == PlayerMode::Moving {
+            state.lerp = 0.0;
+        }
+
+        if let Some((pos, dir)) = cast {
+            self.cast_spell(pos, dir);
+        }
+
+        next_state
+    }
+
+    // Bolts and spreads fire bullets.  Beams and bursts hit at once, their collisions are handled
+    // along with the bullets' this tick.
+    fn cast_spell(&mut self, pos: DVec2, dir: DVec2) {
+        let spell = match self.spells.spells.get(self.selected_spell) {
+            None => {return;}
+            Some(x) => {x.clone()}
+        };
+        let color = self.mana_color;
+        let ws = self.world.grid.get_size();
+        let topology = self.world.grid.get_topology();
+        let offset = combat::PLAYER_HIT_DISTANCE + 0.01;
+        let mut flash_at: Vec<DVec2> = Vec::new();
+        match spell.kind {
+            SpellKind::Bolt => {
+                self.bullets.new_spell_bullet(pos, dir, offset, color, &spell);
+            }
+            SpellKind::Spread {count, angle} => {
+                for d in bullet_directions(dir, BulletPattern::Spread {count, angle}) {
+                    self.bullets.new_spell_bullet(pos, d, offset, color, &spell);
+                }
+            }
+            SpellKind::Beam {range} => {
+                // Phased cells don't stop it, same as bullets
+                let (hit_distance, cell, _, _) = cast_ray(&self.world.grid, &pos, &dir, range);
+                let length = if cell == WallGridCell::Empty {range} else {hit_distance.min(range)};
+                for (h, m) in self.mobs.mob_list.iter() {
+                    let on_beam = level::periodic_images(m.get_pos(), ws, topology).iter().any(|p| {
+                        let along = (*p - pos).dot(dir);
+                        along > 0.0 && along < length && (pos + along * dir).distance(*p) < mob::MONSTER_HIT_DISTANCE
+                    });
+                    if on_beam && m.is_alive {
+                        self.collisions.push(Collision::new_with_bullet(MobId::Mob(h), color, MobId::Player, Some(spell.damage)));
+                    }
+                }
+                let mut along = 0.5;
+                while along < length {
+                    flash_at.push(pos + along * dir);
+                    along += 0.5;
+                }
+            }
+            SpellKind::Burst {radius} => {
+                for (h, m) in self.mobs.mob_list.iter() {
+                    let in_reach = level::periodic_images(m.get_pos(), ws, topology).iter().any(|p| {
+                        p.distance(pos) <= radius && line_of_sight(pos, *p, radius, &self.world.grid).is_some()
+                    });
+                    if in_reach && m.is_alive {
+                        self.collisions.push(Collision::new_with_bullet(MobId::Mob(h), color, MobId::Player, Some(spell.damage)));
+                    }
+                }
+                for i in 0..12 {
+                    flash_at.push(pos + 0.5 * radius * DVec2::from_angle(i as f64 * PI / 6.0));
+                }
+            }
+        }
+        for p in flash_at {
+            self.flashes.push(SpellFlash {
+                pos: apply_boundary_conditions_f64(p, ws, topology),
+                color,
+                spell: spell.id.clone(),
+                time_left: FLASH_TIME,
+            });
+        }
+    }
+
+    fn do_moving_state(&mut self, input: &InputSnapshot, dt: f64) -> PlayerMode {
+        let state = &mut self.player_state;
+        let level = &self.world;
+        let next_state_from_move = match state.new_player_pos {
+            None => PlayerMode::Idle,
+            Some(x) => {
+                let p = self.player_pos.get_pos_ituple();
+                let begin_pos = world_space_centered_coord(p, 0.0, 0.0);
+                let final_pos = world_space_centered_coord(x, 0.0, 0.0);
+                let v = final_pos - begin_pos;
+                state.lerp += dt / 0.25;
+                state.lerp = state.lerp.min(1.0);
+                let upc = begin_pos + state.lerp * v;
+                self.player_world_coord = apply_boundary_conditions_f64(
+                    upc,
+                    level.grid.get_size(),
+                    level.grid.get_topology(),
+                );
+                if state.lerp == 1.0 {
+                    let nc = level::apply_boundary_conditions_i32(
+                        IVec2::from(x),
+                        level.grid.get_size(),
+                        level.grid.get_topology(),
+                    );
+                    let res = self.player_pos.set_pos(nc, &mut self.mob_grid);
+                    if res.is_err() {
+                        eprintln!("Moved player to occupied mob position");
+                    }
+                    PlayerMode::Idle
+                } else {
+                    // Both rooms of the move have to stay open
+                    let rooms = [self.player_pos.get_pos(), IVec2::from(x)];
+                    if input.command == Some(PlayerCommand::SwapColor) && can_take_color(&rooms, self.mana_color.get_opposite(), level) {
+                        self.mana_color = self.mana_color.get_opposite();
+                    }
+                    PlayerMode::Moving
+                }
+            }
+        };
+
+        let look_rate: f64 = PI / 0.3;
+        let next_state_from_look = match state.new_player_look {
+            None => PlayerMode::Idle,
+            Some((start_look, ref mut lerp)) => {
+                *lerp = (*lerp - dt * look_rate).clamp(0.0, 1.0);
+                state.look_rotation = start_look * *lerp;
+                if *lerp == 0.0 {
+                    PlayerMode::Idle
+                } else {
+                    PlayerMode::Moving
+                }
+            }
+        };
+
+        if next_state_from_move == PlayerMode::Moving || next_state_from_look == PlayerMode::Moving {
+            PlayerMode::Moving
+        } else {
+            state.new_player_look = None;
+            state.new_player_pos = None;
+            PlayerMode::Idle
+        }
+    }
+
+    fn do_falling_state(&mut self, input: &InputSnapshot, dt: f64) -> PlayerMode {
+        let state = &mut self.player_state;
+        let level = &self.world;
+        let player_icoords = self.player_pos.get_pos_ituple();
+        let player_pos_ivec = IVec2::from(player_icoords);
+
+        state.player_look(input, dt);
+
+        match state.new_player_pos {
+            None => {
+                if has_floor(player_pos_ivec, level, self.mana_color).is_some() || // Fall stopped by floor
+                    is_climbable(player_pos_ivec, level) || // Caught a ladder or landed in water
+                    !is_room_occupiable(player_pos_ivec + IVec2::new(0, 1), &self.mob_grid)
+                {
+                    // Fall stopped by mob
+                    PlayerMode::Idle
+                } else {
+                    state.new_player_pos = Some((player_icoords.0, player_icoords.1 + 1)); // Fall down one tile
+                    state.lerp = 0.0;
+                    PlayerMode::Falling
+                }
+            }
+            Some(x) => {
+                let begin_pos = world_space_centered_coord(player_icoords, 0.0, 0.0);
+                let final_pos = world_space_centered_coord(x, 0.0, 0.0);
+                let v = final_pos - begin_pos;
+                state.lerp += dt / 0.125;
+                state.lerp = state.lerp.min(1.0);
+                let upc = begin_pos + state.lerp * v;
+                self.player_world_coord = apply_boundary_conditions_f64(
+                    upc,
+                    level.grid.get_size(),
+                    level.grid.get_topology(),
+                );
+                if state.lerp == 1.0 {
+                    let nc = level::apply_boundary_conditions_i32(
+                        IVec2::from(x),
+                        level.grid.get_size(),
+                        level.grid.get_topology(),
+                    );
+                    let res = self.player_pos.set_pos(nc, &mut self.mob_grid);
+                    if res.is_err() {
+                        eprintln!("Player fell through occupied mob position");
+                    }
+                    state.new_player_pos = None;
+                }
+                PlayerMode::Falling
+            }
+        }
+    }
+
+    fn do_turning_state(&mut self, dt: f64) -> PlayerMode {
+        let turn_speed: f64 = 0.35; // Time in seconds to turn around
+        let state = &mut self.player_state;
+        state.turn_lerp = (state.turn_lerp + dt / turn_speed).clamp(0.0, 1.0);
+        if state.turn_lerp == 1.0 {
+            state.turn_lerp = 0.0;
+            self.player_facing = -1.0 * self.player_facing;
+            PlayerMode::Idle
+        } else {
+            state.mode
+        }
+    }
+
+    fn update_mobs(&mut self, dt: f64) {
+        let world = &self.world;
+        let mob_grid = &mut self.mob_grid;
+        let player_pos = self.player_pos.get_pos();
+        let rng = &mut self.rng;
+        let world_size = world.grid.get_size();
+        let topology = world.grid.get_topology();
+
+        // Mobs keep out of the room the player is moving into
+        let player_target = match (self.player_state.mode, self.player_state.new_player_pos) {
+            (PlayerMode::Moving, Some(x)) => Some(level::apply_boundary_conditions_i32(IVec2::from(x), world_size, topology)),
+            _ => None,
+        };
+        let is_free = |room: IVec2, mob_grid: &Grid2D<MobId>| is_room_occupiable(room, mob_grid) && Some(room) != player_target;
+
+        for h in self.mobs.mob_list.handles() {
+            let m = match self.mobs.mob_list.get_mut(h) {
+                None => {continue;}
+                Some(x) => {x}
+            };
+            m.start_tick();
+            let mob_pos = m.get_pos().as_ivec2();
+            let (can_attack, can_change_color, can_move, locomotion) = {
+                match &mut m.mob_type {
+                    MobType::Monster(monster) => {
+                        monster.update(dt);
+                        if monster.last_seen == Some(mob_pos) {
+                            monster.last_seen = None; // Checked, the player isn't here
+                        }
+                        (
+                            monster.can_attack(),
+                            // Fixed color mobs never start the cooldown, so it would always be ready
+                            monster.can_change_color() && monster.color_behavior != ColorBehavior::Fixed,
+                            monster.can_move(),
+                            monster.locomotion,
+                        )
+                    }
+                }
+            };
+
+            // A mob on the way to another room holds both rooms until it gets there
+            if let Some((start, end, _)) = m.moving {
+                let from = start.as_ivec2();
+                let falling = locomotion == Locomotion::Walking && end.y > start.y && !is_supported_position(from, world, m.get_color());
+                let speed = if falling {
+                    m.move_speed.max(mob::MONSTER_FALL_SPEED)
+                } else {
+                    m.move_speed
+                };
+                if m.advance_move(speed * dt, world_size, topology) {
+                    mob_grid.set_cell_at_grid_coords_int(from, MobId::NoMob);
+                    let move_speed_modifier = if m.get_pos().distance(self.player_pos.get_pos_dvec()) <= 3.0 {
+                        0.5f64
+                    } else {
+                        1.0f64
+                    };
+                    match &mut m.mob_type {
+                        MobType::Monster(monster) => {
+                            if !falling {
+                                monster.start_move_cooldown(move_speed_modifier);
+                            }
+                        }
+                    }
+                }
+                continue;
+            }
+
+            // Falling takes priority over everything else
+            if pathfinding::is_falling(mob_pos, locomotion, m.get_color(), world, mob_grid) {
+                let new_room = mob_pos + IVec2::new(0, 1);
+                if is_free(new_room, mob_grid) {
+                    start_mob_move(h, m, new_room, world, mob_grid);
+                }
+                continue;
+            }
+
+            // Nothing to decide until a cooldown runs out
+            if !can_move && !can_attack && !can_change_color {
+                continue;
+            }
+
+            // Looking around is only worth it when the mob can move or shoot on what it sees, a
+            // color change on its own doesn't depend on it
+            let acts_on_perception = can_move || can_attack;
+            let (perception, player_shot, ally_shot) = if acts_on_perception {
+                perceive(h, &self.mobs, self.player_world_coord, player_pos, self.mana_color, world, mob_grid)
+            } else {
+                (Perception::new(player_pos), None, None)
+            };
+            let m = match self.mobs.mob_list.get_mut(h) {
+                None => {continue;}
+                Some(x) => {x}
+            };
+            let intent = {
+                match &mut m.mob_type {
+                    MobType::Monster(monster) => {
+                        if acts_on_perception {
+                            monster.state = behavior::next_state(monster.behavior, monster.state, &perception);
+                            if perception.senses_player {
+                                monster.last_seen = Some(player_pos);
+                            }
+                        }
+                        behavior::intent(monster.behavior, monster.state, &perception)
+                    }
+                }
+            };
+            let wants_to_attack = can_attack && intent.fire.is_some();
+
+            // Change the enemy color if we can, how depends on the archetype
+            let mut change_color: Option<MagicColor> = None;
+            if can_change_color {
+                match &m.mob_type {
+                    MobType::Monster(monster) => {
+                        match monster.color_behavior {
+                            ColorBehavior::Counter => {
+                                if self.mana_color == m.get_color() {
+                                    if wants_to_attack && intent.fire == Some(FireAt::Player) {
+                                        change_color = Some(m.get_color().get_opposite());
+                                    }
+                                }
+                            }
+                            ColorBehavior::Fixed => {}
+                            ColorBehavior::Alternate => {
+                                change_color = Some(m.get_color().get_opposite());
+                            }
+                        }
+                    }
+                }
+            }
+
+            // A mob can't change into a color its room is solid for
+            let change_color = change_color.filter(|c| can_take_color(&[mob_pos], *c, world));
+            if let Some(new_color) = change_color {
+                m.set_color(new_color);
+                match &mut m.mob_type {
+                    MobType::Monster(monster) => {
+                        monster.start_color_change_cooldown();
+                    }
+                }
+            }
+
+            // Players get shot in the mob's color, allies are healed with their own
+            let mut fire: Option<(DVec2, DVec2, f64, MagicColor)> = None;
+            if wants_to_attack {
+                let target = match intent.fire {
+                    Some(FireAt::Player) => player_shot.map(|dir| (dir, m.get_color())),
+                    Some(FireAt::Ally) => ally_shot,
+                    None => None,
+                };
+                if let Some((dir, color)) = target {
+                    fire = Some((m.get_pos(), dir, mob::MONSTER_HIT_DISTANCE + 0.01, color));
+                }
+            }
+
+            let mut pattern = BulletPattern::Single;
+            if fire.is_some() {
+                match &mut m.mob_type {
+                    MobType::Monster(monster) => {
+                        monster.start_attack_cooldown();
+                        pattern = monster.bullet_pattern;
+                    }
+                }
+            }
+
+            if let Some((pos, dir, offset, color)) = fire {
+                for dir in bullet_directions(dir, pattern) {
+                    self.new_bullets.push((pos, dir, offset, color, MobId::Mob(h)))
+                }
+                continue; // One or the other each tick
+            }
+
+            if can_move {
+                let color = m.get_color();
+                let new_room = match &mut m.mob_type {
+                    MobType::Monster(monster) => {
+                        movement_room(intent.movement, monster, color, mob_pos, world, mob_grid, rng)
+                    }
+                };
+                match new_room {
+                    Some(new_room) if is_free(new_room, mob_grid) => {
+                        start_mob_move(h, m, new_room, world, mob_grid);
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+}
+
+// Direction to the target when the mob can see it and nothing else is in the way
+fn clear_shot(mob: &MobData, target: DVec2, world: &Level, mob_grid: &Grid2D<MobId>, is_target: impl Fn(&MobId) -> bool) -> Option<DVec2> {
+    // Check if line of sight blocked by wall
+    let (_, dir_wall) = mob.has_line_of_sight_with_bc(target, &world.grid)?;
+    // Check if another monster blocks line of sight.
+    let (hit, dir) = mob.has_line_of_sight_with_bc(target, mob_grid)?;
+    match mob_grid.get_cell_at_grid_coords_int(hit) {
+        Some(x) if is_target(x) && dir.dot(dir_wall) > 0.0 => Some(dir.normalize()),
+        _ => None,
+    }
+}
+
+// What a mob can tell about the player and its allies from where it is, with the directions to
+// shoot the player and a hurt ally in if it has a clear shot
+fn perceive(
+    h: Handle,
+    mobs: &Mobs,
+    player_world_coord: DVec2,
+    player_pos: IVec2,
+    mana_color: MagicColor,
+    world: &Level,
+    mob_grid: &Grid2D<MobId>,
+) -> (Perception, Option<DVec2>, Option<(DVec2, MagicColor)>) {
+    let mob = match mobs.mob_list.get(h) {
+        None => {return (Perception::new(player_pos), None, None);}
+        Some(x) => {x}
+    };
+    let mob_pos = mob.get_pos().as_ivec2();
+    let monster = match &mob.mob_type {
+        MobType::Monster(x) => x,
+    };
+
+    let mut p = Perception::new(player_pos);
+    p.hp_fraction = mob.hp / mob.max_hp;
+    p.has_patrol = monster.patrol.is_some();
+    p.last_seen = monster.last_seen;
+    p.can_hurt_player = mob.get_color() != mana_color || monster.color_behavior == ColorBehavior::Counter;
+    // Only search as far as the mob can sense, not the whole level
+    let sense_steps = mob.get_line_of_sight().max(0.0).floor() as usize;
+    p.senses_player = pathfinding::find_path_within(mob_pos, player_pos, Some(sense_steps), monster.locomotion, mob.get_color(), world, mob_grid).is_some();
+    let player_shot = clear_shot(mob, player_world_coord, world, mob_grid, |x| matches!(x, MobId::Player));
+    p.clear_shot = player_shot.is_some();
+    if p.senses_player {
+        p.cornered = pathfinding::next_step(mob_pos, PathGoal::Flee(player_pos), monster.locomotion, mob.get_color(), world, mob_grid).is_none();
+    }
+
+    // Only medics look out for their allies
+    let mut ally_shot: Option<(DVec2, MagicColor)> = None;
+    if monster.behavior == Behavior::Medic {
+        for (other, o) in mobs.mob_list.iter() {
+            if other == h {
+                continue;
+            }
+            if !o.is_alive || o.hp >= o.max_hp * HURT_ALLY_HP {
+                continue;
+            }
+            let is_ally = |x: &MobId| *x == MobId::Mob(other);
+            if let Some(dir) = clear_shot(mob, o.get_pos(), world, mob_grid, is_ally) {
+                p.hurt_ally = Some(o.get_pos().as_ivec2());
+                ally_shot = Some((dir, o.get_color()));
+                break;
+            }
+        }
+    }
+    (p, player_shot, ally_shot)
+}
+
+// A color switch is refused while it would leave the player or mob inside a phased cell
+fn can_take_color(rooms: &[IVec2], color: MagicColor, world: &Level) -> bool {
+    rooms.iter().all(|r| !is_wall(*r, world, color))
+}
+
+// Reserves the room the mob moves to, the room it leaves is freed when it arrives
+fn start_mob_move(h: Handle, m: &mut MobData, to: IVec2, world: &Level, mob_grid: &mut Grid2D<MobId>) {
+    m.start_move(to, world.grid.get_size(), world.grid.get_topology());
+    mob_grid.set_cell_at_grid_coords_int(to, MobId::Mob(h));
+}
+
+// Room a monster's movement takes it to next, None to stay put
+fn movement_room(
+    movement: Movement,
+    monster: &mut MonsterState,
+    color: MagicColor,
+    mob_pos: IVec2,
+    world: &Level,
+    mob_grid: &Grid2D<MobId>,
+    rng: &mut GameRng,
+) -> Option<IVec2> {
+    let locomotion = monster.locomotion;
+    match movement {
+        Movement::Stay => None,
+        Movement::Wander => {
+            let room_choices: Vec<IVec2> = pathfinding::neighbors(mob_pos, locomotion, color, world, mob_grid)
+                .into_iter()
+                .filter(|v| is_room_occupiable(*v, mob_grid))
+                .collect();
+            if room_choices.is_empty() {
+                None
+            } else {
+                Some(room_choices[rng.index(room_choices.len())])
+            }
+        }
+        Movement::Patrol => {
+            let route = monster.patrol.as_mut()?;
+            let waypoint = route.target(mob_pos)?;
+            let step = pathfinding::next_step(mob_pos, PathGoal::Reach(waypoint), locomotion, color, world, mob_grid);
+            if step.is_none() {
+                route.advance(); // Blocked or out of reach, try the next one
+            }
+            step
+        }
+        Movement::Path(goal) => {
+            let step = pathfinding::next_step(mob_pos, goal, locomotion, color, world, mob_grid);
+            if step.is_none() {
+                if let PathGoal::Reach(_) = goal {
+                    monster.last_seen = None; // Can't get to where the player was, stop looking
+                }
+            }
+            step
+        }
+    }
+}
+
+// Directions of the bullets in one attack, a spread is centered on the aim
+fn bullet_directions(dir: DVec2, pattern: BulletPattern) -> Vec<DVec2> {
+    match pattern {
+        BulletPattern::Single => {vec![dir]}
+        BulletPattern::Spread {count, angle} => {
+            if count <= 1 {
+                return vec![dir];
+            }
+            let step = angle.to_radians() / (count - 1) as f64;
+            let first = -angle.to_radians() / 2.0;
+            (0..count).map(|i| DVec2::from_angle(first + step * i as f64).rotate(dir)).collect()
+        }
+    }
+}
+
+// Closest mob a bullet of color at pos can see and hurt, looking across world boundaries.  Mobs
+// of the bullet's own color would be healed, they are left alone.
+fn nearest_visible_mob(pos: DVec2, color: MagicColor, world: &Level, mobs: &Mobs) -> Option<DVec2> {
+    let ws = world.grid.get_size();
+    let mut nearest: Option<DVec2> = None;
+    for m in mobs.mob_list.values() {
+        if !m.is_alive || m.get_color() == color {
+            continue;
+        }
+        for image in level::periodic_images(m.get_pos(), ws, world.grid.get_topology()) {
+            let dist = image.distance(pos);
+            if dist > HOMING_RANGE || dist < 1e-9 {
+                continue;
+            }
+            let closer = match nearest {
+                None => {true}
+                Some(x) => {dist < x.distance(pos)}
+            };
+            if closer && line_of_sight(pos, image, HOMING_RANGE, &world.grid).is_some() {
+                nearest = Some(image);
+            }
+        }
+    }
+    nearest
+}
+
+// Turns a homing bullet toward its target.  The path starts over from where the bullet is, with
+// the distance it had left, so it still dies at the end of its lifetime.
+fn steer_bullet(
+    pos: DVec2,
+    color: MagicColor,
+    moving: (DVec2, DVec2, f64),
+    dt: f64,
+    world: &Level,
+    mobs: &Mobs,
+) -> (DVec2, DVec2, f64) {
+    let (start, end, lerp) = moving;
+    let target = match nearest_visible_mob(pos, color, world, mobs) {
+        None => {return moving;}
+        Some(x) => {x}
+    };
+    let dir = (end - start).normalize_or_zero();
+    let wanted = (target - pos).normalize_or_zero();
+    if dir == DVec2::ZERO || wanted == DVec2::ZERO {
+        return moving;
+    }
+    let max_turn = HOMING_TURN_RATE * dt;
+    let turn = dir.angle_between(wanted).clamp(-max_turn, max_turn);
+    let remaining = (1.0 - lerp) * end.distance(start);
+    (pos, pos + remaining * DVec2::from_angle(turn).rotate(dir), 0.0)
+}
+
+// Where a path from pos, going along the unit vector dir for length, first comes within radius of
+// center.  Starting inside already counts as a hit.
+fn segment_hits_circle(pos: DVec2, dir: DVec2, length: f64, center: DVec2, radius: f64) -> Option<f64> {
+    let f = pos - center;
+    let c = f.length_squared() - radius * radius;
+    if c <= 0.0 {
+        return Some(0.0);
+    }
+    let b = f.dot(dir);
+    let discriminant = b * b - c;
+    if b > 0.0 || discriminant < 0.0 {
+        return None; // Heading away, or passing by
+    }
+    let t = -b - discriminant.sqrt();
+    if t <= length {
+        Some(t)
+    } else {
+        None
+    }
+}
+
+// Where a bullet goes this tick: the path from where it is, where it is headed if nothing stops
+// it, and the first thing along the path.  A hit on NoMob is a wall or another bullet.
+#[derive(Clone, Copy)]
+struct BulletPath {
+    from: DVec2,
+    dir: DVec2,
+    length: f64,
+    next: (DVec2, DVec2, f64),
+    hit: Option<(f64, MobId)>
+}
+
+impl BulletPath {
+    // Where along the path the bullet is at time s of the tick, from 0 to 1
+    fn at(&self, s: f64) -> DVec2 {
+        self.from + s * self.length * self.dir
+    }
+
+    // Time of the tick the bullet stops at, 1 if it gets through
+    fn end(&self) -> f64 {
+        match self.hit {
+            None => {1.0}
+            Some((t, _)) => {
+                if self.length > 0.0 {
+                    t / self.length
+                } else {
+                    0.0
+                }
+            }
+        }
+    }
+}
+
+// Sweeps the path a bullet covers this tick, so fast bullets and long ticks can't skip past a
+// wall or a mob.  The first thing along the path stops it.  player is the center of the player's
+// room.  Nothing is hit yet, see finish_bullet.
+fn plan_bullet(bullet: &mut Bullet, dt: f64, world: &Level, mobs: &Mobs, player: DVec2) -> Option<BulletPath> {
+    if let (BulletKind::Homing, Some(moving)) = (bullet.kind, bullet.moving) {
+        bullet.moving = Some(steer_bullet(bullet.get_pos(), bullet.get_color(), moving, dt, world, mobs));
+    }
+    let ws = world.grid.get_size();
+    let topology = world.grid.get_topology();
+    debug_assert!(bullet.moving.is_some());
+    match bullet.moving {
+        None => {
+            bullet.is_alive = false;
+            None
+        }
+        Some((start, end, lerp)) => {
+            let total_move_distance = end.distance(start);
+            let move_this_tick = bullet.move_speed * dt;
+            let new_lerp = (lerp + (move_this_tick / total_move_distance)).clamp(0.0, 1.0);
+            let from = bullet.get_pos();
+            let path = start.lerp(end, new_lerp) - start.lerp(end, lerp);
+            let length = path.length();
+            let dir = path.normalize_or_zero();
+
+            let mut hit: Option<(f64, MobId)> = None;
+
+            // Bullets pass through phased cells, only what is solid for every color stops them
+            if length > 0.0 {
+                let (wall_distance, cell, _, _) = cast_ray(&world.grid, &from, &dir, length);
+                if cell == WallGridCell::Wall && wall_distance <= length {
+                    hit = Some((wall_distance, MobId::NoMob));
+                }
+            }
+
+            // Mobs are measured from where they are, they may be part way between two rooms
+            let mut targets: Vec<(MobId, DVec2, f64)> = mobs.mob_list.iter()
+                .map(|(h, m)| (MobId::Mob(h), m.get_pos(), mob::MONSTER_HIT_DISTANCE))
+                .collect();
+            targets.push((MobId::Player, player, combat::PLAYER_HIT_DISTANCE));
+            for (target, center, radius) in targets {
+                if target == bullet.shooter {
+                    continue; // Never hits whoever fired it
+                }
+                for image in level::periodic_images(center, ws, topology) {
+                    if let Some(t) = segment_hits_circle(from, dir, length, image, radius) {
+                        let closer = match hit {
+                            None => {true}
+                            Some((best, _)) => {t < best}
+                        };
+                        if closer {
+                            hit = Some((t, target));
+                        }
+                    }
+                }
+            }
+
+            Some(BulletPath {
+                from,
+                dir,
+                length,
+                next: (start, end, new_lerp),
+                hit
+            })
+        }
+    }
+}
+
+// Moves a bullet along its planned path, up to whatever stops it
+fn finish_bullet(bullet: &mut Bullet, path: &BulletPath, world: &Level, collisions: &mut Vec<Collision>) {
+    let ws = world.grid.get_size();
+    let topology = world.grid.get_topology();
+    match path.hit {
+        Some((t, target)) => {
+            bullet.is_alive = false;
+            bullet.moving = None;
+            bullet.set_pos(path.from + t * path.dir, ws, topology);
+            if target != MobId::NoMob {
+                collisions.push(Collision::new_with_bullet(
+                    target,
+                    bullet.get_color(),
+                    bullet.shooter,
+                    bullet.damage,
+                ));
+            }
+        }
+        None => {
+            // Leaving through a clamped edge counts as a wall, so look before boundary conditions
+            // pull the bullet back inside
+            let (start, end, new_lerp) = path.next;
+            let unbounded = start.lerp(end, new_lerp);
+            let new_pos = apply_boundary_conditions_f64(unbounded, ws, topology);
+            let room = unbounded.floor().as_ivec2();
+            let hit_wall = is_wall(room, world, MagicColor::White) && is_wall(room, world, MagicColor::Black);
+            if new_lerp >= 1.0 || hit_wall {
+                bullet.is_alive = false;
+                bullet.moving = None;
+            } else {
+                bullet.moving = Some(path.next);
+                bullet.set_pos(new_pos, ws, topology);
+            }
+        }
+    }
+}
+
+// First time of the tick two bullets come within hit distance of each other, before either of
+// them stops.  Checked against every copy of b across world boundaries.
+fn bullets_meet(a: &BulletPath, b: &BulletPath, world: &Level) -> Option<f64> {
+    let ws = world.grid.get_size();
+    let topology = world.grid.get_topology();
+    let until = a.end().min(b.end());
+    let starts = level::periodic_images(b.at(0.0), ws, topology);
+    let ends = level::periodic_images(b.at(1.0), ws, topology);
+    let mut first: Option<f64> = None;
+    for (b_start, b_end) in starts.iter().zip(ends.iter()) {
+        // Seen from b, a moves along relative for the tick
+        let relative = (a.at(1.0) - a.at(0.0)) - (*b_end - *b_start);
+        let speed = relative.length();
+        let meet = segment_hits_circle(a.from - *b_start, relative.normalize_or_zero(), speed * until, DVec2::ZERO, combat::BULLET_HIT_DISTANCE)
+            .map(|t| if speed > 0.0 {t / speed} else {0.0});
+        first = match (first, meet) {
+            (Some(x), Some(y)) => {Some(x.min(y))}
+            (x, y) => {x.or(y)}
+        };
+    }
+    first
+}
+
+// Bullets from different shooters whose paths meet this tick, in the order they meet: opposite
+// colors cancel out, and same colors merge into one bullet carrying both their damage.  The
+// player's bullet is the one that carries on, otherwise the stronger one.  Bullets from the same
+// shooter never meet, a spread starts out overlapping.  Bullets stopped here hit nothing else.
+fn resolve_bullet_collisions(bullets: &mut Bullets, paths: &mut [(Handle, BulletPath)], world: &Level) {
+    let mut meetings: Vec<(f64, usize, usize)> = Vec::new();
+    for (i, (h, a)) in paths.iter().enumerate() {
+        for (j, (g, b)) in paths.iter().enumerate().skip(i + 1) {
+            let different_shooters = match (bullets.bullet_list.get(*h), bullets.bullet_list.get(*g)) {
+                (Some(x), Some(y)) => {x.shooter != y.shooter}
+                _ => {false}
+            };
+            if !different_shooters {
+                continue;
+            }
+            if let Some(s) = bullets_meet(a, b, world) {
+                meetings.push((s, i, j));
+            }
+        }
+    }
+    meetings.sort_by(|x, y| x.0.total_cmp(&y.0));
+
+    for (s, i, j) in meetings {
+        // One of them was already stopped by an earlier meeting
+        if paths[i].1.end() < s || paths[j].1.end() < s {
+            continue;
+        }
+        let (a, b) = match (bullets.bullet_list.get(paths[i].0), bullets.bullet_list.get(paths[j].0)) {
+            (Some(a), Some(b)) => {(a, b)}
+            _ => {continue;}
+        };
+        let (a_damage, b_damage) = (a.damage.unwrap_or(combat::BULLET_DAMAGE), b.damage.unwrap_or(combat::BULLET_DAMAGE));
+        if a.get_color() != b.get_color() {
+            paths[i].1.hit = Some((s * paths[i].1.length, MobId::NoMob));
+            paths[j].1.hit = Some((s * paths[j].1.length, MobId::NoMob));
+            continue;
+        }
+
+        let keep_a = match (a.shooter == MobId::Player, b.shooter == MobId::Player) {
+            (true, false) => {true}
+            (false, true) => {false}
+            _ => {a_damage >= b_damage}
+        };
+        let (kept, stopped) = if keep_a {(i, j)} else {(j, i)};
+        paths[stopped].1.hit = Some((s * paths[stopped].1.length, MobId::NoMob));
+        if let Some(x) = bullets.bullet_list.get_mut(paths[kept].0) {
+            x.damage = Some(a_damage + b_damage);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::behavior::BehaviorState;
+    use crate::grid2d::Topology;
+    use crate::mob::{mob_at_cell, MobSpawn, MonsterParams};
+    use crate::pathfinding::Locomotion;
+    use crate::score::KILL_POINTS;
+    use crate::spell::Spell;
+
+    fn run(sim: &mut Simulation, command: Option<PlayerCommand>, ticks: usize) -> Vec<SimEvent> {
+        let mut events = Vec::new();
+        let mut input = InputSnapshot { command, ..Default::default() };
+        for _ in 0..ticks {
+            events.extend(sim.tick(&input, 0.05));
+            input.command = None;
+        }
+        events
+    }
+
+    // A mob that never moves, shoots or changes color on its own
+    fn idle_mob(x: i32, y: i32, color: MagicColor) -> MobSpawn {
+        let mut spawn = MobSpawn::new(IVec2::new(x, y), color);
+        spawn.params = Some(MonsterParams {
+            move_cooldown: Some(1000.0),
+            attack_cooldown: Some(1000.0),
+            color_change_cooldown: Some(1000.0),
+            ..Default::default()
+        });
+        spawn
+    }
+
+    #[test]
+    fn test_walk_turn_and_win() {
+        let mut level = Level::from_rows(&[
+            "#######",
+            "#.....#",
+            "#######",
+        ]).unwrap();
+        level.player_start = (3, 1);
+        level.win_room = (5, 1);
+        let mut sim = Simulation::new(level, &ArchetypeRegistry::default(), 1);
+
+        // Facing left to start with, forward is -x
+        run(&mut sim, Some(PlayerCommand::WalkForward), 10);
+        assert_eq!(sim.player_pos.get_pos(), IVec2::new(2, 1));
+        assert_eq!(sim.player_state.mode, PlayerMode::Idle);
+
+        run(&mut sim, Some(PlayerCommand::TurnRight), 1);
+        assert!(sim.is_turning());
+        run(&mut sim, None, 10);
+        assert_eq!(sim.player_facing, -1.0);
+
+        for _ in 0..3 {
+            run(&mut sim, Some(PlayerCommand::WalkForward), 10);
+        }
+        assert_eq!(sim.player_pos.get_pos(), IVec2::new(5, 1));
+        assert!(sim.has_won());
+    }
+
+    #[test]
+    fn test_phased_cells() {
+        let mut level = Level::from_rows(&[
+            "#######",
+            "#..b..#",
+            "#######",
+        ]).unwrap();
+        level.player_start = (5, 1);
+        let mut sim = Simulation::new(level, &ArchetypeRegistry::default(), 1);
+
+        // Open while white
+        run(&mut sim, Some(PlayerCommand::WalkForward), 10);
+        run(&mut sim, Some(PlayerCommand::WalkForward), 10);
+        assert_eq!(sim.player_pos.get_pos(), IVec2::new(3, 1));
+        // Turning black inside it is refused
+        run(&mut sim, Some(PlayerCommand::SwapColor), 1);
+        assert_eq!(sim.mana_color, MagicColor::White);
+
+        run(&mut sim, Some(PlayerCommand::WalkForward), 10);
+        run(&mut sim, Some(PlayerCommand::SwapColor), 1);
+        assert_eq!(sim.mana_color, MagicColor::Black);
+        run(&mut sim, Some(PlayerCommand::WalkBackward), 10);
+        assert_eq!(sim.player_pos.get_pos(), IVec2::new(2, 1));
+
+        // Stemming between white walls, switching to black lets go
+        let mut level = Level::from_rows(&[
+            "#####",
+            "#w.w#",
+            "#w.w#",
+            "#...#",
+            "#####",
+        ]).unwrap();
+        level.player_start = (2, 2);
+        let mut sim = Simulation::new(level, &ArchetypeRegistry::default(), 1);
+        run(&mut sim, None, 10);
+        assert_eq!(sim.player_pos.get_pos(), IVec2::new(2, 2));
+        run(&mut sim, Some(PlayerCommand::SwapColor), 1);
+        run(&mut sim, None, 10);
+        assert_eq!(sim.player_pos.get_pos(), IVec2::new(2, 3));
+    }
+
+    #[test]
+    fn test_walking_monster_chases_player() {
+        let mut level = Level::from_rows(&[
+            "#########",
+            "#.......#",
+            "###H#####",
+            "#.......#",
+            "#########",
+        ]).unwrap();
+        level.player_start = (7, 1);
+        level.win_room = (1, 1);
+        let mut spawn = MobSpawn::new(IVec2::new(7, 3), MagicColor::Black);
+        spawn.params = Some(MonsterParams {
+            move_cooldown: Some(0.1),
+            attack_cooldown: Some(1000.0),
+            move_speed: Some(10.0),
+            locomotion: Some(Locomotion::Walking),
+            ..Default::default()
+        });
+        level.mob_list.push(spawn);
+        let mut sim = Simulation::new(level, &ArchetypeRegistry::default(), 1);
+
+        // Round by the ladder and along the top until it is next to the player
+        run(&mut sim, None, 100);
+        let mob_pos = sim.mobs.mob_list.values().next().unwrap().get_pos().as_ivec2();
+        assert_eq!(mob_pos, IVec2::new(6, 1));
+    }
+
+    #[test]
+    fn test_archetypes() {
+        let spread = bullet_directions(DVec2::new(1.0, 0.0), BulletPattern::Spread {count: 3, angle: 30.0});
+        assert_eq!(spread.len(), 3);
+        assert!(spread[1].abs_diff_eq(DVec2::new(1.0, 0.0), 1e-9));
+        assert!((spread[0].angle_between(spread[2]) - 30f64.to_radians()).abs() < 1e-9);
+
+        // Spawns of an archetype that isn't registered are left out
+        let mut level = Level::from_rows(&[
+            "#####",
+            "#...#",
+            "#####",
+        ]).unwrap();
+        level.player_start = (1, 1);
+        level.mob_list.push(MobSpawn::new(IVec2::new(2, 1), MagicColor::Black));
+        let mut imp = MobSpawn::new(IVec2::new(3, 1), MagicColor::Black);
+        imp.archetype = "Imp".to_string();
+        level.mob_list.push(imp);
+        let sim = Simulation::new(level, &ArchetypeRegistry::default(), 1);
+        assert_eq!(sim.living_mob_count(), 1);
+    }
+
+    #[test]
+    fn test_mob_holds_both_rooms_while_moving() {
+        let mut level = Level::from_rows(&[
+            "#######",
+            "#.....#",
+            "#######",
+        ]).unwrap();
+        level.player_start = (5, 1);
+        level.win_room = (5, 1);
+        let mut spawn = MobSpawn::new(IVec2::new(1, 1), MagicColor::Black);
+        spawn.params = Some(MonsterParams {
+            move_cooldown: Some(0.05),
+            attack_cooldown: Some(1000.0),
+            move_speed: Some(2.0),
+            ..Default::default()
+        });
+        level.mob_list.push(spawn);
+        let mut sim = Simulation::new(level, &ArchetypeRegistry::default(), 1);
+        let occupied = |sim: &Simulation, x: i32| matches!(mob_at_cell(IVec2::new(x, 1), &sim.mob_grid), MobId::Mob(_));
+
+        // Sets off on the first tick, half way a quarter of a second later at two rooms a second
+        run(&mut sim, None, 1);
+        run(&mut sim, None, 5);
+        let pos = sim.mobs.mob_list.values().next().unwrap().get_pos();
+        assert!((pos.x - 2.0).abs() < 1e-6);
+        assert!(occupied(&sim, 1) && occupied(&sim, 2));
+
+        run(&mut sim, None, 5);
+        let pos = sim.mobs.mob_list.values().next().unwrap().get_pos();
+        assert!((pos.x - 2.5).abs() < 1e-6);
+        assert!(!occupied(&sim, 1) && occupied(&sim, 2));
+    }
+
+    #[test]
+    fn test_medic_heals_ally() {
+        let mut level = Level::from_rows(&[
+            "##########",
+            "#........#",
+            "##########",
+        ]).unwrap();
+        level.player_start = (8, 1);
+        level.win_room = (8, 1);
+        let mut medic = MobSpawn::new(IVec2::new(1, 1), MagicColor::White);
+        medic.params = Some(MonsterParams {
+            move_cooldown: Some(1000.0),
+            attack_cooldown: Some(0.5),
+            behavior: Some(Behavior::Medic),
+            ..Default::default()
+        });
+        let mut ally = MobSpawn::new(IVec2::new(3, 1), MagicColor::Black);
+        ally.params = Some(MonsterParams {
+            move_cooldown: Some(1000.0),
+            attack_cooldown: Some(1000.0),
+            ..Default::default()
+        });
+        level.mob_list.push(medic);
+        level.mob_list.push(ally);
+        let mut sim = Simulation::new(level, &ArchetypeRegistry::default(), 1);
+        sim.mobs.mob_list.values_mut().nth(1).unwrap().hp = 10.0;
+
+        // Decides once its attack cooldown runs out
+        run(&mut sim, None, 11);
+        match &sim.mobs.mob_list.values().next().unwrap().mob_type {
+            MobType::Monster(monster) => assert_eq!(monster.state, BehaviorState::HealAlly),
+        }
+
+        // Shots of the ally's own color heal it
+        run(&mut sim, None, 60);
+        assert!(sim.mobs.mob_list.values().nth(1).unwrap().hp > 10.0);
+    }
+
+    #[test]
+    fn test_kill_scores() {
+        let mut level = Level::from_rows(&[
+            "##########",
+            "#........#",
+            "##########",
+        ]).unwrap();
+        level.player_start = (8, 1);
+        level.win_room = (1, 1);
+        level.mob_list.push(idle_mob(5, 1, MagicColor::Black));
+        let mut sim = Simulation::new(level, &ArchetypeRegistry::default(), 1);
+        sim.mobs.mob_list.values_mut().next().unwrap().hp = 10.0;
+
+        run(&mut sim, Some(PlayerCommand::Fire), 1);
+        run(&mut sim, None, 60);
+        assert!(sim.mobs.mob_list.is_empty());
+        assert_eq!(sim.score.points, KILL_POINTS);
+        assert_eq!(sim.score.link_progress(), Some((MagicColor::Black, 1)));
+    }
+
+    #[test]
+    fn test_absorb_and_release() {
+        let mut level = Level::from_rows(&[
+            "#########",
+            "#.......#",
+            "#.......#",
+            "#########",
+        ]).unwrap();
+        level.player_start = (7, 2);
+        level.win_room = (1, 1);
+        level.mob_list.push(idle_mob(2, 1, MagicColor::Black));
+        let mut sim = Simulation::new(level, &ArchetypeRegistry::default(), 1);
+        let handle = sim.mobs.mob_list.handles()[0];
+        sim.mobs.mob_list.get_mut(handle).unwrap().hp = 10.0;
+
+        // Nothing to release yet
+        run(&mut sim, Some(PlayerCommand::Release), 1);
+        assert!(sim.bullets.bullet_list.is_empty());
+
+        // A mob's bullet of the player's color fills the meter
+        sim.bullets.new_bullet(DVec2::new(4.5, 2.5), DVec2::new(1.0, 0.0), 0.0, MagicColor::White, MobId::Mob(handle));
+        run(&mut sim, None, 100);
+        assert_eq!(sim.polarity, 1);
+        assert_eq!(sim.score.absorbed, 1);
+
+        // The mob is a row above the line of fire, the released shot homes in on it
+        run(&mut sim, Some(PlayerCommand::Release), 1);
+        assert_eq!(sim.polarity, 0);
+        run(&mut sim, None, 240);
+        assert!(sim.mobs.mob_list.is_empty());
+        assert_eq!(sim.score.link_progress(), Some((MagicColor::Black, 1)));
+    }
+
+    #[test]
+    fn test_homing_skips_same_color() {
+        let mut level = Level::from_rows(&[
+            "#########",
+            "#.......#",
+            "#.......#",
+            "#########",
+        ]).unwrap();
+        level.player_start = (7, 2);
+        level.win_room = (1, 2);
+        level.mob_list.push(idle_mob(5, 1, MagicColor::White));
+        level.mob_list.push(idle_mob(2, 1, MagicColor::Black));
+        let mut sim = Simulation::new(level, &ArchetypeRegistry::default(), 1);
+        let black = sim.mobs.mob_list.values().find(|m| m.get_color() == MagicColor::Black).unwrap().get_pos();
+
+        // The white mob is closer, but a white bullet would heal it
+        let pos = DVec2::new(6.5, 2.5);
+        assert_eq!(nearest_visible_mob(pos, MagicColor::White, &sim.world, &sim.mobs), Some(black));
+        assert_ne!(nearest_visible_mob(pos, MagicColor::Black, &sim.world, &sim.mobs), Some(black));
+
+        sim.mobs.mob_list.values_mut().for_each(|m| m.hp = 10.0);
+        sim.bullets.new_homing_bullet(pos, DVec2::new(-1.0, 0.0), 0.0, MagicColor::White, MobId::Player);
+        run(&mut sim, None, 240);
+        assert_eq!(sim.living_mob_count(), 1);
+        assert!(sim.mobs.mob_list.values().all(|m| m.get_color() == MagicColor::White && m.hp == 10.0));
+    }
+
+    #[test]
+    fn test_spells() {
+        let mut level = Level::from_rows(&[
+            "##########",
+            "#........#",
+            "##########",
+        ]).unwrap();
+        level.player_start = (8, 1);
+        level.win_room = (1, 1);
+        for x in [3, 5] {
+            level.mob_list.push(idle_mob(x, 1, MagicColor::Black));
+        }
+        let mut sim = Simulation::new(level, &ArchetypeRegistry::default(), 1);
+        let mut beam = Spell::bolt();
+        beam.id = "Beam".to_string();
+        beam.kind = SpellKind::Beam {range: 10.0};
+        beam.damage = 200.0;
+        beam.mana_cost = 60.0;
+        sim.spells.spells.push(beam);
+
+        // There is no third spell
+        run(&mut sim, Some(PlayerCommand::SelectSpell(2)), 1);
+        assert_eq!(sim.selected_spell, 0);
+
+        // The beam goes through both mobs at once
+        run(&mut sim, Some(PlayerCommand::SelectSpell(1)), 1);
+        run(&mut sim, Some(PlayerCommand::Fire), 1);
+        assert!(sim.bullets.bullet_list.is_empty());
+        assert!(!sim.flashes.is_empty());
+        assert_eq!(sim.living_mob_count(), 0);
+        assert_eq!(sim.score.points, 2 * KILL_POINTS);
+
+        // The cooldown is over, but there is not enough mana left for a second one
+        run(&mut sim, None, 30);
+        let mana = sim.mana;
+        run(&mut sim, Some(PlayerCommand::Fire), 1);
+        assert!(sim.mana > mana);
+        assert!(sim.player_state.fire_cooldown <= 0.0);
+
+        // A spell book without the beam, the selection goes back to the first spell
+        sim.set_spells(SpellBook::default());
+        assert_eq!(sim.selected_spell, 0);
+    }
+
+    #[test]
+    fn test_swept_bullets() {
+        let mut level = Level::from_rows(&[
+            "##########",
+            "#....#...#",
+            "##########",
+        ]).unwrap();
+        level.player_start = (8, 1);
+        level.win_room = (7, 1);
+        level.mob_list.push(idle_mob(3, 1, MagicColor::Black));
+        let mut sim = Simulation::new(level, &ArchetypeRegistry::default(), 1);
+        // 2.5 rooms a tick, landing on either side of the mob and of the wall
+        let mut fast = Spell::bolt();
+        fast.speed = 50.0;
+        fast.damage = 200.0;
+
+        sim.bullets.new_spell_bullet(DVec2::new(1.5, 1.5), DVec2::new(1.0, 0.0), 0.0, MagicColor::White, &fast);
+        run(&mut sim, None, 1);
+        assert_eq!(sim.living_mob_count(), 0);
+
+        sim.bullets.new_spell_bullet(DVec2::new(1.5, 1.5), DVec2::new(1.0, 0.0), 0.0, MagicColor::White, &fast);
+        run(&mut sim, None, 2);
+        let b = sim.bullets.bullet_list.values().next().unwrap();
+        assert!(!b.is_alive);
+        assert!((b.get_pos().x - 5.0).abs() < 1e-9);
+
+        // A short step that enters the wall part way through still stops at the wall
+        let mut slow = Spell::bolt();
+        slow.speed = 6.0;
+        sim.bullets.bullet_list = Arena::new();
+        sim.bullets.new_spell_bullet(DVec2::new(4.95, 1.5), DVec2::new(1.0, 0.0), 0.0, MagicColor::White, &slow);
+        run(&mut sim, None, 1);
+        let b = sim.bullets.bullet_list.values().next().unwrap();
+        assert!(!b.is_alive);
+        assert!((b.get_pos().x - 5.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_bullets_stop_at_clamped_edge() {
+        let mut level = Level::from_rows(&[
+            "......",
+            "......",
+            "......",
+        ]).unwrap();
+        level.grid.set_topology(Topology::Clamp);
+        level.player_start = (4, 2);
+        level.win_room = (5, 2);
+        let mut sim = Simulation::new(level, &ArchetypeRegistry::default(), 1);
+        // 0.3 rooms a tick, so the edge is crossed part way through the second tick
+        let mut slow = Spell::bolt();
+        slow.speed = 6.0;
+        for (from, dir) in [(DVec2::new(0.5, 1.5), DVec2::new(-1.0, 0.0)), (DVec2::new(2.5, 0.5), DVec2::new(0.0, -1.0))] {
+            sim.bullets.bullet_list = Arena::new();
+            sim.bullets.new_spell_bullet(from, dir, 0.0, MagicColor::White, &slow);
+            run(&mut sim, None, 2);
+            let b = sim.bullets.bullet_list.values().next().unwrap();
+            assert!(!b.is_alive);
+            assert!((b.get_pos() - (from + 0.5 * dir)).length() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn test_bullet_collisions() {
+        let mut level = Level::from_rows(&[
+            "##########",
+            "#........#",
+            "##########",
+        ]).unwrap();
+        level.player_start = (8, 1);
+        level.win_room = (7, 1);
+        // 0.83 rooms a tick each, so they are never within hit distance at the end of a tick.  The
+        // mob's bullet is headed for the player, past the player's bullet.
+        let mut fast = Spell::bolt();
+        fast.speed = 50.0;
+        level.mob_list.push(idle_mob(1, 1, MagicColor::White));
+        level.mob_list.push(idle_mob(2, 1, MagicColor::White));
+        let mut sim = Simulation::new(level, &ArchetypeRegistry::default(), 1);
+        let mobs: Vec<MobId> = sim.mobs.mob_list.iter().map(|(h, _)| MobId::Mob(h)).collect();
+        // left is fired from the player's room, right from further down the corridor at the player
+        let fire = |sim: &mut Simulation, left: (MagicColor, MobId), right: (MagicColor, MobId)| {
+            sim.player_hp = PLAYER_MAX_HP;
+            sim.bullets.bullet_list = Arena::new();
+            for (pos, dir, (color, shooter)) in [(8.0, -1.0, left), (6.0, 1.0, right)] {
+                let (pos, dir) = (DVec2::new(pos, 1.5), DVec2::new(dir, 0.0));
+                let h = if shooter == MobId::Player {
+                    sim.bullets.new_spell_bullet(pos, dir, 0.0, color, &fast)
+                } else {
+                    sim.bullets.new_bullet(pos, dir, 0.0, color, shooter)
+                };
+                sim.bullets.bullet_list.get_mut(h).unwrap().move_speed = fast.speed;
+            }
+            for _ in 0..3 {
+                sim.tick(&InputSnapshot::default(), TICK_TIME);
+            }
+        };
+        let alive = |sim: &Simulation| -> Vec<Bullet> {
+            sim.bullets.bullet_list.values().filter(|b| b.is_alive).cloned().collect()
+        };
+
+        // Off by default, they pass through each other and the player is hit
+        fire(&mut sim, (MagicColor::White, MobId::Player), (MagicColor::Black, MobId::NoMob));
+        assert!(sim.player_hp < PLAYER_MAX_HP);
+
+        // Opposite colors cancel out, before the mob's bullet gets to the player
+        sim.world.bullet_collisions = true;
+        fire(&mut sim, (MagicColor::White, MobId::Player), (MagicColor::Black, MobId::NoMob));
+        assert!(alive(&sim).is_empty());
+        assert_eq!(sim.player_hp, PLAYER_MAX_HP);
+
+        // Same color, the player's bullet takes the other one in
+        fire(&mut sim, (MagicColor::White, MobId::Player), (MagicColor::White, MobId::NoMob));
+        let merged = alive(&sim);
+        assert_eq!(merged.len(), 1);
+        assert_eq!(merged[0].shooter, MobId::Player);
+        assert_eq!(merged[0].damage, Some(fast.damage + combat::BULLET_DAMAGE));
+        assert_eq!(sim.score.absorbed, 0);
+
+        // Two mobs' bullets cancel and merge the same way
+        fire(&mut sim, (MagicColor::White, mobs[0]), (MagicColor::Black, mobs[1]));
+        assert!(alive(&sim).is_empty());
+        assert_eq!(sim.player_hp, PLAYER_MAX_HP);
+        fire(&mut sim, (MagicColor::White, mobs[0]), (MagicColor::White, mobs[1]));
+        let merged = alive(&sim);
+        assert_eq!(merged.len(), 1);
+        assert_eq!(merged[0].damage, Some(2.0 * combat::BULLET_DAMAGE));
+
+        // Bullets from one shooter pass through each other
+        fire(&mut sim, (MagicColor::White, mobs[0]), (MagicColor::Black, mobs[0]));
+        assert!(sim.player_hp < PLAYER_MAX_HP);
+    }
+
+    #[test]
+    fn test_fall_into_hazard() {
+        let mut level = Level::from_rows(&[
+            "#####",
+            "#...#",
+            "#..##",
+            "#^^##",
+            "#####",
+        ]).unwrap();
+        level.player_start = (3, 1);
+        let mut sim = Simulation::new(level, &ArchetypeRegistry::default(), 1);
+
+        run(&mut sim, Some(PlayerCommand::WalkForward), 10);
+        let events = run(&mut sim, None, 20);
+        assert_eq!(sim.player_pos.get_pos(), IVec2::new(2, 3));
+        assert!(events.contains(&SimEvent::HazardHit));
+        assert!(sim.player_hp < PLAYER_MAX_HP);
+    }
+
+    // Plays the same input at different frame rates, the simulation should not notice
+    fn run_frames(frame_time: f64, seconds: f64) -> Simulation {
+        let mut level = Level::from_rows(&[
+            "##########",
+            "#........#",
+            "##########",
+        ]).unwrap();
+        level.player_start = (5, 1);
+        level.win_room = (1, 1);
+        let mut sim = Simulation::new(level, &ArchetypeRegistry::default(), 1);
+        let mut timestep = FixedTimestep::new();
+        let mut input = InputSnapshot { command: Some(PlayerCommand::Fire), ..Default::default() };
+        for frame in 0..(seconds / frame_time).round() as usize {
+            if frame == (0.5 / frame_time).round() as usize {
+                input.command = Some(PlayerCommand::WalkBackward);
+            }
+            for _ in 0..timestep.advance(frame_time) {
+                sim.tick(&input, TICK_TIME);
+                input.command = None;
+            }
+        }
+        sim
+    }
+
+    #[test]
+    fn test_fixed_timestep() {
+        let slow = run_frames(1.0 / 20.0, 1.0);
+        let fast = run_frames(1.0 / 240.0, 1.0);
+        assert_eq!(slow.player_pos.get_pos(), IVec2::new(6, 1));
+        assert_eq!(slow.player_pos.get_pos(), fast.player_pos.get_pos());
+        assert_eq!(slow.bullets.bullet_list.len(), 1);
+        let (a, b) = (slow.bullets.bullet_list.values().next().unwrap().get_pos(), fast.bullets.bullet_list.values().next().unwrap().get_pos());
+        assert!(a.distance(b) < 0.05);
+
+        // A long hitch is capped rather than run as a burst of ticks
+        let mut timestep = FixedTimestep::new();
+        assert_eq!(timestep.advance(5.0), (MAX_FRAME_TIME / TICK_TIME) as usize);
+    }
+}
@@ -0,0 +1,166 @@
+use std::collections::HashSet;
+use std::fs::OpenOptions;
+use std::io::{BufReader, Error, ErrorKind};
+
+use serde::{Deserialize, Serialize};
+
+// Spell the player starts with, and the only one before spells.json
+pub const DEFAULT_SPELL: &str = "Bolt";
+
+// How a spell reaches its targets
+#[derive(Copy, Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum SpellKind {
+    #[default]
+    Bolt, // One bullet straight ahead
+    Spread {count: u32, angle: f64}, // count bullets fanned out evenly over angle degrees
+    Beam {range: f64}, // Hits every mob along the line of fire at once, up to the first wall
+    Burst {radius: f64} // Hits every mob in sight within radius rooms
+}
+
+fn default_cooldown() -> f64 {
+    1.0
+}
+
+fn default_speed() -> f64 {
+    2.0
+}
+
+fn default_lifetime() -> f64 {
+    5.0
+}
+
+fn default_damage() -> f64 {
+    33.0
+}
+
+fn default_light_sprite() -> String {
+    "light_bullet".to_string()
+}
+
+fn default_dark_sprite() -> String {
+    "dark_bullet".to_string()
+}
+
+// One player attack.  Speed and lifetime only matter for spells that fire bullets.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Spell {
+    pub id: String,
+    #[serde(default)]
+    pub kind: SpellKind,
+    #[serde(default = "default_cooldown")]
+    pub cooldown: f64,
+    #[serde(default = "default_speed")]
+    pub speed: f64, // In world coordinates per second
+    #[serde(default = "default_lifetime")]
+    pub lifetime: f64,
+    #[serde(default = "default_damage")]
+    pub damage: f64,
+    #[serde(default)]
+    pub mana_cost: f64,
+    #[serde(default = "default_light_sprite")]
+    pub light_sprite: String, // Asset names, for casting while white and black
+    #[serde(default = "default_dark_sprite")]
+    pub dark_sprite: String
+}
+
+impl Spell {
+    // The original attack, what Space always did before spells
+    pub fn bolt() -> Self {
+        Spell {
+            id: DEFAULT_SPELL.to_string(),
+            kind: SpellKind::Bolt,
+            cooldown: default_cooldown(),
+            speed: default_speed(),
+            lifetime: default_lifetime(),
+            damage: default_damage(),
+            mana_cost: 0.0,
+            light_sprite: default_light_sprite(),
+            dark_sprite: default_dark_sprite()
+        }
+    }
+}
+
+// The player's spells, loaded from spells.json.  The number keys pick them in this order.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SpellBook {
+    pub spells: Vec<Spell>
+}
+
+impl Default for SpellBook {
+    fn default() -> Self {
+        SpellBook {
+            spells: vec![Spell::bolt()]
+        }
+    }
+}
+
+impl SpellBook {
+    pub fn load_from_file(filename: &str) -> Result<Self, Error> {
+        let reader = BufReader::new(OpenOptions::new().read(true).open(filename)?);
+        let book: SpellBook = serde_json::from_reader(reader)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, format!("{}: {}", filename, e)))?;
+        book.check().map_err(|e| Error::new(ErrorKind::InvalidData, format!("{}: {}", filename, e)))?;
+        Ok(book)
+    }
+
+    fn check(&self) -> Result<(), String> {
+        if self.spells.is_empty() {
+            return Err("there are no spells".to_string());
+        }
+        if self.spells.len() > 9 {
+            return Err("only 9 spells fit on the number keys".to_string());
+        }
+        let mut ids: HashSet<&str> = HashSet::new();
+        for s in &self.spells {
+            if !ids.insert(s.id.as_str()) {
+                return Err(format!("spell {} is listed twice", s.id));
+            }
+            let amounts = [s.cooldown, s.damage, s.mana_cost];
+            if amounts.iter().any(|x| *x < 0.0) {
+                return Err(format!("spell {} has a negative cooldown, damage or mana cost", s.id));
+            }
+            if s.speed <= 0.0 || s.lifetime <= 0.0 {
+                return Err(format!("spell {} needs a speed and lifetime above 0", s.id));
+            }
+            match s.kind {
+                SpellKind::Spread {count: 0, ..} => {
+                    return Err(format!("spell {} fires a spread of no bullets", s.id));
+                }
+                SpellKind::Beam {range} | SpellKind::Burst {radius: range} if range <= 0.0 => {
+                    return Err(format!("spell {} has no reach", s.id));
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    pub fn get(&self, id: &str) -> Option<&Spell> {
+        self.spells.iter().find(|s| s.id == id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_spell_book() {
+        let book = SpellBook::load_from_file("spells.json").unwrap();
+        // The shipped bolt has to stay the same as the built in one, games without spells.json get it
+        assert_eq!(book.spells[0], Spell::bolt());
+        assert!(book.spells.iter().any(|s| matches!(s.kind, SpellKind::Beam {..})));
+        assert!(book.spells.iter().any(|s| matches!(s.kind, SpellKind::Burst {..})));
+
+        let text = r#"{"spells": [{"id": "Flare", "kind": {"Burst": {"radius": 2.0}}, "mana_cost": 30.0}]}"#;
+        let book: SpellBook = serde_json::from_str(text).unwrap();
+        let flare = book.get("Flare").unwrap();
+        assert_eq!(flare.cooldown, 1.0);
+        assert_eq!(flare.light_sprite, "light_bullet");
+        assert!(book.check().is_ok());
+
+        let mut broken = book.clone();
+        broken.spells[0].kind = SpellKind::Beam {range: 0.0};
+        assert!(broken.check().is_err());
+    }
+}
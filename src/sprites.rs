@@ -5,6 +5,7 @@ use macroquad::math::{DMat2, DVec2, DVec4};
 
 use crate::{fpv, image};
 use crate::fpv::FirstPersonViewer;
+use crate::grid2d::Topology;
 use crate::image::ImageLoader;
 use crate::mob::MagicColor;
 
@@ -17,14 +18,31 @@ pub struct Sprites {
     sp_draw_order: Vec<(f64, usize)> // distance, index
 }
 
-fn find_distance_across_boundary(obj: DVec2, pos: DVec2, facing: f64, world_width: f64) -> DVec2{
-    let mut diff = obj - pos;
-    if facing < 0.0 &&  diff.x > 0.0{ // facing left
-        diff.x = -pos.x + obj.x - world_width;
-    } else if facing > 0.0 && diff.x < 0.0 {
-        diff.x = world_width + obj.x - pos.x;
+fn find_distance_across_boundary(obj: DVec2, pos: DVec2, facing: f64, world_size: (usize, usize), topology: Topology) -> DVec2{
+    let (x_rule, y_rule) = topology.edge_rules();
+
+    // Horizontally use the nearest copy of the object in front of us
+    let mut diff_x: Option<f64> = None;
+    for x in x_rule.images_f64(obj.x, world_size.0) {
+        let dx = x - pos.x;
+        let closer = match diff_x {
+            None => {true}
+            Some(d) => {dx.abs() < d.abs()}
+        };
+        if dx * facing >= 0.0 && closer {
+            diff_x = Some(dx);
+        }
     }
-    diff
+
+    // Vertically just the nearest copy
+    let mut diff_y = obj.y - pos.y;
+    for y in y_rule.images_f64(obj.y, world_size.1) {
+        if (y - pos.y).abs() < diff_y.abs() {
+            diff_y = y - pos.y;
+        }
+    }
+
+    DVec2::new(diff_x.unwrap_or(obj.x - pos.x), diff_y)
 }
 
 impl Sprites {
@@ -59,7 +77,8 @@ impl Sprites {
         pos: DVec2,
         dir: DVec2,
         plane_scale: f64,
-        world_width: f64)
+        world_size: (usize, usize),
+        topology: Topology)
     {
         let (rw, rh) = (fpv.render_size.0 as usize, fpv.render_size.1 as usize);
         let (w, h) = (fpv.render_size.0 as f64, fpv.render_size.1 as f64);
@@ -74,7 +93,8 @@ impl Sprites {
                 *sprite,
                 pos,
                 dir.x/(dir.x.abs()),
-                world_width
+                world_size,
+                topology
             ); //(*sprite - pos);
             let distance_squared = sprite_rel_pos.dot(sprite_rel_pos);
             let transform = camera_inverse.mul_vec2(sprite_rel_pos);
@@ -98,7 +118,8 @@ impl Sprites {
                 *sprite,
                 pos,
                 dir.x/(dir.x.abs()),
-                world_width
+                world_size,
+                topology
             );
             let transform = camera_inverse.mul_vec2(sprite_rel_pos);
             let sprite_screen_y = (h /2.0) * (1.0 + transform.x/ transform.y);